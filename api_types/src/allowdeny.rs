@@ -0,0 +1,55 @@
+//! Sender allow/deny lists
+//!
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllowDenyKind {
+    Allow,
+    Deny,
+}
+
+impl AllowDenyKind {
+    pub fn is_allow(self) -> bool {
+        matches!(self, Self::Allow)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AllowDenyListResponse {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListAllowDenyRequest {
+    pub mail_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AllowDenyEntryRequest {
+    pub mail_domain: String,
+    pub kind: AllowDenyKind,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AllowDenyEntryResponse {
+    pub kind: AllowDenyKind,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReplaceAllowDenyRequest {
+    pub mail_domain: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
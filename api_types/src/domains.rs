@@ -5,6 +5,17 @@ use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 
+/// The level of access a user has to a domain.  Roles are ordered such that
+/// each includes all the access of those before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum DomainRole {
+    Viewer,
+    Editor,
+    Admin,
+    Owner,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ListDomainResponse {
     pub domains: BTreeMap<String, ListDomainResponseEntry>,
@@ -19,6 +30,12 @@ pub struct ListDomainResponseEntry {
     pub grey_listing: bool,
     pub virus_check: bool,
     pub spamcheck_threshold: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subaddress_separator: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<DomainRole>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -37,4 +54,79 @@ pub struct SetDomainFlagsRequest {
     pub virus_check: Option<bool>,
     #[serde(default)]
     pub spamcheck_threshold: Option<i32>,
+    #[serde(default)]
+    pub subaddress_separator: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemberRole {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl From<MemberRole> for DomainRole {
+    fn from(value: MemberRole) -> Self {
+        match value {
+            MemberRole::Viewer => DomainRole::Viewer,
+            MemberRole::Editor => DomainRole::Editor,
+            MemberRole::Admin => DomainRole::Admin,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListDomainMembersRequest {
+    pub mail_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListDomainMembersResponse {
+    pub owner: String,
+    pub members: BTreeMap<String, MemberRole>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GrantDomainMemberRequest {
+    pub mail_domain: String,
+    pub username: String,
+    pub role: MemberRole,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RevokeDomainMemberRequest {
+    pub mail_domain: String,
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DomainMemberResponse {
+    pub username: String,
+    pub role: MemberRole,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListDomainAliasesRequest {
+    pub mail_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DomainAliasRequest {
+    pub mail_domain: String,
+    pub alias: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DomainAliasesResponse {
+    pub mail_domain: String,
+    pub aliases: Vec<String>,
 }
@@ -1,5 +1,11 @@
 //! API types for comms between the frontend and backend
 //!
 
+pub mod allowdeny;
+pub mod audit;
 pub mod domains;
+pub mod lists;
+pub mod passwords;
+pub mod quota;
 pub mod tokens;
+pub mod vacation;
@@ -0,0 +1,58 @@
+//! Mailing lists
+//!
+
+use serde::{Deserialize, Serialize};
+
+/// Who may post to a mailing list
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostPolicy {
+    /// Only members of the list
+    #[default]
+    Members,
+    /// Anyone at all
+    Anyone,
+    /// Only members flagged as moderators
+    Moderators,
+}
+
+/// Where replies to messages sent out by a mailing list should go
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplyTo {
+    /// Leave the message alone, so replies go to its sender
+    #[default]
+    Sender,
+    /// Set Reply-To so that replies go back to the list
+    List,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListSettings {
+    pub post_policy: PostPolicy,
+    pub reply_to: ReplyTo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_prefix: Option<String>,
+}
+
+/// Per-member flags on a mailing list
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListMemberFlags {
+    /// Receive a periodic digest rather than each message
+    #[serde(default)]
+    pub digest: bool,
+    /// Receive nothing, while remaining a member (and so able to post)
+    #[serde(default)]
+    pub nomail: bool,
+    /// May post when the list is restricted to moderators
+    #[serde(default)]
+    pub moderator: bool,
+}
+
+impl ListMemberFlags {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
@@ -0,0 +1,288 @@
+//! What passwords for logins and accounts must be like
+//!
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The most character classes a password can have: lower case, upper case,
+/// digits, and anything else
+pub const MAX_PASSWORD_CLASSES: u32 = 4;
+
+/// Longer than any sensible minimum, to catch typos
+pub const MAX_PASSWORD_MIN_LENGTH: u32 = 1024;
+
+/// Rules a new password must satisfy.  The deployment has one of these, and
+/// each domain may have its own which can only tighten the deployment's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PasswordPolicy {
+    /// In characters, not bytes
+    #[serde(default)]
+    pub min_length: u32,
+    /// How many different character classes must appear
+    #[serde(default)]
+    pub min_classes: u32,
+    /// Whether the entry's own name, or its domain's, may not appear
+    #[serde(default)]
+    pub forbid_names: bool,
+}
+
+/// The rule a password broke
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PasswordRule {
+    Empty,
+    TooShort { min_length: u32 },
+    TooFewClasses { min_classes: u32 },
+    ContainsName,
+    Breached,
+    Prehashed,
+}
+
+impl PasswordRule {
+    /// A short name for the rule, for machines
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::TooShort { .. } => "too-short",
+            Self::TooFewClasses { .. } => "too-few-classes",
+            Self::ContainsName => "contains-name",
+            Self::Breached => "breached",
+            Self::Prehashed => "prehashed",
+        }
+    }
+}
+
+impl fmt::Display for PasswordRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "the password may not be empty"),
+            Self::TooShort { min_length } => {
+                write!(
+                    f,
+                    "the password must be at least {min_length} characters long"
+                )
+            }
+            Self::TooFewClasses { min_classes } => write!(
+                f,
+                "the password must contain at least {min_classes} of lower case letters, \
+                 upper case letters, digits, and other characters"
+            ),
+            Self::ContainsName => write!(
+                f,
+                "the password may not contain the entry's name or its domain's name"
+            ),
+            Self::Breached => write!(
+                f,
+                "the password is known to have been exposed in a data breach"
+            ),
+            Self::Prehashed => write!(f, "only superusers may give a password already hashed"),
+        }
+    }
+}
+
+impl std::error::Error for PasswordRule {}
+
+/// The parts of a domain name worth not finding in a password: the whole
+/// name, and each label but the last
+fn domain_names(domain: &str) -> impl Iterator<Item = &str> {
+    let labels = domain.rsplit_once('.').map(|(rest, _)| rest).unwrap_or("");
+    std::iter::once(domain).chain(labels.split('.'))
+}
+
+impl PasswordPolicy {
+    /// The stricter of this policy and another, rule by rule
+    pub fn tightened_by(&self, other: &PasswordPolicy) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: self.min_length.max(other.min_length),
+            min_classes: self.min_classes.max(other.min_classes),
+            forbid_names: self.forbid_names || other.forbid_names,
+        }
+    }
+
+    /// Check the policy itself makes sense
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_length > MAX_PASSWORD_MIN_LENGTH {
+            return Err(format!(
+                "min-length may be at most {MAX_PASSWORD_MIN_LENGTH}"
+            ));
+        }
+        if self.min_classes > MAX_PASSWORD_CLASSES {
+            return Err(format!("min-classes may be at most {MAX_PASSWORD_CLASSES}"));
+        }
+        Ok(())
+    }
+
+    /// Check a password for the entry `local` in `domain` against the rules
+    /// which need nothing more than the password itself
+    pub fn check(&self, password: &str, local: &str, domain: &str) -> Result<(), PasswordRule> {
+        if password.is_empty() {
+            return Err(PasswordRule::Empty);
+        }
+        if (password.chars().count() as u64) < u64::from(self.min_length) {
+            return Err(PasswordRule::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if (classes.iter().filter(|c| **c).count() as u32) < self.min_classes {
+            return Err(PasswordRule::TooFewClasses {
+                min_classes: self.min_classes,
+            });
+        }
+        if self.forbid_names {
+            let lowered = password.to_lowercase();
+            // Very short names would rule out far too much
+            if std::iter::once(local)
+                .chain(domain_names(domain))
+                .filter(|name| name.chars().count() >= 3)
+                .any(|name| lowered.contains(&name.to_lowercase()))
+            {
+                return Err(PasswordRule::ContainsName);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GetPasswordPolicyRequest {
+    pub mail_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SetPasswordPolicyRequest {
+    pub mail_domain: String,
+    #[serde(default)]
+    pub min_length: u32,
+    #[serde(default)]
+    pub min_classes: u32,
+    #[serde(default)]
+    pub forbid_names: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PasswordPolicyResponse {
+    pub mail_domain: String,
+    /// The domain's own rules
+    pub domain: PasswordPolicy,
+    /// What actually applies, once the deployment's rules are included
+    pub effective: PasswordPolicy,
+    /// Whether passwords are also checked against known breaches
+    pub check_breached: bool,
+}
+
+/// How the server should make up a password when none is given
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PasswordStyle {
+    /// Random letters, digits and punctuation
+    #[default]
+    Random,
+    /// Random words, which are easier for people to type
+    Passphrase,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_length: u32, min_classes: u32, forbid_names: bool) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length,
+            min_classes,
+            forbid_names,
+        }
+    }
+
+    #[test]
+    fn refuses_empty_passwords() {
+        assert_eq!(
+            PasswordPolicy::default().check("", "me", "example.com"),
+            Err(PasswordRule::Empty)
+        );
+        assert_eq!(
+            PasswordPolicy::default().check("x", "me", "example.com"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn counts_length_in_characters() {
+        let policy = policy(8, 0, false);
+        assert_eq!(
+            policy.check("seven77", "me", "example.com"),
+            Err(PasswordRule::TooShort { min_length: 8 })
+        );
+        assert_eq!(policy.check("eight888", "me", "example.com"), Ok(()));
+        // Eight characters, but sixteen bytes
+        assert_eq!(policy.check("éééééééé", "me", "example.com"), Ok(()));
+    }
+
+    #[test]
+    fn counts_character_classes() {
+        let policy = policy(0, 3, false);
+        assert_eq!(
+            policy.check("lowerUPPER", "me", "example.com"),
+            Err(PasswordRule::TooFewClasses { min_classes: 3 })
+        );
+        assert_eq!(policy.check("lowerUPPER1", "me", "example.com"), Ok(()));
+        assert_eq!(policy.check("lower1!", "me", "example.com"), Ok(()));
+    }
+
+    #[test]
+    fn forbids_names_when_asked() {
+        let forbidding = policy(0, 0, true);
+        for password in ["xxSomeOnexx", "my-EXAMPLE.com", "xexamplex"] {
+            assert_eq!(
+                forbidding.check(password, "someone", "example.com"),
+                Err(PasswordRule::ContainsName),
+                "{password}"
+            );
+            assert_eq!(
+                policy(0, 0, false).check(password, "someone", "example.com"),
+                Ok(())
+            );
+        }
+        // Names too short to be worth ruling out, and the top level domain
+        assert_eq!(forbidding.check("mecomuk", "me", "ab.co.uk"), Ok(()));
+        assert_eq!(
+            forbidding.check("xMailx", "me", "mail.example.org"),
+            Err(PasswordRule::ContainsName)
+        );
+    }
+
+    #[test]
+    fn tightens_rule_by_rule() {
+        assert_eq!(
+            policy(12, 1, false).tightened_by(&policy(8, 3, true)),
+            policy(12, 3, true)
+        );
+        assert_eq!(
+            policy(8, 2, true).tightened_by(&PasswordPolicy::default()),
+            policy(8, 2, true)
+        );
+    }
+
+    #[test]
+    fn validates_limits() {
+        assert!(policy(MAX_PASSWORD_MIN_LENGTH, MAX_PASSWORD_CLASSES, true)
+            .validate()
+            .is_ok());
+        assert!(policy(MAX_PASSWORD_MIN_LENGTH + 1, 0, false)
+            .validate()
+            .is_err());
+        assert!(policy(0, MAX_PASSWORD_CLASSES + 1, false)
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Mailbox storage quotas
+//!
+
+use serde::{Deserialize, Serialize};
+
+/// Storage limits for a mailbox; anything left unset is unlimited
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub messages: Option<u64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes.is_none() && self.messages.is_none()
+    }
+
+    /// Whether this stays within the given limit.  Where the limit is set,
+    /// being unlimited does not.
+    pub fn within(&self, limit: &Quota) -> bool {
+        fn fits(value: Option<u64>, limit: Option<u64>) -> bool {
+            match (value, limit) {
+                (_, None) => true,
+                (Some(value), Some(limit)) => value <= limit,
+                (None, Some(_)) => false,
+            }
+        }
+        fits(self.bytes, limit.bytes) && fits(self.messages, limit.messages)
+    }
+}
+
+/// The quotas of a domain's accounts
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DomainQuota {
+    /// For accounts without a quota of their own
+    #[serde(default)]
+    pub default: Quota,
+    /// The most the domain's accounts may have between them
+    #[serde(default)]
+    pub limit: Quota,
+}
+
+impl DomainQuota {
+    /// The quota an account actually gets, given its own quota if it has one.
+    /// Anything not set by the account or the default is capped at the limit.
+    pub fn effective(&self, own: Option<&Quota>) -> Quota {
+        let own = own.copied().unwrap_or_default();
+        Quota {
+            bytes: own.bytes.or(self.default.bytes).or(self.limit.bytes),
+            messages: own
+                .messages
+                .or(self.default.messages)
+                .or(self.limit.messages),
+        }
+    }
+
+    /// Whether an account may have the given quota of its own
+    pub fn permits(&self, own: &Quota) -> bool {
+        self.effective(Some(own)).within(&self.limit)
+    }
+
+    /// Whether accounts with the given quotas of their own, or none, fit
+    /// within the limit between them.  Anything an account leaves unset
+    /// counts at the default, or not at all if there is no default.
+    pub fn permits_total<'a>(&self, accounts: impl IntoIterator<Item = Option<&'a Quota>>) -> bool {
+        let (mut bytes, mut messages) = (0u64, 0u64);
+        for own in accounts {
+            let own = own.copied().unwrap_or_default();
+            let counted =
+                |value: Option<u64>, default: Option<u64>| value.or(default).unwrap_or_default();
+            bytes = bytes.saturating_add(counted(own.bytes, self.default.bytes));
+            messages = messages.saturating_add(counted(own.messages, self.default.messages));
+        }
+        let total = Quota {
+            bytes: Some(bytes),
+            messages: Some(messages),
+        };
+        total.within(&self.limit)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GetDomainQuotaRequest {
+    pub mail_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SetDomainQuotaRequest {
+    pub mail_domain: String,
+    #[serde(default)]
+    pub default: Quota,
+    #[serde(default)]
+    pub limit: Quota,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DomainQuotaResponse {
+    pub mail_domain: String,
+    pub default: Quota,
+    pub limit: Quota,
+}
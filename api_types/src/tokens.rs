@@ -1,9 +1,100 @@
+use std::{fmt, str::FromStr};
+
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// Capabilities which a token may be restricted to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    #[serde(rename = "domain:read")]
+    DomainRead,
+    #[serde(rename = "domain:flags")]
+    DomainFlags,
+    #[serde(rename = "entries:read")]
+    EntriesRead,
+    #[serde(rename = "entries:write")]
+    EntriesWrite,
+    #[serde(rename = "keys:read")]
+    KeysRead,
+    #[serde(rename = "keys:write")]
+    KeysWrite,
+    #[serde(rename = "allow-deny:read")]
+    AllowDenyRead,
+    #[serde(rename = "allow-deny:write")]
+    AllowDenyWrite,
+    #[serde(rename = "domain:members")]
+    DomainMembers,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+    #[serde(rename = "tokens")]
+    Tokens,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Self::DomainRead,
+        Self::DomainFlags,
+        Self::EntriesRead,
+        Self::EntriesWrite,
+        Self::KeysRead,
+        Self::KeysWrite,
+        Self::AllowDenyRead,
+        Self::AllowDenyWrite,
+        Self::DomainMembers,
+        Self::AuditRead,
+        Self::Tokens,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DomainRead => "domain:read",
+            Self::DomainFlags => "domain:flags",
+            Self::EntriesRead => "entries:read",
+            Self::EntriesWrite => "entries:write",
+            Self::KeysRead => "keys:read",
+            Self::KeysWrite => "keys:write",
+            Self::AllowDenyRead => "allow-deny:read",
+            Self::AllowDenyWrite => "allow-deny:write",
+            Self::DomainMembers => "domain:members",
+            Self::AuditRead => "audit:read",
+            Self::Tokens => "tokens",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|c| c.as_str() == s)
+            .ok_or_else(|| format!("Unknown capability: {s}"))
+    }
+}
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct TokenListResponseEntry {
-    pub token: String,
+    pub id: String,
     pub label: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub last_used_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub expires_at: Option<OffsetDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<Capability>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,43 @@
+//! Out of office auto-replies
+//!
+
+use serde::{Deserialize, Serialize};
+
+fn default_interval_days() -> u32 {
+    7
+}
+
+/// An account's auto-reply settings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VacationSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Subject of the replies; if empty the mail backend picks one
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub body: String,
+    /// First day replies are sent, as `YYYY-MM-DD`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    /// Last day replies are sent, as `YYYY-MM-DD`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    /// How many days to wait before replying to the same sender again
+    #[serde(default = "default_interval_days")]
+    pub interval_days: u32,
+}
+
+impl Default for VacationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: String::new(),
+            body: String::new(),
+            start: None,
+            end: None,
+            interval_days: default_interval_days(),
+        }
+    }
+}
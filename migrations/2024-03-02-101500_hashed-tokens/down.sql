@@ -0,0 +1,6 @@
+-- Remove token prefixes.  The hashed tokens cannot be turned back into
+-- usable plaintext tokens, so every token must be reissued after this.
+
+ALTER TABLE mailauthtoken
+  DROP CONSTRAINT mailauthtoken_prefix_uniq,
+  DROP COLUMN prefix;
@@ -0,0 +1,21 @@
+-- Store authentication tokens hashed, identified by a short public prefix
+
+ALTER TABLE mailauthtoken
+  ADD COLUMN prefix VARCHAR;
+
+-- Existing tokens have no separate prefix, so the first eight characters of
+-- the token act as its identifier, and the whole token string is hashed.
+-- Where two tokens share those characters, each gets its id appended so
+-- that identifiers stay unique; newly issued prefixes never contain '-'.
+UPDATE mailauthtoken AS t
+  SET prefix = CASE
+        WHEN (SELECT count(*) FROM mailauthtoken AS o
+              WHERE left(o.token, 8) = left(t.token, 8)) > 1
+          THEN left(t.token, 8) || '-' || t.id
+        ELSE left(t.token, 8)
+      END,
+      token = encode(sha256(t.token::bytea), 'hex');
+
+ALTER TABLE mailauthtoken
+  ALTER COLUMN prefix SET NOT NULL,
+  ADD CONSTRAINT mailauthtoken_prefix_uniq UNIQUE (prefix);
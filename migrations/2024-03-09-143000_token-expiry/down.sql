@@ -0,0 +1,6 @@
+-- Remove token timestamps
+
+ALTER TABLE mailauthtoken
+  DROP COLUMN created_at,
+  DROP COLUMN last_used_at,
+  DROP COLUMN expires_at;
@@ -0,0 +1,6 @@
+-- Track when tokens were created and last used, and allow them to expire
+
+ALTER TABLE mailauthtoken
+  ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  ADD COLUMN last_used_at TIMESTAMPTZ,
+  ADD COLUMN expires_at TIMESTAMPTZ;
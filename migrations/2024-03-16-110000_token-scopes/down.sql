@@ -0,0 +1,5 @@
+-- Remove token scopes, this makes scoped tokens unrestricted
+
+ALTER TABLE mailauthtoken
+  DROP COLUMN scope_domains,
+  DROP COLUMN scope_capabilities;
@@ -0,0 +1,6 @@
+-- Allow tokens to be restricted to certain domains and capabilities.
+-- A NULL scope means the token is unrestricted in that respect.
+
+ALTER TABLE mailauthtoken
+  ADD COLUMN scope_domains INTEGER[],
+  ADD COLUMN scope_capabilities VARCHAR[];
@@ -0,0 +1,5 @@
+-- Remove delegated domain access
+
+DROP TABLE maildomain_member;
+
+DROP TYPE maildomainrole;
@@ -0,0 +1,12 @@
+-- Allow users other than the owner to access a domain
+
+CREATE TYPE maildomainrole AS ENUM ('viewer', 'editor', 'admin');
+
+CREATE TABLE maildomain_member (
+    id SERIAL NOT NULL PRIMARY KEY,
+    maildomain INTEGER NOT NULL REFERENCES maildomain (id),
+    mailuser INTEGER NOT NULL REFERENCES mailuser (id),
+    role maildomainrole NOT NULL,
+
+    CONSTRAINT maildomain_member_uniq UNIQUE (maildomain, mailuser)
+);
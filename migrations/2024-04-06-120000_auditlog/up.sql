@@ -0,0 +1,16 @@
+-- Record every configuration change made through the API
+
+CREATE TABLE auditlog (
+    id SERIAL NOT NULL PRIMARY KEY,
+    at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    mailuser INTEGER NOT NULL REFERENCES mailuser (id),
+    token_label VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    maildomain INTEGER REFERENCES maildomain (id),
+    target VARCHAR NOT NULL,
+    before JSONB,
+    after JSONB
+);
+
+CREATE INDEX auditlog_at_idx ON auditlog (at);
+CREATE INDEX auditlog_maildomain_idx ON auditlog (maildomain, at);
@@ -0,0 +1,10 @@
+-- Remove the trash, permanently deleting anything in it
+
+DELETE FROM mailentry WHERE deleted_at IS NOT NULL;
+
+DROP INDEX mailentry_name_uniq;
+
+ALTER TABLE mailentry ADD CONSTRAINT mailentry_name_uniq UNIQUE (maildomain, name);
+
+ALTER TABLE mailentry
+  DROP COLUMN deleted_at;
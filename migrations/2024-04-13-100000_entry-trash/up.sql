@@ -0,0 +1,11 @@
+-- Deleted entries are kept in the trash for a while before being purged
+
+ALTER TABLE mailentry
+  ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Names only need to be unique amongst entries which are not in the trash
+
+ALTER TABLE mailentry DROP CONSTRAINT mailentry_name_uniq;
+
+CREATE UNIQUE INDEX mailentry_name_uniq ON mailentry (maildomain, name)
+  WHERE deleted_at IS NULL;
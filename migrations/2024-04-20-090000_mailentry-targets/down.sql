@@ -0,0 +1,25 @@
+-- Put alias and list targets back into their expansions
+
+ALTER TABLE mailentry DROP CONSTRAINT no_expansion_with_targets;
+ALTER TABLE mailentry DROP CONSTRAINT expansion_for_right_kind;
+ALTER TABLE mailentry DROP CONSTRAINT only_one_value;
+
+UPDATE mailentry e
+   SET expansion = COALESCE(
+       (SELECT string_agg(t.target, ', ' ORDER BY t.position)
+          FROM mailentry_target t
+         WHERE t.mailentry = e.id),
+       '')
+ WHERE e.kind IN ('alias', 'list');
+
+ALTER TABLE mailentry ADD CONSTRAINT only_one_value CHECK (
+    ( CASE WHEN password IS NULL THEN 0 ELSE 1 END
+    + CASE WHEN expansion IS NULL THEN 0 ELSE 1 END
+    ) = 1
+);
+
+ALTER TABLE mailentry ADD CONSTRAINT expansion_for_right_kind CHECK (
+    kind NOT IN ('alias', 'bouncer', 'blackhole', 'list') OR expansion IS NOT NULL
+);
+
+DROP TABLE mailentry_target;
@@ -0,0 +1,42 @@
+-- Alias and list targets are stored one per row, rather than as a single
+-- comma-separated expansion
+
+CREATE TABLE mailentry_target (
+    id SERIAL NOT NULL PRIMARY KEY,
+    mailentry INTEGER NOT NULL REFERENCES mailentry (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    target VARCHAR NOT NULL,
+    CONSTRAINT mailentry_target_uniq UNIQUE (mailentry, target)
+);
+
+CREATE INDEX mailentry_target_by_target ON mailentry_target (target);
+
+INSERT INTO mailentry_target (mailentry, position, target)
+SELECT DISTINCT ON (id, target) id, position, target
+  FROM (
+    SELECT e.id, t.position::INTEGER AS position, btrim(t.target) AS target
+      FROM mailentry e,
+           unnest(string_to_array(e.expansion, ',')) WITH ORDINALITY AS t(target, position)
+     WHERE e.kind IN ('alias', 'list')
+  ) AS split
+ WHERE target <> ''
+ ORDER BY id, target, position;
+
+-- Aliases and lists no longer have an expansion of their own
+
+ALTER TABLE mailentry DROP CONSTRAINT only_one_value;
+ALTER TABLE mailentry DROP CONSTRAINT expansion_for_right_kind;
+
+UPDATE mailentry SET expansion = NULL WHERE kind IN ('alias', 'list');
+
+ALTER TABLE mailentry ADD CONSTRAINT only_one_value CHECK (
+    password IS NULL OR expansion IS NULL
+);
+
+ALTER TABLE mailentry ADD CONSTRAINT expansion_for_right_kind CHECK (
+    kind NOT IN ('bouncer', 'blackhole') OR expansion IS NOT NULL
+);
+
+ALTER TABLE mailentry ADD CONSTRAINT no_expansion_with_targets CHECK (
+    kind NOT IN ('alias', 'list') OR expansion IS NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE mailentry_target
+    DROP COLUMN digest,
+    DROP COLUMN nomail,
+    DROP COLUMN moderator;
+
+DROP TABLE mailentry_list;
+
+DROP TYPE listreplyto;
+DROP TYPE listpostpolicy;
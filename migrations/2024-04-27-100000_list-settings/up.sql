@@ -0,0 +1,17 @@
+-- Settings for mailing lists, and flags for their members.  A list with no
+-- settings row uses the defaults.
+
+CREATE TYPE listpostpolicy AS ENUM ('members', 'anyone', 'moderators');
+CREATE TYPE listreplyto AS ENUM ('sender', 'list');
+
+CREATE TABLE mailentry_list (
+    mailentry INTEGER NOT NULL PRIMARY KEY REFERENCES mailentry (id) ON DELETE CASCADE,
+    post_policy listpostpolicy NOT NULL DEFAULT 'members',
+    reply_to listreplyto NOT NULL DEFAULT 'sender',
+    subject_prefix VARCHAR
+);
+
+ALTER TABLE mailentry_target
+    ADD COLUMN digest BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN nomail BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN moderator BOOLEAN NOT NULL DEFAULT FALSE;
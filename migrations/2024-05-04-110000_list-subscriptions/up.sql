@@ -0,0 +1,21 @@
+-- Self-service list subscriptions awaiting confirmation, and the outbox of
+-- mail waiting to be sent by the mail frontend
+
+CREATE TABLE list_subscription (
+    id SERIAL NOT NULL PRIMARY KEY,
+    mailentry INTEGER NOT NULL REFERENCES mailentry (id) ON DELETE CASCADE,
+    address VARCHAR NOT NULL,
+    subscribe BOOLEAN NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    CONSTRAINT list_subscription_uniq UNIQUE (mailentry, address, subscribe)
+);
+
+CREATE TABLE mail_outbox (
+    id SERIAL NOT NULL PRIMARY KEY,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sender VARCHAR NOT NULL,
+    recipient VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    body TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE mailentry DROP CONSTRAINT catch_all_right_kind;
+
+ALTER TABLE maildomain DROP CONSTRAINT subaddress_separator_not_empty;
+ALTER TABLE maildomain DROP COLUMN subaddress_separator;
@@ -0,0 +1,12 @@
+-- Per-domain subaddressing (user+tag), and catch-all entries named '*'
+-- which take any mail not otherwise matched
+
+ALTER TABLE maildomain ADD COLUMN subaddress_separator VARCHAR;
+
+ALTER TABLE maildomain ADD CONSTRAINT subaddress_separator_not_empty CHECK (
+    subaddress_separator IS NULL OR subaddress_separator <> ''
+);
+
+ALTER TABLE mailentry ADD CONSTRAINT catch_all_right_kind CHECK (
+    name <> '*' OR kind IN ('alias', 'bouncer', 'blackhole')
+);
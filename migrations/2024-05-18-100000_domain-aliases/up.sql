@@ -0,0 +1,9 @@
+-- Domains which mirror the entries of another domain
+
+CREATE TABLE maildomain_alias (
+    id SERIAL NOT NULL PRIMARY KEY,
+    maildomain INTEGER NOT NULL REFERENCES maildomain (id) ON DELETE CASCADE,
+    domainname VARCHAR NOT NULL UNIQUE
+);
+
+CREATE INDEX maildomain_alias_by_domain ON maildomain_alias (maildomain);
@@ -0,0 +1,3 @@
+DROP TABLE mailentry_forward;
+
+ALTER TABLE mailentry DROP COLUMN keep_local_copy;
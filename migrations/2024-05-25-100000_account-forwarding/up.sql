@@ -0,0 +1,11 @@
+-- Accounts may forward their mail elsewhere, optionally keeping a copy
+
+ALTER TABLE mailentry ADD COLUMN keep_local_copy BOOLEAN NOT NULL DEFAULT TRUE;
+
+CREATE TABLE mailentry_forward (
+    id SERIAL NOT NULL PRIMARY KEY,
+    mailentry INTEGER NOT NULL REFERENCES mailentry (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    target VARCHAR NOT NULL,
+    UNIQUE (mailentry, target)
+);
@@ -0,0 +1 @@
+DROP TABLE mailentry_vacation;
@@ -0,0 +1,13 @@
+-- Out of office auto-replies for accounts
+
+CREATE TABLE mailentry_vacation (
+    mailentry INTEGER NOT NULL PRIMARY KEY REFERENCES mailentry (id) ON DELETE CASCADE,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    subject VARCHAR NOT NULL DEFAULT '',
+    body TEXT NOT NULL DEFAULT '',
+    starts_on DATE,
+    ends_on DATE,
+    interval_days INTEGER NOT NULL DEFAULT 7,
+    CONSTRAINT interval_positive CHECK (interval_days >= 1),
+    CONSTRAINT dates_in_order CHECK (starts_on IS NULL OR ends_on IS NULL OR starts_on <= ends_on)
+);
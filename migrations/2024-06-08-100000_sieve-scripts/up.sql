@@ -0,0 +1,9 @@
+-- Sieve scripts for accounts to filter their own mail
+
+CREATE TABLE mailentry_sieve (
+    mailentry INTEGER NOT NULL PRIMARY KEY REFERENCES mailentry (id) ON DELETE CASCADE,
+    script TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX mailentry_sieve_by_update ON mailentry_sieve (updated_at);
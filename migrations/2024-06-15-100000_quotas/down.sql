@@ -0,0 +1,2 @@
+DROP TABLE mailentry_quota;
+DROP TABLE maildomain_quota;
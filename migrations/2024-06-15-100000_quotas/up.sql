@@ -0,0 +1,15 @@
+-- Mailbox storage quotas, per account and for a domain as a whole
+
+CREATE TABLE maildomain_quota (
+    maildomain INTEGER NOT NULL PRIMARY KEY REFERENCES maildomain (id) ON DELETE CASCADE,
+    default_bytes BIGINT CHECK (default_bytes >= 0),
+    default_messages BIGINT CHECK (default_messages >= 0),
+    limit_bytes BIGINT CHECK (limit_bytes >= 0),
+    limit_messages BIGINT CHECK (limit_messages >= 0)
+);
+
+CREATE TABLE mailentry_quota (
+    mailentry INTEGER NOT NULL PRIMARY KEY REFERENCES mailentry (id) ON DELETE CASCADE,
+    bytes BIGINT CHECK (bytes >= 0),
+    messages BIGINT CHECK (messages >= 0)
+);
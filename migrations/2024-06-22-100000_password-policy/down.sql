@@ -0,0 +1 @@
+DROP TABLE maildomain_password_policy;
@@ -0,0 +1,8 @@
+-- A domain's own password rules, which tighten the deployment's
+
+CREATE TABLE maildomain_password_policy (
+    maildomain INTEGER NOT NULL PRIMARY KEY REFERENCES maildomain (id) ON DELETE CASCADE,
+    min_length INTEGER NOT NULL DEFAULT 0 CHECK (min_length BETWEEN 0 AND 1024),
+    min_classes INTEGER NOT NULL DEFAULT 0 CHECK (min_classes BETWEEN 0 AND 4),
+    forbid_names BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,2 @@
+-- Both forms meant the same thing, so there is nothing to put back
+SELECT 1;
@@ -0,0 +1,34 @@
+-- Whole domains on the sender allow/deny lists are now always written as
+-- '@domain' rather than '*@domain', so fold any of the latter into the former
+
+-- A value may only be on one of a domain's lists, so a domain named in both
+-- forms on opposite lists can't be folded without choosing which list wins.
+-- Leave that choice to the domain's owner rather than guessing.
+DO $$
+DECLARE
+    clash RECORD;
+BEGIN
+    SELECT d.domainname, bare.value INTO clash
+        FROM allowdenylist AS star
+        JOIN allowdenylist AS bare
+          ON star.maildomain = bare.maildomain
+         AND bare.value = substr(star.value, 2)
+         AND star.allow <> bare.allow
+        JOIN maildomain AS d ON d.id = star.maildomain
+        WHERE star.value LIKE '*@%'
+        LIMIT 1;
+    IF FOUND THEN
+        RAISE EXCEPTION 'Mail domain % has % on one of its allow/deny lists and *% on the other; remove one before migrating',
+            clash.domainname, clash.value, clash.value;
+    END IF;
+END
+$$;
+
+DELETE FROM allowdenylist AS star
+    USING allowdenylist AS bare
+    WHERE star.maildomain = bare.maildomain
+      AND star.allow = bare.allow
+      AND star.value LIKE '*@%'
+      AND bare.value = substr(star.value, 2);
+
+UPDATE allowdenylist SET value = substr(value, 2) WHERE value LIKE '*@%';
@@ -0,0 +1,6 @@
+DELETE FROM auditlog WHERE mailuser IS NULL;
+
+ALTER TABLE auditlog
+  DROP CONSTRAINT auditlog_one_actor,
+  DROP COLUMN account,
+  ALTER COLUMN mailuser SET NOT NULL;
@@ -0,0 +1,7 @@
+-- Accounts may change some of their own settings without any user being
+-- involved, so such changes are recorded against the account instead
+
+ALTER TABLE auditlog
+  ALTER COLUMN mailuser DROP NOT NULL,
+  ADD COLUMN account VARCHAR,
+  ADD CONSTRAINT auditlog_one_actor CHECK ((mailuser IS NULL) <> (account IS NULL));
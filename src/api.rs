@@ -1,3 +1,4 @@
+use api_types::passwords::PasswordRule;
 use axum::{
     extract::{Path, State},
     http::{header, StatusCode},
@@ -5,13 +6,18 @@ use axum::{
     routing::get,
     Json, Router,
 };
+use mailconfig::models::{ExpansionProblem, MalformedHash, SieveError};
 use serde::Serialize;
 use thiserror::Error;
 
 use crate::{configuration::Configuration, state::AppState};
 
+mod account;
+mod audit;
 mod domain;
 mod frontend;
+mod internal;
+mod lists;
 mod tokens;
 mod users;
 
@@ -27,6 +33,8 @@ pub enum APIError {
     AuthErrorNoToken,
     #[error("Authentication failed, bad token provided: {0}")]
     AuthErrorBadToken(String),
+    #[error("Authentication failed, token has expired: {0}")]
+    AuthErrorTokenExpired(String),
     #[error("Authentication token is in use: {0}")]
     AuthErrorTokenInUse(String),
     #[error("Bad token: {0}")]
@@ -35,6 +43,10 @@ pub enum APIError {
     NotLoginOrAccount(String),
     #[error("Not an alias: {0}")]
     NotAlias(String),
+    #[error("Not a list: {0}")]
+    NotList(String),
+    #[error("Not an account: {0}")]
+    NotAccount(String),
     #[error("Alias component {0} was not found")]
     AliasComponentNotFound(String),
     #[error("Cannot remove last component, alias {0} would become empty")]
@@ -43,6 +55,61 @@ pub enum APIError {
     UserAlreadyExists(String),
     #[error("Not a blackhole or bouncer")]
     NotBouncerOrBlackhole(String),
+    #[error("Bad allow/deny list value: {0}")]
+    BadAllowDenyValue(String),
+    #[error("Value already present in allow/deny lists: {0}")]
+    AllowDenyDuplicate(String),
+    #[error("User owns the domain: {0}")]
+    UserIsOwner(String),
+    #[error("Entry name is in use: {0}")]
+    EntryNameInUse(String),
+    #[error("Not a valid entry name: {0}")]
+    BadEntryName(String),
+    #[error("Bad import document: {0}")]
+    BadImport(String),
+    #[error("Bad expansion target: {0}")]
+    BadExpansionTarget(String),
+    #[error("Expansion target does not exist: {0}")]
+    ExpansionTargetMissing(String),
+    #[error("Expansion target is a bouncer or blackhole: {0}")]
+    ExpansionTargetUndeliverable(String),
+    #[error("Expansion would loop: {}", .0.join(" -> "))]
+    ExpansionLoop(Vec<String>),
+    #[error("Bad address: {0}")]
+    BadAddress(String),
+    #[error("Bad subaddress separator: {0}")]
+    BadSubaddressSeparator(String),
+    #[error("Catch-all entries must be an alias, bouncer or blackhole: {0}")]
+    CatchAllKind(String),
+    #[error("Domain name is already in use: {0}")]
+    DomainNameInUse(String),
+    #[error("Bad auto-reply settings: {0}")]
+    BadVacation(String),
+    #[error("Bad Sieve script: {0}")]
+    BadSieve(SieveError),
+    #[error("Quota is over the domain's limit: {0}")]
+    QuotaOverLimit(String),
+    #[error("Bad password: {0}")]
+    MalformedPasswordHash(#[from] MalformedHash),
+    #[error("Password not permitted: {0}")]
+    WeakPassword(#[from] PasswordRule),
+    #[error("Bad password policy: {0}")]
+    BadPasswordPolicy(String),
+    #[error("Too many requests for: {0}")]
+    RateLimited(String),
+}
+
+impl APIError {
+    /// Convert a problem with the expansion of the named alias or list
+    pub fn from_expansion(problem: ExpansionProblem, item: &str) -> Self {
+        match problem {
+            ExpansionProblem::Empty => Self::AliasWouldBecomeEmpty(item.to_string()),
+            ExpansionProblem::Invalid(target) => Self::BadExpansionTarget(target),
+            ExpansionProblem::Missing(target) => Self::ExpansionTargetMissing(target),
+            ExpansionProblem::Undeliverable(target) => Self::ExpansionTargetUndeliverable(target),
+            ExpansionProblem::Loop(cycle) => Self::ExpansionLoop(cycle),
+        }
+    }
 }
 
 pub type APIResult<T> = std::result::Result<T, APIError>;
@@ -50,18 +117,117 @@ pub type APIResult<T> = std::result::Result<T, APIError>;
 #[derive(Serialize)]
 #[serde(tag = "kind", rename_all = "kebab-case")]
 enum APIResponseError {
-    NotFound { item: String },
-    PermissionDenied { why: String },
-    DatabaseError { msg: String },
-    AuthenticationFailure { reason: String },
-    TokenInUse { token: String },
-    BadToken { token: String },
-    NotLoginOrAccount { item: String },
-    NotAlias { item: String },
-    AliasComponentNotFound { component: String },
-    AliasWouldBecomeEmpty { item: String },
-    UserAlreadyExists { item: String },
-    NotBouncerOrBlackhole { item: String },
+    NotFound {
+        item: String,
+    },
+    PermissionDenied {
+        why: String,
+    },
+    DatabaseError {
+        msg: String,
+    },
+    AuthenticationFailure {
+        reason: String,
+    },
+    TokenExpired {
+        token: String,
+    },
+    TokenInUse {
+        token: String,
+    },
+    BadToken {
+        token: String,
+    },
+    NotLoginOrAccount {
+        item: String,
+    },
+    NotAlias {
+        item: String,
+    },
+    NotList {
+        item: String,
+    },
+    NotAccount {
+        item: String,
+    },
+    AliasComponentNotFound {
+        component: String,
+    },
+    AliasWouldBecomeEmpty {
+        item: String,
+    },
+    UserAlreadyExists {
+        item: String,
+    },
+    NotBouncerOrBlackhole {
+        item: String,
+    },
+    BadAllowDenyValue {
+        value: String,
+    },
+    AllowDenyDuplicate {
+        value: String,
+    },
+    UserIsOwner {
+        user: String,
+    },
+    EntryNameInUse {
+        item: String,
+    },
+    BadEntryName {
+        item: String,
+    },
+    BadImport {
+        reason: String,
+    },
+    BadExpansionTarget {
+        target: String,
+    },
+    ExpansionTargetMissing {
+        target: String,
+    },
+    ExpansionTargetUndeliverable {
+        target: String,
+    },
+    ExpansionLoop {
+        cycle: Vec<String>,
+    },
+    BadAddress {
+        address: String,
+    },
+    BadSubaddressSeparator {
+        separator: String,
+    },
+    CatchAllKind {
+        item: String,
+    },
+    DomainNameInUse {
+        item: String,
+    },
+    BadVacation {
+        reason: String,
+    },
+    BadSieve {
+        line: usize,
+        column: usize,
+        reason: String,
+    },
+    QuotaOverLimit {
+        item: String,
+    },
+    MalformedPasswordHash {
+        scheme: String,
+    },
+    WeakPassword {
+        rule: String,
+        reason: String,
+    },
+    BadPasswordPolicy {
+        reason: String,
+    },
+    RateLimited {
+        item: String,
+    },
 }
 
 impl From<APIError> for APIResponseError {
@@ -74,6 +240,7 @@ impl From<APIError> for APIResponseError {
             e @ APIError::AuthErrorBadToken(_) => Self::AuthenticationFailure {
                 reason: e.to_string(),
             },
+            APIError::AuthErrorTokenExpired(token) => Self::TokenExpired { token },
             e @ APIError::AuthErrorTokenInUse(_) => Self::TokenInUse {
                 token: e.to_string(),
             },
@@ -84,10 +251,44 @@ impl From<APIError> for APIResponseError {
             APIError::PermissionDenied(e) => Self::PermissionDenied { why: e },
             APIError::NotLoginOrAccount(e) => Self::NotLoginOrAccount { item: e },
             APIError::NotAlias(e) => Self::NotAlias { item: e },
+            APIError::NotList(e) => Self::NotList { item: e },
+            APIError::NotAccount(e) => Self::NotAccount { item: e },
             APIError::AliasComponentNotFound(s) => Self::AliasComponentNotFound { component: s },
             APIError::AliasWouldBecomeEmpty(s) => Self::AliasWouldBecomeEmpty { item: s },
             APIError::UserAlreadyExists(s) => Self::UserAlreadyExists { item: s },
             APIError::NotBouncerOrBlackhole(s) => Self::NotBouncerOrBlackhole { item: s },
+            APIError::BadAllowDenyValue(s) => Self::BadAllowDenyValue { value: s },
+            APIError::AllowDenyDuplicate(s) => Self::AllowDenyDuplicate { value: s },
+            APIError::UserIsOwner(s) => Self::UserIsOwner { user: s },
+            APIError::EntryNameInUse(s) => Self::EntryNameInUse { item: s },
+            APIError::BadEntryName(s) => Self::BadEntryName { item: s },
+            APIError::BadImport(s) => Self::BadImport { reason: s },
+            APIError::BadExpansionTarget(s) => Self::BadExpansionTarget { target: s },
+            APIError::ExpansionTargetMissing(s) => Self::ExpansionTargetMissing { target: s },
+            APIError::ExpansionTargetUndeliverable(s) => {
+                Self::ExpansionTargetUndeliverable { target: s }
+            }
+            APIError::ExpansionLoop(cycle) => Self::ExpansionLoop { cycle },
+            APIError::BadAddress(s) => Self::BadAddress { address: s },
+            APIError::BadSubaddressSeparator(s) => Self::BadSubaddressSeparator { separator: s },
+            APIError::CatchAllKind(s) => Self::CatchAllKind { item: s },
+            APIError::DomainNameInUse(s) => Self::DomainNameInUse { item: s },
+            APIError::BadVacation(s) => Self::BadVacation { reason: s },
+            APIError::BadSieve(e) => Self::BadSieve {
+                line: e.line,
+                column: e.column,
+                reason: e.message,
+            },
+            APIError::QuotaOverLimit(s) => Self::QuotaOverLimit { item: s },
+            APIError::MalformedPasswordHash(e) => Self::MalformedPasswordHash {
+                scheme: e.scheme.to_string(),
+            },
+            APIError::WeakPassword(rule) => Self::WeakPassword {
+                rule: rule.name().to_string(),
+                reason: rule.to_string(),
+            },
+            APIError::BadPasswordPolicy(s) => Self::BadPasswordPolicy { reason: s },
+            APIError::RateLimited(s) => Self::RateLimited { item: s },
         }
     }
 }
@@ -98,15 +299,39 @@ impl APIResponseError {
             APIResponseError::NotFound { .. } => StatusCode::NOT_FOUND,
             APIResponseError::BadToken { .. }
             | APIResponseError::AuthenticationFailure { .. }
+            | APIResponseError::TokenExpired { .. }
             | APIResponseError::PermissionDenied { .. } => StatusCode::FORBIDDEN,
             APIResponseError::TokenInUse { .. } => StatusCode::BAD_REQUEST,
             APIResponseError::DatabaseError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             APIResponseError::AliasComponentNotFound { .. }
             | APIResponseError::AliasWouldBecomeEmpty { .. }
             | APIResponseError::NotAlias { .. }
+            | APIResponseError::NotList { .. }
+            | APIResponseError::NotAccount { .. }
             | APIResponseError::UserAlreadyExists { .. }
             | APIResponseError::NotLoginOrAccount { .. }
-            | APIResponseError::NotBouncerOrBlackhole { .. } => StatusCode::BAD_REQUEST,
+            | APIResponseError::NotBouncerOrBlackhole { .. }
+            | APIResponseError::BadAllowDenyValue { .. }
+            | APIResponseError::AllowDenyDuplicate { .. }
+            | APIResponseError::UserIsOwner { .. }
+            | APIResponseError::EntryNameInUse { .. }
+            | APIResponseError::BadEntryName { .. }
+            | APIResponseError::BadImport { .. }
+            | APIResponseError::BadExpansionTarget { .. }
+            | APIResponseError::ExpansionTargetMissing { .. }
+            | APIResponseError::ExpansionTargetUndeliverable { .. }
+            | APIResponseError::ExpansionLoop { .. }
+            | APIResponseError::BadAddress { .. }
+            | APIResponseError::BadSubaddressSeparator { .. }
+            | APIResponseError::CatchAllKind { .. }
+            | APIResponseError::DomainNameInUse { .. }
+            | APIResponseError::BadVacation { .. }
+            | APIResponseError::BadSieve { .. }
+            | APIResponseError::QuotaOverLimit { .. }
+            | APIResponseError::MalformedPasswordHash { .. }
+            | APIResponseError::WeakPassword { .. }
+            | APIResponseError::BadPasswordPolicy { .. } => StatusCode::BAD_REQUEST,
+            APIResponseError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
@@ -172,7 +397,11 @@ pub fn router(state: &AppState) -> Router<AppState> {
     Router::new()
         .route("/autoconfig/:domain", get(autoconfig))
         .route("/ping", get(get_ping))
-        .nest("/frontend", frontend::router())
+        .nest("/account", account::router())
+        .nest("/audit", audit::router(state))
+        .nest("/frontend", frontend::router(state))
+        .nest("/internal", internal::router(state))
+        .nest("/list", lists::router())
         .nest("/token", tokens::router(state))
         .nest("/domain", domain::router(state))
         .nest("/user", users::router(state))
@@ -0,0 +1,137 @@
+//! Self-service for accounts
+//!
+//! Rather than an API token, these authenticate with HTTP basic auth using
+//! the account's own address and password, so that people can manage their
+//! own account without needing access to the whole domain.  Failed attempts
+//! count towards the same limits as those checked for the mail services.
+
+use std::net::{IpAddr, SocketAddr};
+
+use api_types::vacation::VacationSettings;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap},
+    routing::get,
+    Json, Router,
+};
+use base64::prelude::*;
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
+use mailconfig::{models::*, Connection};
+
+use crate::{
+    api::{APIError, APIResult},
+    configuration::Configuration,
+    ratelimit::{client_ip, AuthLimiter},
+    state::AppState,
+};
+
+/// The account whose address and password were given in the request, along
+/// with its full address
+async fn authenticate(
+    db: &mut AsyncPgConnection,
+    limiter: &AuthLimiter,
+    ip: IpAddr,
+    headers: &HeaderMap,
+) -> APIResult<(MailEntry, String)> {
+    let credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Basic "))
+        .and_then(|val| BASE64_STANDARD.decode(val.trim()).ok())
+        .and_then(|val| String::from_utf8(val).ok())
+        .ok_or(APIError::AuthErrorNoToken)?;
+    let (address, password) = credentials
+        .split_once(':')
+        .ok_or(APIError::AuthErrorNoToken)?;
+
+    let mut found = None;
+    if let Some((local, domain_name)) = address.rsplit_once('@') {
+        if let Some(domain) = MailDomain::by_name(db, &domain_name.to_ascii_lowercase()).await? {
+            found = domain.entry_by_name(db, local).await?.map(|entry| {
+                let address = format!("{}@{}", entry.name, domain.domainname);
+                (entry, address)
+            });
+        }
+    }
+    // Count failures against the account they were for, however its address
+    // was spelled
+    let key = found
+        .as_ref()
+        .map(|(_, canonical)| canonical.as_str())
+        .unwrap_or(address);
+    if limiter.blocked(key, Some(ip)) {
+        return Err(APIError::RateLimited(address.to_string()));
+    }
+
+    match found {
+        Some((mut entry, canonical))
+            if entry.kind == MailEntryKind::Account && entry.verify_password(password) =>
+        {
+            limiter.record_success(&canonical);
+            entry.upgrade_password(db, password).await?;
+            Ok((entry, canonical))
+        }
+        _ => {
+            limiter.record_failure(key, Some(ip));
+            Err(APIError::PermissionDenied(address.to_string()))
+        }
+    }
+}
+
+async fn get_vacation(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    State(limiter): State<AuthLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> APIResult<Json<VacationSettings>> {
+    let ip = client_ip(&config, &headers, peer);
+    let (entry, _) = authenticate(&mut db, &limiter, ip, &headers).await?;
+    let vacation = entry
+        .vacation(&mut db)
+        .await?
+        .unwrap_or_else(|| MailVacation::defaults(entry.id));
+    Ok(Json::from(vacation.to_api()))
+}
+
+async fn set_vacation(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    State(limiter): State<AuthLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<VacationSettings>,
+) -> APIResult<Json<VacationSettings>> {
+    let ip = client_ip(&config, &headers, peer);
+    let (entry, address) = authenticate(&mut db, &limiter, ip, &headers).await?;
+    let mut vacation = entry
+        .vacation(&mut db)
+        .await?
+        .unwrap_or_else(|| MailVacation::defaults(entry.id));
+    let before = vacation.audit_json();
+    vacation.apply(&body).map_err(APIError::BadVacation)?;
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            vacation.save(db).await?;
+            AuditLog::record_by_account(
+                db,
+                &address,
+                "entry:vacation",
+                entry.maildomain,
+                Some(before),
+                Some(vacation.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(vacation.to_api()))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/vacation", get(get_vacation).put(set_vacation))
+}
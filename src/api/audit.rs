@@ -0,0 +1,90 @@
+//! Audit log of configuration changes
+//!
+
+use axum::{extract::Query, routing::get, Extension, Json, Router};
+use mailconfig::{
+    models::{AuditFilter, AuditLog, Authorisation, Capability, MailDomain, MailUser},
+    Connection,
+};
+
+use crate::{state::AppState, tokens::Authorised};
+
+use super::{APIError, APIResult};
+
+use api_types::audit::*;
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+async fn get_audit_log(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Query(query): Query<AuditLogRequest>,
+) -> APIResult<Json<AuditLogResponse>> {
+    if !auth.has_capability(Capability::AuditRead) {
+        return Err(APIError::PermissionDenied(
+            "Token may not read the audit log".into(),
+        ));
+    }
+
+    let mut filter = AuditFilter {
+        since: query.since,
+        until: query.until,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT).into(),
+        target: query.target,
+        ..AuditFilter::default()
+    };
+
+    if let Some(domain_name) = &query.domain {
+        let domain = MailDomain::by_name(&mut db, domain_name)
+            .await?
+            .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+        if !domain
+            .may_read(&mut db, &auth, Capability::AuditRead)
+            .await?
+        {
+            return Err(APIError::PermissionDenied(domain_name.clone()));
+        }
+        filter.domain = Some(domain.id);
+    }
+
+    if let Some(username) = &query.user {
+        let user = MailUser::by_name(&mut db, username)
+            .await?
+            .ok_or_else(|| APIError::NotFound(username.clone()))?;
+        filter.user = Some(user.id);
+    }
+
+    if !auth.superuser() {
+        let domains = MailDomain::visible_to(&mut db, &auth)
+            .await?
+            .into_iter()
+            .map(|(dom, _)| dom.id)
+            .collect();
+        filter.visible_to = Some((domains, auth.user()));
+    }
+
+    let entries = AuditLog::search(&mut db, &filter)
+        .await?
+        .into_iter()
+        .map(|(entry, user, domain)| AuditLogResponseEntry {
+            at: entry.at,
+            // Changes accounts made to themselves show the account instead
+            user: user.or(entry.account).unwrap_or_default(),
+            token_label: entry.token_label,
+            action: entry.action,
+            domain,
+            target: entry.target,
+            before: entry.before,
+            after: entry.after,
+        })
+        .collect();
+
+    Ok(AuditLogResponse { entries }.into())
+}
+
+pub fn router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_audit_log))
+        .authorise(state.clone())
+}
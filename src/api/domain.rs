@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+
 use axum::{
     routing::{get, post},
     Extension, Json, Router,
 };
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
 use mailconfig::{
-    models::{Authorisation, MailDomain, MailUser},
+    models::{
+        valid_subaddress_separator, AuditLog, Authorisation, Capability, MailDomain, MailUser,
+    },
     Connection,
 };
 use serde::Deserialize;
@@ -12,20 +17,48 @@ use crate::{api::APIError, state::AppState, tokens::Authorised};
 
 use super::APIResult;
 
+mod aliases;
+mod allowdeny;
+mod apply;
 mod entries;
 mod keys;
+mod members;
+mod password_policy;
+mod quota;
+mod resolve;
 
 use api_types::domains::*;
 
+/// Check a requested subaddress separator, where empty means none
+pub(super) fn subaddress_separator(separator: &str) -> APIResult<Option<String>> {
+    if separator.is_empty() {
+        Ok(None)
+    } else if valid_subaddress_separator(separator) {
+        Ok(Some(separator.to_string()))
+    } else {
+        Err(APIError::BadSubaddressSeparator(separator.to_string()))
+    }
+}
+
 async fn list_domains(
     mut db: Connection,
     Extension(auth): Extension<Authorisation>,
 ) -> APIResult<Json<ListDomainResponse>> {
-    let all_domains = MailDomain::by_owner(&mut db, auth.user()).await?;
+    if !auth.has_capability(Capability::DomainRead) {
+        return Err(APIError::PermissionDenied(
+            "Token may not list domains".into(),
+        ));
+    }
+    let all_domains = MailDomain::visible_to(&mut db, &auth).await?;
+    let mut aliases: HashMap<String, Vec<String>> = HashMap::new();
+    for (alias, domain) in MailDomain::all_aliases(&mut db).await? {
+        aliases.entry(domain).or_default().push(alias);
+    }
     let ret = ListDomainResponse {
         domains: all_domains
             .into_iter()
-            .map(|dom| {
+            .map(|(dom, role)| {
+                let aliases = aliases.remove(&dom.domainname).unwrap_or_default();
                 (
                     dom.domainname,
                     ListDomainResponseEntry {
@@ -34,6 +67,9 @@ async fn list_domains(
                         grey_listing: dom.grey_listing,
                         virus_check: dom.virus_check,
                         spamcheck_threshold: dom.spamcheck_threshold,
+                        subaddress_separator: dom.subaddress_separator,
+                        aliases,
+                        role: Some(role),
                     },
                 )
             })
@@ -51,10 +87,16 @@ async fn set_domain_flags(
         .await?
         .ok_or_else(|| APIError::NotFound(body.domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_administer(&mut db, &auth, Capability::DomainFlags)
+        .await?
+    {
         return Err(APIError::PermissionDenied(body.domain_name.clone()));
     }
 
+    let before = domain.audit_json();
+    let old_owner = domain.owner;
+
     // Permission granted, so let's see what we can do...
     domain.owner = if let Some(owner) = body.owner.as_deref() {
         if !auth.superuser() {
@@ -89,15 +131,45 @@ async fn set_domain_flags(
     if let Some(spamcheck_threshold) = body.spamcheck_threshold {
         domain.spamcheck_threshold = spamcheck_threshold;
     }
+    if let Some(separator) = body.subaddress_separator.as_deref() {
+        domain.subaddress_separator = subaddress_separator(separator)?;
+    }
 
-    domain.save(&mut db).await?;
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            domain.save(db).await?;
+            if domain.owner != old_owner {
+                // The old owner gives the domain up entirely, and the new
+                // one no longer needs any role they were granted on it
+                domain.revoke(db, old_owner).await?;
+                domain.revoke(db, domain.owner).await?;
+            }
+            AuditLog::record(
+                db,
+                &auth,
+                "domain:set-flags",
+                Some(domain.id),
+                &domain.domainname,
+                Some(before),
+                Some(domain.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
 
+    let aliases = domain.aliases(&mut db).await?;
     Ok(ListDomainResponseEntry {
         remote_mx: domain.remotemx,
         sender_verify: domain.sender_verify,
         grey_listing: domain.grey_listing,
         virus_check: domain.virus_check,
         spamcheck_threshold: domain.spamcheck_threshold,
+        subaddress_separator: domain.subaddress_separator,
+        aliases,
+        role: None,
     }
     .into())
 }
@@ -118,6 +190,8 @@ struct CreateDomainRequest {
     virus_check: Option<bool>,
     #[serde(default)]
     spamcheck_threshold: Option<i32>,
+    #[serde(default)]
+    subaddress_separator: Option<String>,
 }
 
 async fn create_domain(
@@ -140,17 +214,48 @@ async fn create_domain(
         auth.user()
     };
 
-    let domain = MailDomain::create(
-        &mut db,
-        &body.domain_name,
-        owner,
-        body.remote_mx.as_deref(),
-        body.sender_verify.unwrap_or(true),
-        body.grey_listing.unwrap_or(false),
-        body.virus_check.unwrap_or(true),
-        body.spamcheck_threshold.unwrap_or(100),
-    )
-    .await?;
+    let separator = match body.subaddress_separator.as_deref() {
+        Some(separator) => subaddress_separator(separator)?,
+        None => None,
+    };
+
+    if MailDomain::by_alias(&mut db, &body.domain_name)
+        .await?
+        .is_some()
+    {
+        return Err(APIError::DomainNameInUse(body.domain_name));
+    }
+
+    let domain = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let domain = MailDomain::create(
+                    db,
+                    &body.domain_name,
+                    owner,
+                    body.remote_mx.as_deref(),
+                    body.sender_verify.unwrap_or(true),
+                    body.grey_listing.unwrap_or(false),
+                    body.virus_check.unwrap_or(true),
+                    body.spamcheck_threshold.unwrap_or(100),
+                    separator.as_deref(),
+                )
+                .await?;
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "domain:create",
+                    Some(domain.id),
+                    &domain.domainname,
+                    None,
+                    Some(domain.audit_json()),
+                )
+                .await?;
+                Ok(domain)
+            }
+            .scope_boxed()
+        })
+        .await?;
 
     Ok(Json::from(ListDomainResponseEntry {
         remote_mx: domain.remotemx,
@@ -158,6 +263,9 @@ async fn create_domain(
         grey_listing: domain.grey_listing,
         virus_check: domain.virus_check,
         spamcheck_threshold: domain.spamcheck_threshold,
+        subaddress_separator: domain.subaddress_separator,
+        aliases: vec![],
+        role: None,
     }))
 }
 
@@ -166,7 +274,14 @@ pub fn router(state: &AppState) -> Router<AppState> {
         .route("/new", post(create_domain))
         .route("/list", get(list_domains))
         .route("/set-flags", post(set_domain_flags))
+        .route("/apply", post(apply::apply_domain))
+        .route("/resolve/:address", get(resolve::resolve_address))
+        .nest("/alias", aliases::router())
+        .nest("/allow-deny", allowdeny::router())
         .nest("/key", keys::router())
+        .nest("/member", members::router())
+        .nest("/password-policy", password_policy::router())
+        .nest("/quota", quota::router())
         .nest("/entry", entries::router())
         .authorise(state.clone())
 }
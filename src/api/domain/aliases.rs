@@ -0,0 +1,143 @@
+//! Domain aliases, which mirror the entries of another domain
+//!
+
+use axum::{routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{
+    models::{valid_domain_name, AuditLog, Authorisation, Capability, MailDomain},
+    Connection,
+};
+use serde_json::json;
+
+use crate::{
+    api::{APIError, APIResult},
+    state::AppState,
+};
+
+use api_types::domains::*;
+
+async fn list_aliases(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<ListDomainAliasesRequest>,
+) -> APIResult<Json<DomainAliasesResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::DomainRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    Ok(DomainAliasesResponse {
+        aliases: domain.aliases(&mut db).await?,
+        mail_domain: domain.domainname,
+    }
+    .into())
+}
+
+async fn add_alias(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<DomainAliasRequest>,
+) -> APIResult<Json<DomainAliasesResponse>> {
+    // Like creating a domain, this claims a whole domain name for us
+    if !auth.superuser() {
+        return Err(APIError::PermissionDenied(
+            "You are not permitted to add domain aliases".into(),
+        ));
+    }
+
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    let alias = body.alias.trim().to_ascii_lowercase();
+    if !valid_domain_name(&alias) {
+        return Err(APIError::BadAddress(body.alias));
+    }
+    if MailDomain::hosting(&mut db, &alias).await?.is_some() {
+        return Err(APIError::DomainNameInUse(alias));
+    }
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            domain.add_alias(db, &alias).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "domain:add-alias",
+                Some(domain.id),
+                &alias,
+                None,
+                Some(json!({ "alias": alias, "domain": domain.domainname })),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(DomainAliasesResponse {
+        aliases: domain.aliases(&mut db).await?,
+        mail_domain: domain.domainname,
+    }
+    .into())
+}
+
+async fn remove_alias(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<DomainAliasRequest>,
+) -> APIResult<Json<DomainAliasesResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_administer(&mut db, &auth, Capability::DomainFlags)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let alias = body.alias.trim().to_ascii_lowercase();
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            if !domain.remove_alias(db, &alias).await? {
+                return Err(APIError::NotFound(alias.clone()));
+            }
+            AuditLog::record(
+                db,
+                &auth,
+                "domain:remove-alias",
+                Some(domain.id),
+                &alias,
+                Some(json!({ "alias": alias, "domain": domain.domainname })),
+                None,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(DomainAliasesResponse {
+        aliases: domain.aliases(&mut db).await?,
+        mail_domain: domain.domainname,
+    }
+    .into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/list", post(list_aliases))
+        .route("/add", post(add_alias))
+        .route("/remove", post(remove_alias))
+}
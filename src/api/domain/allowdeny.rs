@@ -0,0 +1,231 @@
+//! Sender allow/deny lists for domains
+//!
+
+use std::collections::HashSet;
+
+use axum::{routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
+use mailconfig::{
+    models::{AllowDenyList, AuditLog, Authorisation, Capability, MailDomain},
+    Connection,
+};
+
+use crate::{
+    api::{APIError, APIResult},
+    state::AppState,
+};
+
+use api_types::allowdeny::*;
+
+async fn domain_for(
+    db: &mut Connection,
+    auth: &Authorisation,
+    mail_domain: &str,
+    capability: Capability,
+) -> APIResult<MailDomain> {
+    let domain = MailDomain::by_name(db, mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(mail_domain.to_string()))?;
+
+    let permitted = match capability {
+        Capability::AllowDenyRead => domain.may_read(db, auth, capability).await?,
+        _ => domain.may_write(db, auth, capability).await?,
+    };
+    if !permitted {
+        return Err(APIError::PermissionDenied(mail_domain.to_string()));
+    }
+
+    Ok(domain)
+}
+
+pub(super) async fn current_lists(
+    db: &mut AsyncPgConnection,
+    maildomain: i32,
+) -> APIResult<AllowDenyListResponse> {
+    let mut res = AllowDenyListResponse::default();
+    for entry in AllowDenyList::by_domain(db, maildomain).await? {
+        if entry.allow {
+            res.allow.push(entry.value);
+        } else {
+            res.deny.push(entry.value);
+        }
+    }
+    Ok(res)
+}
+
+fn normalise(value: &str) -> APIResult<String> {
+    AllowDenyList::normalise_value(value)
+        .ok_or_else(|| APIError::BadAllowDenyValue(value.to_string()))
+}
+
+/// Normalise and sort complete allow and deny lists, ensuring that no value
+/// appears more than once across them
+pub(super) fn check_lists(allow: &[String], deny: &[String]) -> APIResult<AllowDenyListResponse> {
+    let mut seen = HashSet::new();
+    let mut check = |values: &[String]| -> APIResult<Vec<String>> {
+        let mut ret = values
+            .iter()
+            .map(|v| normalise(v))
+            .collect::<APIResult<Vec<_>>>()?;
+        if let Some(dup) = ret.iter().find(|v| !seen.insert((*v).clone())) {
+            return Err(APIError::AllowDenyDuplicate(dup.clone()));
+        }
+        ret.sort();
+        Ok(ret)
+    };
+    Ok(AllowDenyListResponse {
+        allow: check(allow)?,
+        deny: check(deny)?,
+    })
+}
+
+async fn list_allow_deny(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<ListAllowDenyRequest>,
+) -> APIResult<Json<AllowDenyListResponse>> {
+    let domain = domain_for(&mut db, &auth, &body.mail_domain, Capability::AllowDenyRead).await?;
+
+    Ok(current_lists(&mut db, domain.id).await?.into())
+}
+
+async fn add_allow_deny(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<AllowDenyEntryRequest>,
+) -> APIResult<Json<AllowDenyEntryResponse>> {
+    let domain = domain_for(
+        &mut db,
+        &auth,
+        &body.mail_domain,
+        Capability::AllowDenyWrite,
+    )
+    .await?;
+    let value = normalise(&body.value)?;
+
+    if AllowDenyList::by_value(&mut db, domain.id, &value)
+        .await?
+        .is_some()
+    {
+        return Err(APIError::AllowDenyDuplicate(value));
+    }
+
+    let res = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let entry =
+                    AllowDenyList::create(db, domain.id, body.kind.is_allow(), &value).await?;
+                let res = AllowDenyEntryResponse {
+                    kind: body.kind,
+                    value: entry.value,
+                };
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "allow-deny:add",
+                    Some(domain.id),
+                    &domain.domainname,
+                    None,
+                    serde_json::to_value(&res).ok(),
+                )
+                .await?;
+                Ok(res)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(res.into())
+}
+
+async fn remove_allow_deny(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<AllowDenyEntryRequest>,
+) -> APIResult<Json<AllowDenyEntryResponse>> {
+    let domain = domain_for(
+        &mut db,
+        &auth,
+        &body.mail_domain,
+        Capability::AllowDenyWrite,
+    )
+    .await?;
+    let value = normalise(&body.value)?;
+
+    let entry = AllowDenyList::by_value(&mut db, domain.id, &value)
+        .await?
+        .filter(|e| e.allow == body.kind.is_allow())
+        .ok_or_else(|| APIError::NotFound(value.clone()))?;
+
+    let res = AllowDenyEntryResponse {
+        kind: body.kind,
+        value,
+    };
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            entry.delete_self(db).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "allow-deny:remove",
+                Some(domain.id),
+                &domain.domainname,
+                serde_json::to_value(&res).ok(),
+                None,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(res.into())
+}
+
+async fn replace_allow_deny(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<ReplaceAllowDenyRequest>,
+) -> APIResult<Json<AllowDenyListResponse>> {
+    let domain = domain_for(
+        &mut db,
+        &auth,
+        &body.mail_domain,
+        Capability::AllowDenyWrite,
+    )
+    .await?;
+
+    let res = check_lists(&body.allow, &body.deny)?;
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let before = current_lists(db, domain.id).await?;
+            AllowDenyList::replace_all(db, domain.id, &res.allow, &res.deny).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "allow-deny:replace",
+                Some(domain.id),
+                &domain.domainname,
+                serde_json::to_value(&before).ok(),
+                serde_json::to_value(&res).ok(),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(res.into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/list", post(list_allow_deny))
+        .route("/add", post(add_allow_deny))
+        .route("/remove", post(remove_allow_deny))
+        .route("/replace", post(replace_allow_deny))
+}
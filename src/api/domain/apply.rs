@@ -0,0 +1,403 @@
+//! Declarative application of a whole domain's configuration
+//!
+//! The caller provides the desired state of any of the domain's flags,
+//! entries, sender allow/deny lists and DKIM keys.  We work out what would
+//! have to change to get there and, if asked, make those changes atomically.
+
+use std::collections::BTreeMap;
+
+use axum::{extract::State, Extension, Json};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{
+    models::{AllowDenyList, AuditLog, Authorisation, Capability, MailDomain, MailDomainKey},
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    api::{APIError, APIResult},
+    configuration::Configuration,
+};
+
+use super::{
+    allowdeny::{check_lists, current_lists},
+    entries::bulk::{
+        apply_changes, errored, plan_entries, DocumentEntry, ImportMode, ImportOutcome, NotApplied,
+    },
+    keys::key_target,
+    password_policy::PasswordChecker,
+    subaddress_separator,
+};
+
+use api_types::allowdeny::AllowDenyListResponse;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(super) struct DomainFlags {
+    #[serde(default)]
+    remote_mx: Option<String>,
+    sender_verify: bool,
+    grey_listing: bool,
+    virus_check: bool,
+    spamcheck_threshold: i32,
+    #[serde(default)]
+    subaddress_separator: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(super) struct DomainKeyState {
+    #[serde(default)]
+    signing: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(super) struct ApplyDomainRequest {
+    mail_domain: String,
+    #[serde(default)]
+    apply: bool,
+    #[serde(default)]
+    flags: Option<DomainFlags>,
+    #[serde(default)]
+    entries: Option<BTreeMap<String, DocumentEntry>>,
+    #[serde(default)]
+    allow_deny: Option<AllowDenyListResponse>,
+    #[serde(default)]
+    keys: Option<BTreeMap<String, DomainKeyState>>,
+    /// Accept expansions which refer to missing entries, bouncers or
+    /// blackholes
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct FlagChange {
+    from: Value,
+    to: Value,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct ListChanges {
+    add: Vec<String>,
+    remove: Vec<String>,
+}
+
+impl ListChanges {
+    fn between(current: &[String], desired: &[String]) -> Option<Self> {
+        let res = Self {
+            add: desired
+                .iter()
+                .filter(|v| !current.contains(v))
+                .cloned()
+                .collect(),
+            remove: current
+                .iter()
+                .filter(|v| !desired.contains(v))
+                .cloned()
+                .collect(),
+        };
+        (!(res.add.is_empty() && res.remove.is_empty())).then_some(res)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+enum KeyOutcome {
+    Created { signing: bool },
+    Updated { signing: bool },
+    Deleted,
+}
+
+enum KeyChange {
+    Create(String, bool),
+    Update(MailDomainKey, Value),
+    Delete(MailDomainKey),
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct ApplyDomainResponse {
+    applied: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    flags: BTreeMap<String, FlagChange>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    entries: BTreeMap<String, ImportOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow: Option<ListChanges>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deny: Option<ListChanges>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    keys: BTreeMap<String, KeyOutcome>,
+}
+
+pub(super) async fn apply_domain(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<ApplyDomainRequest>,
+) -> APIResult<Json<ApplyDomainResponse>> {
+    let mut domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    // Each part of the document needs the same access as the individual
+    // APIs would to read it, or to change it if we're applying
+    let mut permitted = true;
+    if body.flags.is_some() {
+        permitted &= if body.apply {
+            domain
+                .may_administer(&mut db, &auth, Capability::DomainFlags)
+                .await?
+        } else {
+            domain
+                .may_read(&mut db, &auth, Capability::DomainRead)
+                .await?
+        };
+    }
+    if body.entries.is_some() {
+        // Comparing passwords needs the same access as exporting entries
+        permitted &= domain
+            .may_write(&mut db, &auth, Capability::EntriesWrite)
+            .await?;
+    }
+    if body.allow_deny.is_some() {
+        permitted &= if body.apply {
+            domain
+                .may_write(&mut db, &auth, Capability::AllowDenyWrite)
+                .await?
+        } else {
+            domain
+                .may_read(&mut db, &auth, Capability::AllowDenyRead)
+                .await?
+        };
+    }
+    if body.keys.is_some() {
+        permitted &= if body.apply {
+            domain
+                .may_administer(&mut db, &auth, Capability::KeysWrite)
+                .await?
+        } else {
+            domain
+                .may_read(&mut db, &auth, Capability::KeysRead)
+                .await?
+        };
+    }
+    if !permitted {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let allow_deny = body
+        .allow_deny
+        .as_ref()
+        .map(|lists| check_lists(&lists.allow, &lists.deny))
+        .transpose()?;
+
+    let flags_before = domain.audit_json();
+    if let Some(flags) = &body.flags {
+        domain.remotemx = flags.remote_mx.clone().filter(|mx| !mx.is_empty());
+        domain.sender_verify = flags.sender_verify;
+        domain.grey_listing = flags.grey_listing;
+        domain.virus_check = flags.virus_check;
+        domain.spamcheck_threshold = flags.spamcheck_threshold;
+        domain.subaddress_separator = match flags.subaddress_separator.as_deref() {
+            Some(separator) => subaddress_separator(separator)?,
+            None => None,
+        };
+    }
+    let flags_after = domain.audit_json();
+
+    let mut res = ApplyDomainResponse::default();
+    if let (Value::Object(before), Value::Object(after)) = (&flags_before, &flags_after) {
+        for (flag, to) in after {
+            let from = before.get(flag).cloned().unwrap_or_default();
+            if &from != to {
+                res.flags.insert(
+                    flag.clone(),
+                    FlagChange {
+                        from,
+                        to: to.clone(),
+                    },
+                );
+            }
+        }
+    }
+
+    // As with imports, superusers may carry whole domains over from elsewhere
+    let passwords = PasswordChecker::for_domain(&mut db, &config, &domain)
+        .await?
+        .allowing_prehashed(auth.superuser());
+
+    // Flags and entries are always changed, so that expansions can be checked
+    // against the domain as it would be, and then rolled back unless they
+    // are to be kept
+    let res = db
+        .transaction::<_, NotApplied<ApplyDomainResponse>, _>(|db| {
+            async move {
+                let entry_plan = match body.entries {
+                    Some(entries) => Some(
+                        plan_entries(
+                            domain.entries(db).await?,
+                            entries
+                                .into_iter()
+                                .map(|(name, entry)| (name, Ok(entry)))
+                                .collect(),
+                            ImportMode::Replace,
+                            &passwords,
+                        )
+                        .await,
+                    ),
+                    None => None,
+                };
+                let lists_before = match &allow_deny {
+                    Some(desired) => {
+                        let current = current_lists(db, domain.id).await?;
+                        res.allow = ListChanges::between(&current.allow, &desired.allow);
+                        res.deny = ListChanges::between(&current.deny, &desired.deny);
+                        Some(current)
+                    }
+                    None => None,
+                };
+
+                let mut key_changes = Vec::new();
+                if let Some(desired) = &body.keys {
+                    let mut current: BTreeMap<String, MailDomainKey> =
+                        MailDomainKey::by_domain(db, domain.id)
+                            .await?
+                            .into_iter()
+                            .map(|key| (key.selector.clone(), key))
+                            .collect();
+                    for (selector, state) in desired {
+                        match current.remove(selector) {
+                            None => {
+                                res.keys.insert(
+                                    selector.clone(),
+                                    KeyOutcome::Created {
+                                        signing: state.signing,
+                                    },
+                                );
+                                key_changes
+                                    .push(KeyChange::Create(selector.clone(), state.signing));
+                            }
+                            Some(key) if key.signing == state.signing => {}
+                            Some(mut key) => {
+                                let before = key.audit_json();
+                                res.keys.insert(
+                                    selector.clone(),
+                                    KeyOutcome::Updated {
+                                        signing: state.signing,
+                                    },
+                                );
+                                key.signing = state.signing;
+                                key_changes.push(KeyChange::Update(key, before));
+                            }
+                        }
+                    }
+                    for (selector, key) in current {
+                        res.keys.insert(selector, KeyOutcome::Deleted);
+                        key_changes.push(KeyChange::Delete(key));
+                    }
+                }
+
+                if !res.flags.is_empty() {
+                    domain.save(db).await?;
+                    AuditLog::record(
+                        db,
+                        &auth,
+                        "domain:set-flags",
+                        Some(domain.id),
+                        &domain.domainname,
+                        Some(flags_before),
+                        Some(flags_after),
+                    )
+                    .await?;
+                }
+
+                let mut entries_errored = false;
+                if let Some(plan) = entry_plan {
+                    let outcomes = apply_changes(db, &auth, &domain, plan, body.force).await?;
+                    entries_errored = errored(&outcomes);
+                    res.entries = outcomes
+                        .into_iter()
+                        .filter(|(_, outcome)| !matches!(outcome, ImportOutcome::Unchanged))
+                        .collect();
+                }
+
+                res.applied = body.apply && !entries_errored;
+                if !res.applied {
+                    return Err(NotApplied::Rejected(res));
+                }
+
+                if let (Some(before), Some(after)) = (lists_before, allow_deny) {
+                    if res.allow.is_some() || res.deny.is_some() {
+                        AllowDenyList::replace_all(db, domain.id, &after.allow, &after.deny)
+                            .await?;
+                        AuditLog::record(
+                            db,
+                            &auth,
+                            "allow-deny:replace",
+                            Some(domain.id),
+                            &domain.domainname,
+                            serde_json::to_value(&before).ok(),
+                            serde_json::to_value(&after).ok(),
+                        )
+                        .await?;
+                    }
+                }
+
+                for change in key_changes {
+                    match change {
+                        KeyChange::Create(selector, signing) => {
+                            let key =
+                                MailDomainKey::create(db, domain.id, &selector, signing).await?;
+                            AuditLog::record(
+                                db,
+                                &auth,
+                                "key:create",
+                                Some(domain.id),
+                                &key_target(&key, &domain),
+                                None,
+                                Some(key.audit_json()),
+                            )
+                            .await?;
+                        }
+                        KeyChange::Update(key, before) => {
+                            key.save(db).await?;
+                            AuditLog::record(
+                                db,
+                                &auth,
+                                "key:set-signing",
+                                Some(domain.id),
+                                &key_target(&key, &domain),
+                                Some(before),
+                                Some(key.audit_json()),
+                            )
+                            .await?;
+                        }
+                        KeyChange::Delete(key) => {
+                            let target = key_target(&key, &domain);
+                            let before = key.audit_json();
+                            key.delete_self(db).await?;
+                            AuditLog::record(
+                                db,
+                                &auth,
+                                "key:delete",
+                                Some(domain.id),
+                                &target,
+                                Some(before),
+                                None,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+
+                Ok(res)
+            }
+            .scope_boxed()
+        })
+        .await;
+
+    Ok(Json::from(NotApplied::report(res)?))
+}
@@ -1,17 +1,34 @@
 //! Domain entries (logins, accounts, aliases, etc)
 //!
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use axum::{extract::Path, routing::get, Extension, Json, Router};
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
 use mailconfig::{models::*, Connection};
 use serde::{Deserialize, Serialize};
 
+use api_types::lists::{ListMemberFlags, ListSettings, PostPolicy, ReplyTo};
+use api_types::passwords::PasswordStyle;
+use api_types::quota::Quota;
+
 use crate::{
     api::{APIError, APIResult},
+    configuration::Configuration,
     state::AppState,
 };
 
+use super::password_policy::PasswordChecker;
+
+pub(super) mod bulk;
+mod sieve;
+mod trash;
+mod vacation;
+
 #[derive(Serialize, Debug, Default)]
 struct EntryListResponse {
     entries: HashMap<String, EntryListResponseItem>,
@@ -19,14 +36,108 @@ struct EntryListResponse {
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "kind", rename_all = "kebab-case")]
-enum EntryListResponseItem {
+pub(super) enum EntryListResponseItem {
     Login,
-    Account,
-    Alias { expansion: String },
-    Blackhole { reason: String },
-    Bouncer { reason: String },
-    List { members: String },
+    Account {
+        /// Where the account's mail is forwarded to, if anywhere
+        #[serde(skip_serializing_if = "Option::is_none")]
+        forward: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        keep_local_copy: Option<bool>,
+        /// The account's own quota, if it has one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quota: Option<Quota>,
+    },
+    Alias {
+        expansion: String,
+    },
+    Blackhole {
+        reason: String,
+    },
+    Bouncer {
+        reason: String,
+    },
+    List {
+        members: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        settings: Option<ListSettings>,
+        /// Only members with any flags set are included
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        member_flags: BTreeMap<String, ListMemberFlags>,
+    },
+}
+
+impl From<MailEntry> for EntryListResponseItem {
+    fn from(entry: MailEntry) -> Self {
+        let expansion = entry.expansion.unwrap_or_default();
+        match entry.kind {
+            MailEntryKind::Login => Self::Login,
+            MailEntryKind::Account => Self::Account {
+                forward: None,
+                keep_local_copy: None,
+                quota: None,
+            },
+            MailEntryKind::Alias => Self::Alias { expansion },
+            MailEntryKind::Bouncer => Self::Bouncer { reason: expansion },
+            MailEntryKind::Blackhole => Self::Blackhole { reason: expansion },
+            MailEntryKind::List => Self::List {
+                members: expansion,
+                settings: None,
+                member_flags: BTreeMap::new(),
+            },
+        }
+    }
 }
+
+impl EntryListResponseItem {
+    /// Include a list's settings and member flags in the response
+    fn with_list(mut self, list: Option<MailList>) -> Self {
+        if let (
+            Self::List {
+                settings,
+                member_flags,
+                ..
+            },
+            Some(list),
+        ) = (&mut self, list)
+        {
+            *settings = Some(list.settings.to_api());
+            *member_flags = list
+                .members
+                .iter()
+                .filter(|member| !member.flags().is_default())
+                .map(|member| (member.target.clone(), member.flags()))
+                .collect();
+        }
+        self
+    }
+
+    /// Include where an account forwards its mail in the response
+    fn with_forwarding(mut self, forwarding: Option<Forwarding>) -> Self {
+        if let (
+            Self::Account {
+                forward,
+                keep_local_copy,
+                ..
+            },
+            Some(forwarding),
+        ) = (&mut self, forwarding)
+        {
+            *forward = Some(forwarding.targets.join(", "));
+            *keep_local_copy = Some(forwarding.keep_local_copy);
+        }
+        self
+    }
+
+    /// Include an account's own quota in the response
+    fn with_quota(mut self, own: Option<Quota>) -> Self {
+        if let Self::Account { quota, .. } = &mut self {
+            *quota = own;
+        }
+        self
+    }
+}
+
 async fn list_entries(
     mut db: Connection,
     Path(domain_name): Path<String>,
@@ -36,37 +147,28 @@ async fn list_entries(
         .await?
         .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
         return Err(APIError::PermissionDenied(domain_name.clone()));
     }
 
     let mut res = EntryListResponse::default();
+    let mut lists = domain.mail_lists(&mut db).await?;
+    let mut forwardings = domain.forwardings(&mut db).await?;
+    let mut quotas = domain.account_quotas(&mut db).await?;
 
-    for MailEntry {
-        name,
-        kind,
-        expansion,
-        ..
-    } in domain.entries(&mut db).await?
-    {
+    for entry in domain.entries(&mut db).await? {
+        let list = lists.remove(&entry.id);
+        let forwarding = forwardings.remove(&entry.id);
+        let quota = quotas.remove(&entry.id);
         res.entries.insert(
-            name,
-            match kind {
-                MailEntryKind::Login => EntryListResponseItem::Login,
-                MailEntryKind::Account => EntryListResponseItem::Account,
-                MailEntryKind::Alias => EntryListResponseItem::Alias {
-                    expansion: expansion.unwrap_or_default(),
-                },
-                MailEntryKind::Bouncer => EntryListResponseItem::Bouncer {
-                    reason: expansion.unwrap_or_default(),
-                },
-                MailEntryKind::Blackhole => EntryListResponseItem::Blackhole {
-                    reason: expansion.unwrap_or_default(),
-                },
-                MailEntryKind::List => EntryListResponseItem::List {
-                    members: expansion.unwrap_or_default(),
-                },
-            },
+            entry.name.clone(),
+            EntryListResponseItem::from(entry)
+                .with_list(list)
+                .with_forwarding(forwarding)
+                .with_quota(quota),
         );
     }
 
@@ -74,8 +176,10 @@ async fn list_entries(
 }
 
 #[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 struct DeletionResponse {
     deleted: String,
+    trash_id: i32,
 }
 
 async fn delete_entry(
@@ -87,34 +191,92 @@ async fn delete_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(domain_name.clone()));
     }
 
     let full_name = format!("{entry}@{domain_name}");
 
-    let db_entry = domain
+    let mut db_entry = domain
         .entry_by_name(&mut db, &entry)
         .await?
         .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
 
-    db_entry.delete(&mut db).await?;
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            db_entry.trash(db).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "entry:delete",
+                Some(domain.id),
+                &full_name,
+                Some(db_entry.audit_json()),
+                None,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
 
-    Ok(Json::from(DeletionResponse { deleted: full_name }))
+    Ok(Json::from(DeletionResponse {
+        deleted: full_name,
+        trash_id: db_entry.id,
+    }))
 }
 
+/// As given, logins and accounts have an optional password; by the time they
+/// are created it has been checked and hashed
 #[derive(Deserialize, Debug)]
 #[serde(tag = "kind", rename_all = "kebab-case")]
-enum CreateEntryRequest {
-    Login { name: String, password: String },
-    Account { name: String, password: String },
-    Alias { name: String, expansion: String },
-    Bouncer { name: String, reason: String },
-    Blackhole { name: String, reason: String },
-    List { name: String, members: String },
+enum CreateEntryRequest<P = Option<String>> {
+    Login {
+        name: String,
+        /// Made up by the server if left out
+        #[serde(default)]
+        password: P,
+        #[serde(default, rename = "password-style")]
+        password_style: Option<PasswordStyle>,
+    },
+    Account {
+        name: String,
+        #[serde(default)]
+        password: P,
+        #[serde(default, rename = "password-style")]
+        password_style: Option<PasswordStyle>,
+        #[serde(default)]
+        quota: Option<Quota>,
+    },
+    Alias {
+        name: String,
+        expansion: String,
+        #[serde(default)]
+        force: bool,
+    },
+    Bouncer {
+        name: String,
+        reason: String,
+    },
+    Blackhole {
+        name: String,
+        reason: String,
+    },
+    List {
+        name: String,
+        members: String,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        settings: Option<ListSettings>,
+    },
 }
 
-impl CreateEntryRequest {
+impl<P> CreateEntryRequest<P> {
     fn name(&self) -> &str {
         match self {
             Self::Login { name, .. } => name,
@@ -127,13 +289,156 @@ impl CreateEntryRequest {
     }
 }
 
+/// Check and hash a new password for the entry `name`, or make one up if none
+/// is given, returning the made up one as well
+async fn new_password(
+    db: &mut AsyncPgConnection,
+    config: &Configuration,
+    domain: &MailDomain,
+    auth: &Authorisation,
+    name: &str,
+    password: Option<&str>,
+    style: Option<PasswordStyle>,
+) -> APIResult<(HashedPassword, Option<GeneratedPassword>)> {
+    let passwords = PasswordChecker::for_domain(db, config, domain)
+        .await?
+        .allowing_prehashed(auth.superuser());
+    Ok(match password {
+        Some(password) => {
+            passwords.check(name, password).await?;
+            (HashedPassword::new(password)?, None)
+        }
+        None => {
+            let made_up = passwords.generate(name, style).await?;
+            (HashedPassword::new(made_up.as_str())?, Some(made_up))
+        }
+    })
+}
+
+impl CreateEntryRequest {
+    /// Check and hash a login's or account's password, or make one up, ahead
+    /// of creating it since that can be slow.  The made up password is
+    /// returned alongside.
+    async fn hash_password(
+        self,
+        db: &mut AsyncPgConnection,
+        config: &Configuration,
+        domain: &MailDomain,
+        auth: &Authorisation,
+    ) -> APIResult<(
+        CreateEntryRequest<HashedPassword>,
+        Option<GeneratedPassword>,
+    )> {
+        Ok(match self {
+            Self::Login {
+                name,
+                password,
+                password_style,
+            } => {
+                let (password, made_up) = new_password(
+                    db,
+                    config,
+                    domain,
+                    auth,
+                    &name,
+                    password.as_deref(),
+                    password_style,
+                )
+                .await?;
+                let login = CreateEntryRequest::Login {
+                    name,
+                    password,
+                    password_style,
+                };
+                (login, made_up)
+            }
+            Self::Account {
+                name,
+                password,
+                password_style,
+                quota,
+            } => {
+                let (password, made_up) = new_password(
+                    db,
+                    config,
+                    domain,
+                    auth,
+                    &name,
+                    password.as_deref(),
+                    password_style,
+                )
+                .await?;
+                let account = CreateEntryRequest::Account {
+                    name,
+                    password,
+                    password_style,
+                    quota,
+                };
+                (account, made_up)
+            }
+            Self::Alias {
+                name,
+                expansion,
+                force,
+            } => (
+                CreateEntryRequest::Alias {
+                    name,
+                    expansion,
+                    force,
+                },
+                None,
+            ),
+            Self::Bouncer { name, reason } => (CreateEntryRequest::Bouncer { name, reason }, None),
+            Self::Blackhole { name, reason } => {
+                (CreateEntryRequest::Blackhole { name, reason }, None)
+            }
+            Self::List {
+                name,
+                members,
+                force,
+                settings,
+            } => (
+                CreateEntryRequest::List {
+                    name,
+                    members,
+                    force,
+                    settings,
+                },
+                None,
+            ),
+        })
+    }
+}
+
+/// Check a proposed alias or list expansion, returning it in normal form
+pub(super) async fn check_expansion(
+    db: &mut AsyncPgConnection,
+    domain: &MailDomain,
+    name: &str,
+    expansion: &str,
+    force: bool,
+) -> APIResult<String> {
+    let targets = domain
+        .check_expansion(db, name, expansion, force)
+        .await?
+        .map_err(|problem| {
+            APIError::from_expansion(problem, &format!("{name}@{}", domain.domainname))
+        })?;
+    Ok(render_expansion(&targets))
+}
+
 #[derive(Serialize, Debug)]
 struct CreationResponse {
     created: String,
+    /// Only present if the server made the password up, since this is the
+    /// only time it is ever shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<GeneratedPassword>,
 }
 
 async fn create_entry(
     mut db: Connection,
+    State(config): State<Configuration>,
     Path(domain_name): Path<String>,
     Extension(auth): Extension<Authorisation>,
     Json(body): Json<CreateEntryRequest>,
@@ -142,34 +447,105 @@ async fn create_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(domain_name.clone()));
     }
 
     let full_name = format!("{}@{domain_name}", body.name());
 
-    match body {
-        CreateEntryRequest::Login { name, password } => {
-            domain.new_login(&mut db, &name, &password, false).await?
-        }
-        CreateEntryRequest::Account { name, password } => {
-            domain.new_login(&mut db, &name, &password, true).await?
-        }
-        CreateEntryRequest::Alias { name, expansion } => {
-            domain.new_alias(&mut db, &name, &expansion).await?
-        }
-        CreateEntryRequest::Bouncer { name, reason } => {
-            domain.new_bouncer(&mut db, &name, &reason).await?
-        }
-        CreateEntryRequest::Blackhole { name, reason } => {
-            domain.new_blackhole(&mut db, &name, &reason).await?
-        }
-        CreateEntryRequest::List { name, members } => {
-            domain.new_list(&mut db, &name, &members).await?
-        }
+    if body.name() == CATCH_ALL
+        && !matches!(
+            body,
+            CreateEntryRequest::Alias { .. }
+                | CreateEntryRequest::Bouncer { .. }
+                | CreateEntryRequest::Blackhole { .. }
+        )
+    {
+        return Err(APIError::CatchAllKind(full_name));
     }
+    if body.name() != CATCH_ALL && !valid_entry_name(body.name()) {
+        return Err(APIError::BadEntryName(full_name));
+    }
+
+    let (body, generated) = body.hash_password(&mut db, &config, &domain, &auth).await?;
 
-    Ok(Json::from(CreationResponse { created: full_name }))
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let name = body.name().to_string();
+            match body {
+                CreateEntryRequest::Login { name, password, .. } => {
+                    domain.new_login(db, &name, &password, false).await?
+                }
+                CreateEntryRequest::Account {
+                    name,
+                    password,
+                    quota,
+                    ..
+                } => {
+                    domain.new_login(db, &name, &password, true).await?;
+                    if let (Some(quota), Some(created)) =
+                        (quota, domain.entry_by_name(db, &name).await?)
+                    {
+                        created.set_quota(db, &quota).await?;
+                    }
+                    // Even without a quota of its own, it takes up the default
+                    check_quota(db, &domain, &full_name, &quota.unwrap_or_default()).await?;
+                }
+                CreateEntryRequest::Alias {
+                    name,
+                    expansion,
+                    force,
+                } => {
+                    let expansion = check_expansion(db, &domain, &name, &expansion, force).await?;
+                    domain.new_alias(db, &name, &expansion).await?
+                }
+                CreateEntryRequest::Bouncer { name, reason } => {
+                    domain.new_bouncer(db, &name, &reason).await?
+                }
+                CreateEntryRequest::Blackhole { name, reason } => {
+                    domain.new_blackhole(db, &name, &reason).await?
+                }
+                CreateEntryRequest::List {
+                    name,
+                    members,
+                    force,
+                    settings,
+                } => {
+                    let members = check_expansion(db, &domain, &name, &members, force).await?;
+                    domain.new_list(db, &name, &members).await?;
+                    if let (Some(settings), Some(created)) =
+                        (settings, domain.entry_by_name(db, &name).await?)
+                    {
+                        let mut list = MailListSettings::defaults(created.id);
+                        list.apply(&settings);
+                        list.save(db).await?;
+                    }
+                }
+            }
+            let created = domain.entry_by_name(db, &name).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "entry:create",
+                Some(domain.id),
+                &full_name,
+                None,
+                created.map(|e| e.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(CreationResponse {
+        created: full_name,
+        password: generated,
+    }))
 }
 
 async fn get_entry(
@@ -181,7 +557,10 @@ async fn get_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
         return Err(APIError::PermissionDenied(domain_name.clone()));
     }
 
@@ -192,41 +571,149 @@ async fn get_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
 
-    Ok(Json::from(match db_entry.kind {
-        MailEntryKind::Login => EntryListResponseItem::Login,
-        MailEntryKind::Account => EntryListResponseItem::Account,
-        MailEntryKind::Alias => EntryListResponseItem::Alias {
-            expansion: db_entry.expansion.unwrap_or_default(),
-        },
-        MailEntryKind::Bouncer => EntryListResponseItem::Bouncer {
-            reason: db_entry.expansion.unwrap_or_default(),
-        },
-        MailEntryKind::Blackhole => EntryListResponseItem::Blackhole {
-            reason: db_entry.expansion.unwrap_or_default(),
-        },
-        MailEntryKind::List => EntryListResponseItem::List {
-            members: db_entry.expansion.unwrap_or_default(),
-        },
-    }))
+    let list = db_entry.mail_list(&mut db).await?;
+    let forwarding = db_entry.forwarding(&mut db).await?;
+    let quota = db_entry.quota(&mut db).await?;
+    Ok(Json::from(
+        EntryListResponseItem::from(db_entry)
+            .with_list(list)
+            .with_forwarding(forwarding)
+            .with_quota(quota),
+    ))
+}
+
+/// A new password, or `null` for one to be made up.  One of the fields must
+/// be given, so that other edits aren't taken for this.
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "SetPasswordFields")]
+struct SetPasswordRequest {
+    password: Option<String>,
+    password_style: Option<PasswordStyle>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SetPasswordFields {
+    /// Outer `None` if left out, inner `None` if `null`
+    #[serde(default, deserialize_with = "given")]
+    password: Option<Option<String>>,
+    #[serde(default)]
+    password_style: Option<PasswordStyle>,
+}
+
+fn given<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(d).map(Some)
+}
+
+impl TryFrom<SetPasswordFields> for SetPasswordRequest {
+    type Error = &'static str;
+
+    fn try_from(fields: SetPasswordFields) -> Result<Self, Self::Error> {
+        match fields {
+            SetPasswordFields {
+                password: None,
+                password_style: None,
+            } => Err("neither password nor password-style given"),
+            SetPasswordFields {
+                password,
+                password_style,
+            } => Ok(Self {
+                password: password.flatten(),
+                password_style,
+            }),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(untagged, rename_all = "kebab-case")]
 enum EditEntryRequest {
-    SetPassword { password: String },
-    Expansion { expansion: String },
-    AddExpansion { add: String },
-    RemoveExpansion { remove: String },
-    ChangeReason { reason: String },
+    SetPassword(SetPasswordRequest),
+    Expansion {
+        expansion: String,
+        #[serde(default)]
+        force: bool,
+    },
+    AddExpansion {
+        add: String,
+        #[serde(default)]
+        force: bool,
+    },
+    RemoveExpansion {
+        remove: String,
+    },
+    ChangeReason {
+        reason: String,
+    },
+    Forward {
+        /// Comma separated; empty to stop forwarding
+        forward: String,
+        #[serde(default, rename = "keep-local-copy")]
+        keep_local_copy: Option<bool>,
+        #[serde(default)]
+        force: bool,
+    },
+    KeepLocalCopy {
+        #[serde(rename = "keep-local-copy")]
+        keep_local_copy: bool,
+    },
+    Quota {
+        quota: Quota,
+    },
+    List(EditListRequest),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged, rename_all = "kebab-case")]
+enum EditListRequest {
+    Settings {
+        settings: ListSettingsChange,
+    },
+    MemberFlags {
+        member: String,
+        flags: ListMemberFlags,
+    },
+}
+
+/// Changes to a list's settings; anything left out is unchanged
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ListSettingsChange {
+    #[serde(default)]
+    post_policy: Option<PostPolicy>,
+    #[serde(default)]
+    reply_to: Option<ReplyTo>,
+    /// An empty prefix removes it
+    #[serde(default)]
+    subject_prefix: Option<String>,
+}
+
+/// Changes to where an account forwards its mail; anything left out is
+/// unchanged
+struct ForwardingChange {
+    /// The new forwarding targets, and whether to skip checking them
+    forward: Option<(String, bool)>,
+    keep_local_copy: Option<bool>,
+}
+
+/// Adding or removing a single target of an alias or list, which is done
+/// directly on its stored targets
+enum TargetChange {
+    Add(String, bool),
+    Remove(String),
 }
 
 #[derive(Serialize, Debug)]
 struct EditEntryResponse {
     updated: String,
+    /// Only present if the server made up a new password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<GeneratedPassword>,
 }
 
 async fn update_entry(
     mut db: Connection,
+    State(config): State<Configuration>,
     Path((domain_name, entry)): Path<(String, String)>,
     Extension(auth): Extension<Authorisation>,
     Json(body): Json<EditEntryRequest>,
@@ -235,7 +722,10 @@ async fn update_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(domain_name.clone()));
     }
 
@@ -246,55 +736,58 @@ async fn update_entry(
         .await?
         .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
 
+    let before = db_entry.audit_json();
+    let action = match body {
+        EditEntryRequest::SetPassword(_) => "entry:set-password",
+        _ => "entry:update",
+    };
+
+    // Set if the server made up a new password, to be shown just this once
+    let mut generated = None;
+    // Set if the expansion has grown and so needs checking
+    let mut check_force = None;
+    // Set if a single target is being added or removed
+    let mut target_change = None;
+
     match body {
-        EditEntryRequest::SetPassword { password } => {
+        EditEntryRequest::SetPassword(SetPasswordRequest {
+            password,
+            password_style,
+        }) => {
             if !matches!(db_entry.kind, MailEntryKind::Login | MailEntryKind::Account) {
                 return Err(APIError::NotLoginOrAccount(full_name));
             }
-            db_entry.set_password(&password);
+            let (hashed, made_up) = new_password(
+                &mut db,
+                &config,
+                &domain,
+                &auth,
+                &entry,
+                password.as_deref(),
+                password_style,
+            )
+            .await?;
+            db_entry.set_password(hashed);
+            generated = made_up;
         }
-        EditEntryRequest::Expansion { expansion } => {
+        EditEntryRequest::Expansion { expansion, force } => {
             if !matches!(db_entry.kind, MailEntryKind::Alias) {
                 return Err(APIError::NotAlias(full_name));
             }
             db_entry.expansion = Some(expansion);
+            check_force = Some(force);
         }
-        EditEntryRequest::AddExpansion { add } => {
-            if !matches!(db_entry.kind, MailEntryKind::Alias | MailEntryKind::List) {
+        EditEntryRequest::AddExpansion { add, force } => {
+            if !db_entry.has_targets() {
                 return Err(APIError::NotAlias(full_name));
             }
-            let mut bits: Vec<&str> = db_entry
-                .expansion
-                .as_deref()
-                .unwrap_or("")
-                .split(',')
-                .map(str::trim)
-                .collect();
-            if !bits.contains(&add.as_str()) {
-                bits.push(&add);
-            }
-            db_entry.expansion = Some(bits.join(", "));
+            target_change = Some(TargetChange::Add(add, force));
         }
         EditEntryRequest::RemoveExpansion { remove } => {
-            if !matches!(db_entry.kind, MailEntryKind::Alias | MailEntryKind::List) {
+            if !db_entry.has_targets() {
                 return Err(APIError::NotAlias(full_name));
             }
-            let bits: Vec<&str> = db_entry
-                .expansion
-                .as_deref()
-                .unwrap_or("")
-                .split(',')
-                .map(str::trim)
-                .filter(|e| e != &remove)
-                .collect();
-            if bits.is_empty() {
-                return Err(APIError::AliasWouldBecomeEmpty(full_name));
-            }
-            let new_expn = Some(bits.join(", "));
-            if new_expn == db_entry.expansion {
-                return Err(APIError::AliasComponentNotFound(remove));
-            }
-            db_entry.expansion = new_expn;
+            target_change = Some(TargetChange::Remove(remove));
         }
         EditEntryRequest::ChangeReason { reason } => {
             if !matches!(
@@ -305,18 +798,286 @@ async fn update_entry(
             }
             db_entry.expansion = Some(reason);
         }
+        EditEntryRequest::Forward {
+            forward,
+            keep_local_copy,
+            force,
+        } => {
+            let change = ForwardingChange {
+                forward: Some((forward, force)),
+                keep_local_copy,
+            };
+            update_forwarding(&mut db, &auth, &domain, db_entry, &full_name, change).await?;
+            return Ok(Json::from(EditEntryResponse {
+                updated: full_name,
+                password: None,
+            }));
+        }
+        EditEntryRequest::KeepLocalCopy { keep_local_copy } => {
+            let change = ForwardingChange {
+                forward: None,
+                keep_local_copy: Some(keep_local_copy),
+            };
+            update_forwarding(&mut db, &auth, &domain, db_entry, &full_name, change).await?;
+            return Ok(Json::from(EditEntryResponse {
+                updated: full_name,
+                password: None,
+            }));
+        }
+        EditEntryRequest::Quota { quota } => {
+            update_quota(&mut db, &auth, &domain, &db_entry, &full_name, quota).await?;
+            return Ok(Json::from(EditEntryResponse {
+                updated: full_name,
+                password: None,
+            }));
+        }
+        EditEntryRequest::List(change) => {
+            // List settings are kept apart from the entry itself
+            update_list(&mut db, &auth, &domain, &db_entry, &full_name, change).await?;
+            return Ok(Json::from(EditEntryResponse {
+                updated: full_name,
+                password: None,
+            }));
+        }
+    }
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            match target_change {
+                Some(TargetChange::Add(add, force)) => {
+                    let current = db_entry.expansion.as_deref().unwrap_or("");
+                    let proposed = format!("{current}, {add}");
+                    check_expansion(db, &domain, &entry, &proposed, force).await?;
+                    // Checked above, so this will parse
+                    let target = ExpansionTarget::parse(add.trim())
+                        .map(|target| target.to_string())
+                        .unwrap_or(add);
+                    db_entry.add_target(db, &target).await?;
+                }
+                Some(TargetChange::Remove(remove)) => {
+                    let remove = remove.trim();
+                    let target = ExpansionTarget::parse(remove)
+                        .map(|target| target.to_string())
+                        .unwrap_or_else(|| remove.to_string());
+                    if !db_entry.remove_target(db, &target).await? {
+                        return Err(APIError::AliasComponentNotFound(target));
+                    }
+                    if db_entry.target_count(db).await? == 0 {
+                        return Err(APIError::AliasWouldBecomeEmpty(full_name.clone()));
+                    }
+                }
+                None => {
+                    if let Some(force) = check_force {
+                        let expansion = db_entry.expansion.as_deref().unwrap_or("");
+                        let expansion =
+                            check_expansion(db, &domain, &entry, expansion, force).await?;
+                        db_entry.expansion = Some(expansion);
+                    }
+                    db_entry.save(db).await?;
+                }
+            }
+            let after = domain
+                .entry_by_name(db, &entry)
+                .await?
+                .map(|entry| entry.audit_json());
+            AuditLog::record(
+                db,
+                &auth,
+                action,
+                Some(domain.id),
+                &full_name,
+                Some(before),
+                after,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(EditEntryResponse {
+        updated: full_name,
+        password: generated,
+    }))
+}
+
+/// Change a list's settings, or the flags on one of its members
+async fn update_list(
+    db: &mut AsyncPgConnection,
+    auth: &Authorisation,
+    domain: &MailDomain,
+    db_entry: &MailEntry,
+    full_name: &str,
+    change: EditListRequest,
+) -> APIResult<()> {
+    let Some(mut list) = db_entry.mail_list(db).await? else {
+        return Err(APIError::NotList(full_name.to_string()));
+    };
+    let before = list.audit_json();
+
+    db.transaction::<_, APIError, _>(|db| {
+        async move {
+            let action = match change {
+                EditListRequest::Settings { settings } => {
+                    let mut desired = list.settings.to_api();
+                    if let Some(post_policy) = settings.post_policy {
+                        desired.post_policy = post_policy;
+                    }
+                    if let Some(reply_to) = settings.reply_to {
+                        desired.reply_to = reply_to;
+                    }
+                    if let Some(subject_prefix) = settings.subject_prefix {
+                        desired.subject_prefix = Some(subject_prefix);
+                    }
+                    list.settings.apply(&desired);
+                    list.settings.save(db).await?;
+                    "entry:list-settings"
+                }
+                EditListRequest::MemberFlags { member, flags } => {
+                    let member = member.trim();
+                    let target = ExpansionTarget::parse(member)
+                        .map(|target| target.to_string())
+                        .unwrap_or_else(|| member.to_string());
+                    if !db_entry.set_member_flags(db, &target, flags).await? {
+                        return Err(APIError::AliasComponentNotFound(target));
+                    }
+                    "entry:list-member"
+                }
+            };
+            let after = db_entry.mail_list(db).await?.map(|list| list.audit_json());
+            AuditLog::record(
+                db,
+                auth,
+                action,
+                Some(domain.id),
+                full_name,
+                Some(before),
+                after,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Check that an account's own quota, once set, is within its domain's limit,
+/// and that the domain's accounts still fit within it between them.  This
+/// must be within the transaction which sets it.
+async fn check_quota(
+    db: &mut AsyncPgConnection,
+    domain: &MailDomain,
+    full_name: &str,
+    quota: &Quota,
+) -> APIResult<()> {
+    if domain.accounts_within_quota(db).await? && domain.quota(db).await?.permits(quota) {
+        Ok(())
+    } else {
+        Err(APIError::QuotaOverLimit(full_name.to_string()))
     }
+}
 
-    db_entry.save(&mut db).await?;
+/// Set an account's own quota, or remove it by giving an unlimited one
+async fn update_quota(
+    db: &mut AsyncPgConnection,
+    auth: &Authorisation,
+    domain: &MailDomain,
+    db_entry: &MailEntry,
+    full_name: &str,
+    quota: Quota,
+) -> APIResult<()> {
+    if db_entry.kind != MailEntryKind::Account {
+        return Err(APIError::NotAccount(full_name.to_string()));
+    }
+    let before = db_entry.quota(db).await?;
 
-    Ok(Json::from(EditEntryResponse { updated: full_name }))
+    db.transaction::<_, APIError, _>(|db| {
+        async move {
+            db_entry.set_quota(db, &quota).await?;
+            check_quota(db, domain, full_name, &quota).await?;
+            let after = db_entry.quota(db).await?;
+            AuditLog::record(
+                db,
+                auth,
+                "entry:quota",
+                Some(domain.id),
+                full_name,
+                before.map(|quota| serde_json::json!(quota)),
+                after.map(|quota| serde_json::json!(quota)),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Change where an account forwards its mail, and whether it keeps a copy
+async fn update_forwarding(
+    db: &mut AsyncPgConnection,
+    auth: &Authorisation,
+    domain: &MailDomain,
+    mut db_entry: MailEntry,
+    full_name: &str,
+    change: ForwardingChange,
+) -> APIResult<()> {
+    if db_entry.kind != MailEntryKind::Account {
+        return Err(APIError::NotAccount(full_name.to_string()));
+    }
+    let before = db_entry.forwarding(db).await?.map(|f| f.audit_json());
+
+    db.transaction::<_, APIError, _>(|db| {
+        async move {
+            if let Some((forward, force)) = change.forward {
+                let forward = match forward.trim() {
+                    "" => String::new(),
+                    forward => check_expansion(db, domain, &db_entry.name, forward, force).await?,
+                };
+                let targets: Vec<&str> = forward.split(", ").filter(|t| !t.is_empty()).collect();
+                db_entry.set_forwards(db, &targets).await?;
+            }
+            if let Some(keep_local_copy) = change.keep_local_copy {
+                db_entry.keep_local_copy = keep_local_copy;
+                db_entry.save(db).await?;
+            }
+            let after = db_entry.forwarding(db).await?.map(|f| f.audit_json());
+            AuditLog::record(
+                db,
+                auth,
+                "entry:forwarding",
+                Some(domain.id),
+                full_name,
+                before,
+                after,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
 }
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/:domain_name", get(list_entries).put(create_entry))
+        .route("/:domain_name/export", get(bulk::export_entries))
+        .route("/:domain_name/import", post(bulk::import_entries))
+        .route("/:domain_name/trash", get(trash::list_trash))
+        .route("/:domain_name/trash/:id", post(trash::restore_entry))
         .route(
             "/:domain_name/:entry",
             get(get_entry).delete(delete_entry).post(update_entry),
         )
+        .route(
+            "/:domain_name/:entry/sieve",
+            get(sieve::get_sieve).put(sieve::set_sieve),
+        )
+        .route(
+            "/:domain_name/:entry/vacation",
+            get(vacation::get_vacation).put(vacation::set_vacation),
+        )
 }
@@ -0,0 +1,875 @@
+//! Bulk import and export of a domain's entries
+//!
+//! Documents are either JSON, mapping entry names to entries in the same
+//! shape as the entry list (with passwords), or CSV with a `name,kind,value`
+//! header where the value is the password, expansion, reason, or members
+//! depending on the kind of entry.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
+use mailconfig::{models::*, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{APIError, APIResult},
+    configuration::Configuration,
+};
+
+use super::super::password_policy::PasswordChecker;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum DocumentFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(in crate::api::domain) enum DocumentEntry {
+    Login { password: String },
+    Account { password: String },
+    Alias { expansion: String },
+    Bouncer { reason: String },
+    Blackhole { reason: String },
+    List { members: String },
+}
+
+impl DocumentEntry {
+    fn from_parts(kind: &str, value: String) -> Option<Self> {
+        Some(match kind {
+            "login" => Self::Login { password: value },
+            "account" => Self::Account { password: value },
+            "alias" => Self::Alias { expansion: value },
+            "bouncer" => Self::Bouncer { reason: value },
+            "blackhole" => Self::Blackhole { reason: value },
+            "list" => Self::List { members: value },
+            _ => return None,
+        })
+    }
+
+    fn kind(&self) -> MailEntryKind {
+        match self {
+            Self::Login { .. } => MailEntryKind::Login,
+            Self::Account { .. } => MailEntryKind::Account,
+            Self::Alias { .. } => MailEntryKind::Alias,
+            Self::Bouncer { .. } => MailEntryKind::Bouncer,
+            Self::Blackhole { .. } => MailEntryKind::Blackhole,
+            Self::List { .. } => MailEntryKind::List,
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self {
+            Self::Login { .. } => "login",
+            Self::Account { .. } => "account",
+            Self::Alias { .. } => "alias",
+            Self::Bouncer { .. } => "bouncer",
+            Self::Blackhole { .. } => "blackhole",
+            Self::List { .. } => "list",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            Self::Login { password } | Self::Account { password } => password,
+            Self::Alias { expansion } => expansion,
+            Self::Bouncer { reason } | Self::Blackhole { reason } => reason,
+            Self::List { members } => members,
+        }
+    }
+
+    fn has_password(&self) -> bool {
+        matches!(self, Self::Login { .. } | Self::Account { .. })
+    }
+
+    /// Whether an existing entry already matches this one
+    fn matches(&self, entry: &MailEntry) -> bool {
+        if entry.kind != self.kind() {
+            return false;
+        }
+        match self {
+            Self::Login { password } | Self::Account { password } => {
+                entry.password_matches(password)
+            }
+            Self::Alias { expansion } | Self::List { members: expansion } => {
+                render_expansion(&entry.targets()) == *expansion
+            }
+            Self::Bouncer { reason } | Self::Blackhole { reason } => {
+                entry.expansion.as_deref() == Some(reason.as_str())
+            }
+        }
+    }
+
+    fn is_expansion(&self) -> bool {
+        matches!(self, Self::Alias { .. } | Self::List { .. })
+    }
+
+    /// Check the entry makes sense, returning it with any expansion in
+    /// normal form, or the reason if not
+    fn validate(self, name: &str) -> Result<Self, String> {
+        if name == CATCH_ALL {
+            if !matches!(
+                self,
+                Self::Alias { .. } | Self::Bouncer { .. } | Self::Blackhole { .. }
+            ) {
+                return Err("The catch-all must be an alias, bouncer or blackhole".into());
+            }
+        } else if !valid_entry_name(name) {
+            return Err(format!("Invalid entry name: {name}"));
+        }
+        let normalise = |expansion: &str| match parse_expansion(expansion) {
+            Ok(targets) => Ok(render_expansion(&targets)),
+            Err(ExpansionProblem::Invalid(target)) => Err(format!("Bad target: {target}")),
+            Err(_) => Err("Expansion may not be empty".into()),
+        };
+        match self {
+            Self::Login { password } | Self::Account { password } if password.is_empty() => {
+                Err("Password may not be empty".into())
+            }
+            entry @ (Self::Login { .. } | Self::Account { .. }) => {
+                HashedPassword::check(entry.value()).map_err(|e| format!("Bad password: {e}"))?;
+                Ok(entry)
+            }
+            Self::Alias { expansion } => Ok(Self::Alias {
+                expansion: normalise(&expansion)?,
+            }),
+            Self::List { members } => Ok(Self::List {
+                members: normalise(&members)?,
+            }),
+            entry => Ok(entry),
+        }
+    }
+}
+
+impl From<MailEntry> for DocumentEntry {
+    fn from(entry: MailEntry) -> Self {
+        let value = match entry.kind {
+            MailEntryKind::Login | MailEntryKind::Account => entry.password,
+            _ => entry.expansion,
+        }
+        .unwrap_or_default();
+        match entry.kind {
+            MailEntryKind::Login => Self::Login { password: value },
+            MailEntryKind::Account => Self::Account { password: value },
+            MailEntryKind::Alias => Self::Alias { expansion: value },
+            MailEntryKind::Bouncer => Self::Bouncer { reason: value },
+            MailEntryKind::Blackhole => Self::Blackhole { reason: value },
+            MailEntryKind::List => Self::List { members: value },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    entries: BTreeMap<String, DocumentEntry>,
+}
+
+const CSV_HEADER: [&str; 3] = ["name", "kind", "value"];
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn render_csv(document: &Document) -> String {
+    let mut out = CSV_HEADER.join(",");
+    out.push_str("\r\n");
+    for (name, entry) in &document.entries {
+        let row = [name.as_str(), entry.kind_name(), entry.value()];
+        out.push_str(&row.map(csv_field).join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Split CSV text into records of fields, returning each record along with
+/// the line number on which it starts
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if !(record.len() == 1 && record[0].is_empty()) {
+                    records.push((record_line, std::mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(format!(
+            "Unterminated quoted field starting on line {record_line}"
+        ));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+
+    Ok(records)
+}
+
+pub(in crate::api::domain) type DocumentItems = Vec<(String, Result<DocumentEntry, String>)>;
+
+fn parse_document(format: DocumentFormat, body: &str) -> APIResult<DocumentItems> {
+    match format {
+        DocumentFormat::Json => {
+            let document: Document =
+                serde_json::from_str(body).map_err(|e| APIError::BadImport(e.to_string()))?;
+            Ok(document
+                .entries
+                .into_iter()
+                .map(|(name, entry)| (name, Ok(entry)))
+                .collect())
+        }
+        DocumentFormat::Csv => {
+            let mut records = parse_csv(body).map_err(APIError::BadImport)?.into_iter();
+            match records.next() {
+                Some((_, header)) if header == CSV_HEADER => {}
+                _ => {
+                    return Err(APIError::BadImport(format!(
+                        "CSV must start with a {} header",
+                        CSV_HEADER.join(",")
+                    )))
+                }
+            }
+            records
+                .map(|(line, record)| {
+                    let [name, kind, value]: [String; 3] = record.try_into().map_err(|_| {
+                        APIError::BadImport(format!("Line {line} does not have three fields"))
+                    })?;
+                    let entry = DocumentEntry::from_parts(&kind, value)
+                        .ok_or_else(|| format!("Unknown entry kind: {kind}"));
+                    Ok((name, entry))
+                })
+                .collect()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(super) struct ExportQuery {
+    #[serde(default)]
+    format: DocumentFormat,
+}
+
+pub(super) async fn export_entries(
+    mut db: Connection,
+    Path(domain_name): Path<String>,
+    Extension(auth): Extension<Authorisation>,
+    Query(query): Query<ExportQuery>,
+) -> APIResult<Response> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    // Exports contain password hashes, so are restricted to those who could
+    // set the passwords anyway
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let document = Document {
+        entries: domain
+            .entries(&mut db)
+            .await?
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry.into()))
+            .collect(),
+    };
+
+    Ok(match query.format {
+        DocumentFormat::Json => Json::from(document).into_response(),
+        DocumentFormat::Csv => (
+            [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+            render_csv(&document),
+        )
+            .into_response(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(in crate::api::domain) enum ImportMode {
+    /// Create and update entries, leaving others alone
+    #[default]
+    Merge,
+    /// As with merge, but also delete entries not in the document
+    Replace,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct ImportQuery {
+    #[serde(default)]
+    format: DocumentFormat,
+    #[serde(default)]
+    mode: ImportMode,
+    #[serde(default)]
+    dry_run: bool,
+    /// Accept expansions which refer to missing entries, bouncers or
+    /// blackholes
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub(in crate::api::domain) enum ImportOutcome {
+    Created,
+    Updated,
+    Deleted,
+    Unchanged,
+    Errored { reason: String },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct ImportResponse {
+    mode: ImportMode,
+    dry_run: bool,
+    applied: bool,
+    entries: BTreeMap<String, ImportOutcome>,
+}
+
+pub(in crate::api::domain) enum ImportChange {
+    Create(String, DocumentEntry),
+    Update(MailEntry, DocumentEntry),
+    Delete(MailEntry),
+}
+
+/// Make all the changes in a plan, then check the expansions of the aliases
+/// and lists it creates or updates, returning the plan's outcomes with any
+/// unacceptable expansions marked as errored.  Expansions are only checked
+/// once every entry is in place, so that entries in a document may refer to
+/// one another in any order, and are checked against the domain as it would
+/// be.  Callers must roll the changes back if anything errored, or if they
+/// only wanted to see what would happen.
+pub(in crate::api::domain) async fn apply_changes(
+    db: &mut AsyncPgConnection,
+    auth: &Authorisation,
+    domain: &MailDomain,
+    plan: EntryPlan,
+    force: bool,
+) -> APIResult<BTreeMap<String, ImportOutcome>> {
+    let EntryPlan {
+        mut outcomes,
+        changes,
+    } = plan;
+    let mut expansions = Vec::new();
+    for change in changes {
+        match &change {
+            ImportChange::Create(name, entry) if entry.is_expansion() => {
+                expansions.push((name.clone(), entry.value().to_string()))
+            }
+            ImportChange::Update(db_entry, entry) if entry.is_expansion() => {
+                expansions.push((db_entry.name.clone(), entry.value().to_string()))
+            }
+            _ => {}
+        }
+        apply_change(db, auth, domain, change).await?;
+    }
+    for (name, expansion) in expansions {
+        if let Err(problem) = domain.check_expansion(db, &name, &expansion, force).await? {
+            let address = format!("{name}@{}", domain.domainname);
+            outcomes.insert(
+                name,
+                ImportOutcome::Errored {
+                    reason: APIError::from_expansion(problem, &address).to_string(),
+                },
+            );
+        }
+    }
+    // New accounts take up the domain's default quota
+    if !errored(&outcomes) && !domain.accounts_within_quota(db).await? {
+        return Err(APIError::QuotaOverLimit(domain.domainname.clone()));
+    }
+    Ok(outcomes)
+}
+
+async fn apply_change(
+    db: &mut AsyncPgConnection,
+    auth: &Authorisation,
+    domain: &MailDomain,
+    change: ImportChange,
+) -> APIResult<()> {
+    match change {
+        ImportChange::Create(name, entry) => {
+            match &entry {
+                DocumentEntry::Login { password } => {
+                    domain
+                        .new_login(db, &name, &HashedPassword::new(password)?, false)
+                        .await?
+                }
+                DocumentEntry::Account { password } => {
+                    domain
+                        .new_login(db, &name, &HashedPassword::new(password)?, true)
+                        .await?
+                }
+                DocumentEntry::Alias { expansion } => {
+                    domain.new_alias(db, &name, expansion).await?
+                }
+                DocumentEntry::Bouncer { reason } => domain.new_bouncer(db, &name, reason).await?,
+                DocumentEntry::Blackhole { reason } => {
+                    domain.new_blackhole(db, &name, reason).await?
+                }
+                DocumentEntry::List { members } => domain.new_list(db, &name, members).await?,
+            }
+            let created = domain.entry_by_name(db, &name).await?;
+            AuditLog::record(
+                db,
+                auth,
+                "entry:create",
+                Some(domain.id),
+                &format!("{name}@{}", domain.domainname),
+                None,
+                created.map(|e| e.audit_json()),
+            )
+            .await?;
+        }
+        ImportChange::Update(mut db_entry, entry) => {
+            let before = db_entry.audit_json();
+            db_entry.kind = entry.kind();
+            if entry.has_password() {
+                db_entry.set_password(HashedPassword::new(entry.value())?);
+                db_entry.expansion = None;
+            } else {
+                db_entry.password = None;
+                db_entry.expansion = Some(entry.value().to_string());
+            }
+            db_entry.save(db).await?;
+            AuditLog::record(
+                db,
+                auth,
+                "entry:update",
+                Some(domain.id),
+                &format!("{}@{}", db_entry.name, domain.domainname),
+                Some(before),
+                Some(db_entry.audit_json()),
+            )
+            .await?;
+        }
+        ImportChange::Delete(mut db_entry) => {
+            db_entry.trash(db).await?;
+            AuditLog::record(
+                db,
+                auth,
+                "entry:delete",
+                Some(domain.id),
+                &format!("{}@{}", db_entry.name, domain.domainname),
+                Some(db_entry.audit_json()),
+                None,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The changes needed to bring a domain's entries in line with a document
+pub(in crate::api::domain) struct EntryPlan {
+    outcomes: BTreeMap<String, ImportOutcome>,
+    changes: Vec<ImportChange>,
+}
+
+/// Whether any entry has an error, so that nothing may be changed
+pub(in crate::api::domain) fn errored(outcomes: &BTreeMap<String, ImportOutcome>) -> bool {
+    outcomes
+        .values()
+        .any(|outcome| matches!(outcome, ImportOutcome::Errored { .. }))
+}
+
+/// Why a transaction's changes were rolled back
+pub(in crate::api::domain) enum NotApplied<T> {
+    /// Something went wrong
+    Failed(APIError),
+    /// Nothing was meant to be changed, or an entry had an error, so this
+    /// is the report of what would have happened
+    Rejected(T),
+}
+
+impl<T> From<diesel::result::Error> for NotApplied<T> {
+    fn from(e: diesel::result::Error) -> Self {
+        Self::Failed(e.into())
+    }
+}
+
+impl<T> From<APIError> for NotApplied<T> {
+    fn from(e: APIError) -> Self {
+        Self::Failed(e)
+    }
+}
+
+impl<T> NotApplied<T> {
+    /// The report from a transaction, whether or not it was rolled back
+    pub fn report(res: Result<T, Self>) -> APIResult<T> {
+        match res {
+            Ok(res) | Err(Self::Rejected(res)) => Ok(res),
+            Err(Self::Failed(e)) => Err(e),
+        }
+    }
+}
+
+pub(in crate::api::domain) async fn plan_entries(
+    existing: Vec<MailEntry>,
+    items: DocumentItems,
+    mode: ImportMode,
+    passwords: &PasswordChecker,
+) -> EntryPlan {
+    let mut existing: HashMap<String, MailEntry> = existing
+        .into_iter()
+        .map(|entry| (entry.name.clone(), entry))
+        .collect();
+    let mut outcomes = BTreeMap::new();
+    let mut changes = Vec::new();
+
+    for (name, entry) in items {
+        if let Some(outcome) = outcomes.get_mut(&name) {
+            *outcome = ImportOutcome::Errored {
+                reason: "Entry appears more than once".into(),
+            };
+            continue;
+        }
+        let entry = match entry.and_then(|entry| entry.validate(&name)) {
+            Ok(entry) => entry,
+            Err(reason) => {
+                existing.remove(&name);
+                outcomes.insert(name, ImportOutcome::Errored { reason });
+                continue;
+            }
+        };
+        let db_entry = existing.remove(&name);
+        // Passwords already in place were checked when they were set
+        let unchanged = db_entry.as_ref().map(|db_entry| entry.matches(db_entry));
+        if entry.has_password() && unchanged != Some(true) {
+            if let Err(rule) = passwords.check(&name, entry.value()).await {
+                outcomes.insert(
+                    name,
+                    ImportOutcome::Errored {
+                        reason: format!("Password not permitted: {rule}"),
+                    },
+                );
+                continue;
+            }
+        }
+        let outcome = match db_entry {
+            None => {
+                changes.push(ImportChange::Create(name.clone(), entry));
+                ImportOutcome::Created
+            }
+            Some(_) if unchanged == Some(true) => ImportOutcome::Unchanged,
+            Some(db_entry) => {
+                changes.push(ImportChange::Update(db_entry, entry));
+                ImportOutcome::Updated
+            }
+        };
+        outcomes.insert(name, outcome);
+    }
+
+    if mode == ImportMode::Replace {
+        // Deletions go first so they can't get in the way of later checks
+        let mut deletions = Vec::new();
+        for (name, db_entry) in existing {
+            deletions.push(ImportChange::Delete(db_entry));
+            outcomes.insert(name, ImportOutcome::Deleted);
+        }
+        deletions.append(&mut changes);
+        changes = deletions;
+    }
+
+    EntryPlan { outcomes, changes }
+}
+
+pub(super) async fn import_entries(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    Path(domain_name): Path<String>,
+    Extension(auth): Extension<Authorisation>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> APIResult<Json<ImportResponse>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let items = parse_document(query.format, &body)?;
+    // Imports may carry over accounts from elsewhere, whose passwords we only
+    // have hashed, but since those can't be checked only superusers may
+    // give them, as when creating an entry
+    let passwords = PasswordChecker::for_domain(&mut db, &config, &domain)
+        .await?
+        .allowing_prehashed(auth.superuser());
+
+    // The changes are always made, so that expansions can be checked against
+    // the result, and then rolled back unless they are to be kept
+    let res = db
+        .transaction::<_, NotApplied<ImportResponse>, _>(|db| {
+            async {
+                let plan =
+                    plan_entries(domain.entries(db).await?, items, query.mode, &passwords).await;
+                let entries = apply_changes(db, &auth, &domain, plan, query.force).await?;
+                let applied = !(query.dry_run || errored(&entries));
+                let res = ImportResponse {
+                    mode: query.mode,
+                    dry_run: query.dry_run,
+                    applied,
+                    entries,
+                };
+                if !applied {
+                    return Err(NotApplied::Rejected(res));
+                }
+                Ok(res)
+            }
+            .scope_boxed()
+        })
+        .await;
+
+    Ok(Json::from(NotApplied::report(res)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use api_types::passwords::PasswordPolicy;
+    use config::Config;
+
+    use super::*;
+
+    fn csv_items(body: &str) -> DocumentItems {
+        parse_document(DocumentFormat::Csv, body).unwrap()
+    }
+
+    fn document() -> Document {
+        Document {
+            entries: [
+                (
+                    "abuse",
+                    DocumentEntry::Alias {
+                        expansion: "myname, other@example.org".into(),
+                    },
+                ),
+                (
+                    "myname",
+                    DocumentEntry::Account {
+                        password: "{SSHA512}OBt+/EzKlfaGPOu7f5nIoRBRhjG5T+Iwiedivo1hZLUq8vBB3/X5mJaf9uXy4jLvKqMFRVuAyKceBEMuoN/EZQECAwRzYWx0eSEh".into(),
+                    },
+                ),
+                (
+                    "old",
+                    DocumentEntry::Bouncer {
+                        reason: "Gone, \"elsewhere\"\nfor good".into(),
+                    },
+                ),
+            ]
+            .into_iter()
+            .map(|(name, entry)| (name.to_string(), entry))
+            .collect(),
+        }
+    }
+
+    fn same_entries(items: DocumentItems, document: &Document) {
+        assert_eq!(items.len(), document.entries.len());
+        for (name, entry) in items {
+            let entry = entry.unwrap();
+            let expected = &document.entries[&name];
+            assert_eq!(entry.kind_name(), expected.kind_name(), "{name}");
+            assert_eq!(entry.value(), expected.value(), "{name}");
+        }
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let document = document();
+        let body = serde_json::to_string(&document).unwrap();
+        same_entries(
+            parse_document(DocumentFormat::Json, &body).unwrap(),
+            &document,
+        );
+    }
+
+    #[test]
+    fn round_trips_csv() {
+        let document = document();
+        let body = render_csv(&document);
+        assert!(body.starts_with("name,kind,value\r\n"));
+        assert!(body.contains("abuse,alias,\"myname, other@example.org\"\r\n"));
+        same_entries(csv_items(&body), &document);
+    }
+
+    #[test]
+    fn parses_csv_quoting() {
+        let items = csv_items(
+            "name,kind,value\n\
+             list,list,\"a, b,c\"\n\
+             \"quoted\",bouncer,\"Say \"\"no\"\"\"\r\n\
+             \n\
+             last,blackhole,no newline",
+        );
+        let values: Vec<_> = items
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.as_ref().unwrap().value()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("list", "a, b,c"),
+                ("quoted", "Say \"no\""),
+                ("last", "no newline")
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_bad_csv() {
+        for body in ["", "kind,name,value\n", "abuse,alias,myname\n"] {
+            assert!(matches!(
+                parse_document(DocumentFormat::Csv, body),
+                Err(APIError::BadImport(_))
+            ));
+        }
+        assert!(matches!(
+            parse_document(DocumentFormat::Csv, "name,kind,value\nabuse,alias\n"),
+            Err(APIError::BadImport(e)) if e.contains("Line 2")
+        ));
+        assert!(matches!(
+            parse_document(DocumentFormat::Csv, "name,kind,value\n\"open,alias,x\n"),
+            Err(APIError::BadImport(e)) if e.contains("Unterminated")
+        ));
+        // Unknown kinds only spoil their own entry
+        let items = csv_items("name,kind,value\nodd,mailbox,x\nabuse,alias,myname\n");
+        assert_eq!(
+            items[0].1.as_ref().unwrap_err(),
+            "Unknown entry kind: mailbox"
+        );
+        assert!(items[1].1.is_ok());
+    }
+
+    fn entry(name: &str, kind: MailEntryKind, expansion: &str) -> MailEntry {
+        MailEntry {
+            id: 0,
+            maildomain: 0,
+            name: name.into(),
+            kind,
+            password: None,
+            expansion: Some(expansion.into()),
+            deleted_at: None,
+            keep_local_copy: false,
+        }
+    }
+
+    async fn plan(mode: ImportMode) -> BTreeMap<String, &'static str> {
+        let config = Config::builder()
+            .set_override("database_url", "postgres://localhost/test")
+            .unwrap()
+            .build()
+            .unwrap();
+        let config = Configuration::from_config(config).unwrap();
+        let passwords = PasswordChecker::new(&config, &PasswordPolicy::default(), "example.com");
+        let existing = vec![
+            entry("same", MailEntryKind::Alias, "a, b"),
+            entry("changed", MailEntryKind::Alias, "a"),
+            entry("old", MailEntryKind::Bouncer, "Gone"),
+        ];
+        let items = csv_items(
+            "name,kind,value\n\
+             same,alias,\"a,b\"\n\
+             changed,alias,b\n\
+             fresh,login,correct horse battery\n\
+             twice,alias,a\n\
+             twice,alias,b\n\
+             bad name,alias,a\n\
+             weak,login,x\n",
+        );
+        plan_entries(existing, items, mode, &passwords)
+            .await
+            .outcomes
+            .into_iter()
+            .map(|(name, outcome)| {
+                let outcome = match outcome {
+                    ImportOutcome::Created => "created",
+                    ImportOutcome::Updated => "updated",
+                    ImportOutcome::Deleted => "deleted",
+                    ImportOutcome::Unchanged => "unchanged",
+                    ImportOutcome::Errored { .. } => "errored",
+                };
+                (name, outcome)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn plans_merges() {
+        let outcomes = plan(ImportMode::Merge).await;
+        let expected: BTreeMap<_, _> = [
+            ("same", "unchanged"),
+            ("changed", "updated"),
+            ("fresh", "created"),
+            ("twice", "errored"),
+            ("bad name", "errored"),
+            ("weak", "errored"),
+        ]
+        .into_iter()
+        .map(|(name, outcome)| (name.to_string(), outcome))
+        .collect();
+        assert_eq!(outcomes, expected);
+    }
+
+    #[tokio::test]
+    async fn plans_replacements() {
+        let outcomes = plan(ImportMode::Replace).await;
+        assert_eq!(outcomes["old"], "deleted");
+        assert_eq!(outcomes["same"], "unchanged");
+        assert_eq!(outcomes["fresh"], "created");
+    }
+}
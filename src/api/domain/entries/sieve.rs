@@ -0,0 +1,130 @@
+//! Accounts' own Sieve scripts
+//!
+
+use axum::{extract::Path, Extension, Json};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{models::*, Connection};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::api::{APIError, APIResult};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(super) struct SetSieveRequest {
+    /// An empty script removes it
+    script: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct SieveResponse {
+    /// Empty if the account has no script
+    script: String,
+    #[serde(
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    updated_at: Option<OffsetDateTime>,
+}
+
+impl From<Option<MailSieveScript>> for SieveResponse {
+    fn from(script: Option<MailSieveScript>) -> Self {
+        match script {
+            Some(script) => Self {
+                script: script.script,
+                updated_at: Some(script.updated_at),
+            },
+            None => Self {
+                script: String::new(),
+                updated_at: None,
+            },
+        }
+    }
+}
+
+pub(super) async fn get_sieve(
+    mut db: Connection,
+    Path((domain_name, entry)): Path<(String, String)>,
+    Extension(auth): Extension<Authorisation>,
+) -> APIResult<Json<SieveResponse>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let full_name = format!("{entry}@{domain_name}");
+
+    let db_entry = domain
+        .entry_by_name(&mut db, &entry)
+        .await?
+        .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
+
+    if db_entry.kind != MailEntryKind::Account {
+        return Err(APIError::NotAccount(full_name));
+    }
+
+    let script = db_entry.sieve_script(&mut db).await?;
+    Ok(Json::from(SieveResponse::from(script)))
+}
+
+pub(super) async fn set_sieve(
+    mut db: Connection,
+    Path((domain_name, entry)): Path<(String, String)>,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<SetSieveRequest>,
+) -> APIResult<Json<SieveResponse>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let full_name = format!("{entry}@{domain_name}");
+
+    let db_entry = domain
+        .entry_by_name(&mut db, &entry)
+        .await?
+        .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
+
+    if db_entry.kind != MailEntryKind::Account {
+        return Err(APIError::NotAccount(full_name));
+    }
+
+    validate_sieve(&body.script).map_err(APIError::BadSieve)?;
+
+    let script = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let before = db_entry.sieve_script(db).await?;
+                db_entry.set_sieve_script(db, &body.script).await?;
+                let after = db_entry.sieve_script(db).await?;
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "entry:sieve",
+                    Some(domain.id),
+                    &full_name,
+                    before.map(|script| script.audit_json()),
+                    after.as_ref().map(|script| script.audit_json()),
+                )
+                .await?;
+                Ok(after)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(Json::from(SieveResponse::from(script)))
+}
@@ -0,0 +1,119 @@
+//! Deleted entries awaiting restoration or purging
+//!
+
+use axum::{extract::Path, Extension, Json};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{models::*, Connection};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::api::{APIError, APIResult};
+
+use super::EntryListResponseItem;
+
+#[derive(Serialize, Debug, Default)]
+pub(super) struct TrashListResponse {
+    entries: Vec<TrashListResponseItem>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct TrashListResponseItem {
+    id: i32,
+    name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    deleted_at: OffsetDateTime,
+    #[serde(flatten)]
+    entry: EntryListResponseItem,
+}
+
+pub(super) async fn list_trash(
+    mut db: Connection,
+    Path(domain_name): Path<String>,
+    Extension(auth): Extension<Authorisation>,
+) -> APIResult<Json<TrashListResponse>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let entries = domain
+        .trashed_entries(&mut db)
+        .await?
+        .into_iter()
+        .filter_map(|entry| {
+            Some(TrashListResponseItem {
+                id: entry.id,
+                name: entry.name.clone(),
+                deleted_at: entry.deleted_at?,
+                entry: entry.into(),
+            })
+        })
+        .collect();
+
+    Ok(Json::from(TrashListResponse { entries }))
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct RestoreResponse {
+    restored: String,
+}
+
+pub(super) async fn restore_entry(
+    mut db: Connection,
+    Path((domain_name, id)): Path<(String, i32)>,
+    Extension(auth): Extension<Authorisation>,
+) -> APIResult<Json<RestoreResponse>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let mut db_entry = domain
+        .trashed_entry_by_id(&mut db, id)
+        .await?
+        .ok_or_else(|| APIError::NotFound(format!("{id} in trash of {domain_name}")))?;
+
+    let full_name = format!("{}@{domain_name}", db_entry.name);
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            if domain.entry_by_name(db, &db_entry.name).await?.is_some() {
+                return Err(APIError::EntryNameInUse(full_name.clone()));
+            }
+            db_entry.restore(db).await?;
+            if !domain.accounts_within_quota(db).await? {
+                return Err(APIError::QuotaOverLimit(full_name.clone()));
+            }
+            AuditLog::record(
+                db,
+                &auth,
+                "entry:restore",
+                Some(domain.id),
+                &full_name,
+                None,
+                Some(db_entry.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(RestoreResponse {
+        restored: full_name,
+    }))
+}
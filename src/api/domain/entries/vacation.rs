@@ -0,0 +1,94 @@
+//! Out of office auto-replies, as managed by those looking after a domain
+//!
+//! Accounts can also manage their own auto-replies, see `api::account`.
+
+use api_types::vacation::VacationSettings;
+use axum::{extract::Path, Extension, Json};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{models::*, Connection};
+
+use crate::api::{APIError, APIResult};
+
+pub(super) async fn get_vacation(
+    mut db: Connection,
+    Path((domain_name, entry)): Path<(String, String)>,
+    Extension(auth): Extension<Authorisation>,
+) -> APIResult<Json<VacationSettings>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let full_name = format!("{entry}@{domain_name}");
+
+    let db_entry = domain
+        .entry_by_name(&mut db, &entry)
+        .await?
+        .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
+
+    let vacation = db_entry
+        .vacation(&mut db)
+        .await?
+        .ok_or(APIError::NotAccount(full_name))?;
+
+    Ok(Json::from(vacation.to_api()))
+}
+
+pub(super) async fn set_vacation(
+    mut db: Connection,
+    Path((domain_name, entry)): Path<(String, String)>,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<VacationSettings>,
+) -> APIResult<Json<VacationSettings>> {
+    let domain = MailDomain::by_name(&mut db, &domain_name)
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.clone()))?;
+
+    if !domain
+        .may_write(&mut db, &auth, Capability::EntriesWrite)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain_name.clone()));
+    }
+
+    let full_name = format!("{entry}@{domain_name}");
+
+    let db_entry = domain
+        .entry_by_name(&mut db, &entry)
+        .await?
+        .ok_or_else(|| APIError::NotFound(full_name.clone()))?;
+
+    let mut vacation = db_entry
+        .vacation(&mut db)
+        .await?
+        .ok_or_else(|| APIError::NotAccount(full_name.clone()))?;
+    let before = vacation.audit_json();
+    vacation.apply(&body).map_err(APIError::BadVacation)?;
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            vacation.save(db).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "entry:vacation",
+                Some(domain.id),
+                &full_name,
+                Some(before),
+                Some(vacation.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(vacation.to_api()))
+}
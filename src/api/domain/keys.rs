@@ -1,8 +1,9 @@
 use std::collections::BTreeMap;
 
 use axum::{routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
 use mailconfig::{
-    models::{Authorisation, MailDomain, MailDomainKey},
+    models::{AuditLog, Authorisation, Capability, MailDomain, MailDomainKey},
     Connection,
 };
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,10 @@ use crate::{
     state::AppState,
 };
 
+pub(super) fn key_target(key: &MailDomainKey, domain: &MailDomain) -> String {
+    format!("{}._domainkey.{}", key.selector, domain.domainname)
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 struct ListDomainKeyRequest {
@@ -33,7 +38,10 @@ async fn list_domain_keys(
         .await?
         .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_read(&mut db, &auth, Capability::KeysRead)
+        .await?
+    {
         return Err(APIError::PermissionDenied(body.mail_domain));
     }
 
@@ -76,7 +84,10 @@ async fn set_domainkey_signing(
         .await?
         .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_administer(&mut db, &auth, Capability::KeysWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(body.mail_domain));
     }
 
@@ -84,9 +95,27 @@ async fn set_domainkey_signing(
         .await?
         .ok_or_else(|| APIError::NotFound(body.selector.clone()))?;
 
+    let before = key.audit_json();
     key.signing = body.signing;
 
-    key.save(&mut db).await?;
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            key.save(db).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "key:set-signing",
+                Some(domain.id),
+                &key_target(&key, &domain),
+                Some(before),
+                Some(key.audit_json()),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
 
     Ok(SetDomainKeySigningResponse {
         signing: key.signing,
@@ -118,11 +147,33 @@ async fn create_domain_key(
         .await?
         .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_administer(&mut db, &auth, Capability::KeysWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(body.mail_domain));
     }
 
-    let key = MailDomainKey::create(&mut db, domain.id, &body.selector, body.signing).await?;
+    let key = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let key =
+                    MailDomainKey::create(db, domain.id, &body.selector, body.signing).await?;
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "key:create",
+                    Some(domain.id),
+                    &key_target(&key, &domain),
+                    None,
+                    Some(key.audit_json()),
+                )
+                .await?;
+                Ok(key)
+            }
+            .scope_boxed()
+        })
+        .await?;
 
     Ok(CreateDomainKeyResponse {
         signing: key.signing,
@@ -153,7 +204,10 @@ async fn delete_domainkey(
         .await?
         .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
 
-    if !domain.may_access(&mut db, &auth).await? {
+    if !domain
+        .may_administer(&mut db, &auth, Capability::KeysWrite)
+        .await?
+    {
         return Err(APIError::PermissionDenied(body.mail_domain));
     }
 
@@ -166,7 +220,27 @@ async fn delete_domainkey(
         signing: key.signing,
     };
 
-    key.delete_self(&mut db).await?;
+    let target = key_target(&key, &domain);
+    let before = key.audit_json();
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            key.delete_self(db).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "key:delete",
+                Some(domain.id),
+                &target,
+                Some(before),
+                None,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
 
     Ok(res.into())
 }
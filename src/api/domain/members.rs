@@ -0,0 +1,161 @@
+//! Delegated access to domains for users other than the owner
+//!
+
+use axum::{routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{
+    models::{AuditLog, Authorisation, Capability, MailDomain, MailUser},
+    Connection,
+};
+use serde_json::json;
+
+use crate::{
+    api::{APIError, APIResult},
+    state::AppState,
+};
+
+use api_types::domains::*;
+
+async fn list_members(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<ListDomainMembersRequest>,
+) -> APIResult<Json<ListDomainMembersResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::DomainMembers)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let owner = MailUser::by_id(&mut db, domain.owner).await?;
+
+    Ok(ListDomainMembersResponse {
+        owner: owner.username,
+        members: domain
+            .members(&mut db)
+            .await?
+            .into_iter()
+            .map(|(username, role)| (username, role.into()))
+            .collect(),
+    }
+    .into())
+}
+
+async fn grant_member(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<GrantDomainMemberRequest>,
+) -> APIResult<Json<DomainMemberResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_administer(&mut db, &auth, Capability::DomainMembers)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let user = MailUser::by_name(&mut db, &body.username)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.username.clone()))?;
+
+    if user.id == domain.owner {
+        return Err(APIError::UserIsOwner(body.username));
+    }
+
+    let res = DomainMemberResponse {
+        username: body.username,
+        role: body.role,
+    };
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let before = domain.member_role(db, user.id).await?.map(|role| {
+                json!({
+                    "username": res.username,
+                    "role": MemberRole::from(role),
+                })
+            });
+            domain.grant(db, user.id, res.role.into()).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "member:grant",
+                Some(domain.id),
+                &res.username,
+                before,
+                serde_json::to_value(&res).ok(),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(res.into())
+}
+
+async fn revoke_member(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<RevokeDomainMemberRequest>,
+) -> APIResult<Json<DomainMemberResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_administer(&mut db, &auth, Capability::DomainMembers)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let user = MailUser::by_name(&mut db, &body.username)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.username.clone()))?;
+
+    let res = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let role = domain
+                    .revoke(db, user.id)
+                    .await?
+                    .ok_or_else(|| APIError::NotFound(body.username.clone()))?;
+                let res = DomainMemberResponse {
+                    username: body.username,
+                    role: role.into(),
+                };
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "member:revoke",
+                    Some(domain.id),
+                    &res.username,
+                    serde_json::to_value(&res).ok(),
+                    None,
+                )
+                .await?;
+                Ok(res)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(res.into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/list", post(list_members))
+        .route("/grant", post(grant_member))
+        .route("/revoke", post(revoke_member))
+}
@@ -0,0 +1,201 @@
+//! The rules a domain's logins' and accounts' passwords must follow
+//!
+//! Every password set through the API must satisfy the deployment's policy,
+//! tightened by its domain's own, and must not be on the list of known
+//! breached passwords if there is one.  Passwords given already hashed can't
+//! be checked, so are only let through from superusers, who may need to
+//! carry accounts over from elsewhere.  Passwords the server makes up are
+//! held to the same rules.
+
+use std::path::PathBuf;
+
+use api_types::passwords::*;
+use axum::{extract::State, routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
+use mailconfig::{
+    models::{AuditLog, Authorisation, Capability, GeneratedPassword, HashedPassword, MailDomain},
+    Connection,
+};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{
+    api::{APIError, APIResult},
+    breached::is_breached,
+    configuration::Configuration,
+    state::AppState,
+};
+
+/// Made up passwords are random enough that the rules almost never reject
+/// one, so a few tries is plenty
+const GENERATE_ATTEMPTS: usize = 8;
+
+/// Everything needed to check new passwords for one domain's entries
+pub(in crate::api::domain) struct PasswordChecker {
+    config: Configuration,
+    policy: PasswordPolicy,
+    breached: Option<PathBuf>,
+    domain: String,
+    prehashed: bool,
+}
+
+impl PasswordChecker {
+    pub async fn for_domain(
+        db: &mut AsyncPgConnection,
+        config: &Configuration,
+        domain: &MailDomain,
+    ) -> APIResult<Self> {
+        let policy = domain.password_policy(db).await?;
+        Ok(Self::new(config, &policy, &domain.domainname))
+    }
+
+    /// Check passwords for entries in the named domain, which has the given
+    /// policy of its own
+    pub fn new(config: &Configuration, policy: &PasswordPolicy, domain: &str) -> Self {
+        Self {
+            config: config.clone(),
+            policy: config.password_policy().tightened_by(policy),
+            breached: config.breached_passwords().map(PathBuf::from),
+            domain: domain.to_string(),
+            prehashed: false,
+        }
+    }
+
+    /// Let passwords given already hashed through unchecked
+    pub fn allowing_prehashed(mut self, allowed: bool) -> Self {
+        self.prehashed = allowed;
+        self
+    }
+
+    /// Check a new password for the entry `local`
+    pub async fn check(&self, local: &str, password: &str) -> Result<(), PasswordRule> {
+        if HashedPassword::is_prehashed(password) {
+            return if self.prehashed {
+                Ok(())
+            } else {
+                Err(PasswordRule::Prehashed)
+            };
+        }
+        self.policy.check(password, local, &self.domain)?;
+        if let Some(dir) = &self.breached {
+            match is_breached(dir, password).await {
+                Ok(true) => return Err(PasswordRule::Breached),
+                Ok(false) => {}
+                // Better to let people set passwords than to lock everyone
+                // out because the list has gone missing
+                Err(e) => warn!("Unable to check breached passwords in {dir:?}: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Make up a password for the entry `local`, in the given style or else
+    /// the deployment's
+    pub async fn generate(
+        &self,
+        local: &str,
+        style: Option<PasswordStyle>,
+    ) -> Result<GeneratedPassword, PasswordRule> {
+        let style = style.unwrap_or_else(|| self.config.generated_password_style());
+        let size = self.config.generated_password_size(style);
+        let min_length = usize::try_from(self.policy.min_length).unwrap_or(usize::MAX);
+        let mut broken = PasswordRule::Empty;
+        for _ in 0..GENERATE_ATTEMPTS {
+            let password = GeneratedPassword::generate(style, size, min_length);
+            match self.check(local, password.as_str()).await {
+                Ok(()) => return Ok(password),
+                Err(rule) => broken = rule,
+            }
+        }
+        Err(broken)
+    }
+}
+
+fn response(
+    config: &Configuration,
+    domain: MailDomain,
+    policy: PasswordPolicy,
+) -> PasswordPolicyResponse {
+    PasswordPolicyResponse {
+        mail_domain: domain.domainname,
+        domain: policy,
+        effective: config.password_policy().tightened_by(&policy),
+        check_breached: config.breached_passwords().is_some(),
+    }
+}
+
+async fn get_policy(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<GetPasswordPolicyRequest>,
+) -> APIResult<Json<PasswordPolicyResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::DomainRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let policy = domain.password_policy(&mut db).await?;
+    Ok(Json::from(response(&config, domain, policy)))
+}
+
+async fn set_policy(
+    mut db: Connection,
+    State(config): State<Configuration>,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<SetPasswordPolicyRequest>,
+) -> APIResult<Json<PasswordPolicyResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    // Domains can only tighten the deployment's rules, so this is theirs to
+    // decide rather than ours
+    if !domain
+        .may_administer(&mut db, &auth, Capability::DomainFlags)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let policy = PasswordPolicy {
+        min_length: body.min_length,
+        min_classes: body.min_classes,
+        forbid_names: body.forbid_names,
+    };
+    policy.validate().map_err(APIError::BadPasswordPolicy)?;
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let before = domain.password_policy(db).await?;
+            domain.set_password_policy(db, &policy).await?;
+            AuditLog::record(
+                db,
+                &auth,
+                "domain:set-password-policy",
+                Some(domain.id),
+                &domain.domainname,
+                Some(json!(before)),
+                Some(json!(policy)),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(Json::from(response(&config, domain, policy)))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/get", post(get_policy))
+        .route("/set", post(set_policy))
+}
@@ -0,0 +1,113 @@
+//! A domain's default quota for its accounts, and the most any may have
+//!
+
+use api_types::quota::*;
+use axum::{routing::post, Extension, Json, Router};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
+use mailconfig::{
+    models::{AuditLog, Authorisation, Capability, MailDomain, MailEntry},
+    Connection,
+};
+use serde_json::json;
+
+use crate::{
+    api::{APIError, APIResult},
+    state::AppState,
+};
+
+async fn get_quota(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<GetDomainQuotaRequest>,
+) -> APIResult<Json<DomainQuotaResponse>> {
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    if !domain
+        .may_read(&mut db, &auth, Capability::DomainRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(body.mail_domain));
+    }
+
+    let quota = domain.quota(&mut db).await?;
+    Ok(DomainQuotaResponse {
+        mail_domain: domain.domainname,
+        default: quota.default,
+        limit: quota.limit,
+    }
+    .into())
+}
+
+async fn set_quota(
+    mut db: Connection,
+    Extension(auth): Extension<Authorisation>,
+    Json(body): Json<SetDomainQuotaRequest>,
+) -> APIResult<Json<DomainQuotaResponse>> {
+    // Storage is ours to hand out, not the domain owner's
+    if !auth.superuser() {
+        return Err(APIError::PermissionDenied(
+            "You are not permitted to set domain quotas".into(),
+        ));
+    }
+
+    let domain = MailDomain::by_name(&mut db, &body.mail_domain)
+        .await?
+        .ok_or_else(|| APIError::NotFound(body.mail_domain.clone()))?;
+
+    let quota = DomainQuota {
+        default: body.default,
+        limit: body.limit,
+    };
+    if !quota.permits(&quota.default) {
+        return Err(APIError::QuotaOverLimit(domain.domainname));
+    }
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let before = domain.quota(db).await?;
+            // Accounts which already have more than the new limit must be
+            // brought down first
+            for (id, own) in domain.account_quotas(db).await? {
+                if !quota.permits(&own) {
+                    let name = MailEntry::by_id(db, id)
+                        .await?
+                        .map(|entry| format!("{}@{}", entry.name, domain.domainname))
+                        .unwrap_or_else(|| domain.domainname.clone());
+                    return Err(APIError::QuotaOverLimit(name));
+                }
+            }
+            domain.set_quota(db, &quota).await?;
+            if !domain.accounts_within_quota(db).await? {
+                return Err(APIError::QuotaOverLimit(domain.domainname.clone()));
+            }
+            AuditLog::record(
+                db,
+                &auth,
+                "domain:set-quota",
+                Some(domain.id),
+                &domain.domainname,
+                Some(json!(before)),
+                Some(json!(quota)),
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(DomainQuotaResponse {
+        mail_domain: domain.domainname,
+        default: quota.default,
+        limit: quota.limit,
+    }
+    .into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/get", post(get_quota))
+        .route("/set", post(set_quota))
+}
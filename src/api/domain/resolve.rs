@@ -0,0 +1,217 @@
+//! Working out where mail to an address ends up
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::{Path, Query},
+    Extension, Json,
+};
+use diesel_async::AsyncPgConnection;
+use mailconfig::{models::*, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{APIError, APIResult};
+
+/// The deepest chain of aliases and lists we're prepared to follow
+const MAX_DEPTH: usize = 32;
+
+#[derive(Deserialize, Debug)]
+pub(super) struct ResolveQuery {
+    #[serde(default)]
+    depth: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum Destination {
+    /// An account which receives the mail
+    Mailbox,
+    /// A login, which cannot receive mail
+    Login,
+    /// An address in a domain we do not host
+    External,
+    /// Rejected with the given reason
+    Bouncer { reason: String },
+    /// Silently discarded
+    Blackhole,
+    /// Not known here, so forwarded to the domain's remote MX
+    RemoteMx { remote_mx: String },
+    /// Not known here, and the domain has no remote MX
+    Unknown,
+    /// In a domain whose entries the caller may not see
+    Hidden,
+    /// Expanding this address would lead back round to an earlier one
+    Loop,
+    /// Expanding this address would go deeper than permitted
+    DepthExceeded,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ResolvedDestination {
+    address: String,
+    #[serde(flatten)]
+    destination: Destination,
+    /// The addresses passed through to get here, starting with the one
+    /// being resolved and ending with this one
+    path: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct ResolveResponse {
+    address: String,
+    destinations: Vec<ResolvedDestination>,
+}
+
+struct Resolver<'a> {
+    auth: &'a Authorisation,
+    lookup: HostedLookup,
+    visible: HashMap<String, bool>,
+}
+
+impl Resolver<'_> {
+    /// Work out where mail to a single address goes.  If it is an alias or
+    /// list then its targets are returned for further resolution instead.
+    /// An account which forwards returns the addresses it forwards to, along
+    /// with its mailbox if it keeps a local copy.
+    async fn step(
+        &mut self,
+        db: &mut AsyncPgConnection,
+        local: &str,
+        domain_name: &str,
+    ) -> APIResult<(Option<Destination>, Vec<String>)> {
+        let Some(domain) = self.lookup.domain(db, domain_name).await? else {
+            return Ok((Some(Destination::External), vec![]));
+        };
+        let visible = match self.visible.get(domain_name) {
+            Some(visible) => *visible,
+            None => {
+                let visible = domain
+                    .may_read(db, self.auth, Capability::EntriesRead)
+                    .await?;
+                self.visible.insert(domain_name.to_string(), visible);
+                visible
+            }
+        };
+        if !visible {
+            return Ok((Some(Destination::Hidden), vec![]));
+        }
+        let Some(entry) = domain.entry_for(db, local).await? else {
+            let destination = match domain.remotemx.clone() {
+                Some(remote_mx) => Destination::RemoteMx { remote_mx },
+                None => Destination::Unknown,
+            };
+            return Ok((Some(destination), vec![]));
+        };
+        if entry.name != local || domain.domainname != domain_name {
+            // Matched by subaddress, catch-all or domain alias, so follow on
+            // to the entry which actually matched
+            return Ok((None, vec![format!("{}@{}", entry.name, domain.domainname)]));
+        }
+        let targets = entry
+            .onward_targets(db)
+            .await?
+            .iter()
+            .map(|target| target.address(domain_name))
+            .collect::<Vec<_>>();
+        Ok(match entry.kind {
+            MailEntryKind::Account if targets.is_empty() || entry.keep_local_copy => {
+                (Some(Destination::Mailbox), targets)
+            }
+            MailEntryKind::Account => (None, targets),
+            MailEntryKind::Login => (Some(Destination::Login), vec![]),
+            MailEntryKind::Bouncer => (
+                Some(Destination::Bouncer {
+                    reason: entry.expansion.unwrap_or_default(),
+                }),
+                vec![],
+            ),
+            MailEntryKind::Blackhole => (Some(Destination::Blackhole), vec![]),
+            MailEntryKind::Alias | MailEntryKind::List => (None, targets),
+        })
+    }
+}
+
+pub(super) async fn resolve_address(
+    mut db: Connection,
+    Path(address): Path<String>,
+    Extension(auth): Extension<Authorisation>,
+    Query(query): Query<ResolveQuery>,
+) -> APIResult<Json<ResolveResponse>> {
+    let (local, domain_name) = address
+        .rsplit_once('@')
+        .ok_or_else(|| APIError::BadExpansionTarget(address.clone()))?;
+    let address = format!("{local}@{}", domain_name.to_ascii_lowercase());
+
+    let domain = MailDomain::hosting(&mut db, &domain_name.to_ascii_lowercase())
+        .await?
+        .ok_or_else(|| APIError::NotFound(domain_name.to_string()))?;
+    if !domain
+        .may_read(&mut db, &auth, Capability::EntriesRead)
+        .await?
+    {
+        return Err(APIError::PermissionDenied(domain.domainname));
+    }
+
+    let depth = query.depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH);
+    let mut resolver = Resolver {
+        auth: &auth,
+        lookup: HostedLookup::default(),
+        visible: HashMap::new(),
+    };
+    let mut destinations = Vec::new();
+    let mut expanded = HashSet::new();
+    let mut reached = HashSet::new();
+    let mut pending = vec![vec![address.clone()]];
+
+    // Depth first, so that destinations come out in a natural order
+    while let Some(path) = pending.pop() {
+        let current = path.last().expect("paths are never empty").clone();
+        let (local, domain_name) = current
+            .rsplit_once('@')
+            .expect("resolved addresses are always qualified");
+        let (destination, targets) = resolver.step(&mut db, local, domain_name).await?;
+        // Only report each destination once, by the first route found
+        if let Some(destination) = destination {
+            if reached.insert(current.clone()) {
+                destinations.push(ResolvedDestination {
+                    address: current.clone(),
+                    destination,
+                    path: path.clone(),
+                });
+            }
+        }
+        if targets.is_empty() || !expanded.insert(current.clone()) {
+            // Nothing further, or already followed by another route
+            continue;
+        }
+        if path.len() > depth {
+            destinations.push(ResolvedDestination {
+                address: current,
+                destination: Destination::DepthExceeded,
+                path,
+            });
+            continue;
+        }
+        for target in targets.into_iter().rev() {
+            let mut next = path.clone();
+            next.push(target.clone());
+            if path.contains(&target) {
+                destinations.push(ResolvedDestination {
+                    address: target,
+                    destination: Destination::Loop,
+                    path: next,
+                });
+            } else {
+                pending.push(next);
+            }
+        }
+    }
+
+    Ok(Json::from(ResolveResponse {
+        address,
+        destinations,
+    }))
+}
@@ -2,11 +2,19 @@
 
 use std::collections::BTreeMap;
 
-use axum::{extract::State, routing::get, Json, Router};
+use api_types::lists::{PostPolicy, ReplyTo};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use mailconfig::{models, Connection};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
 
-use crate::{configuration::Configuration, state::AppState};
+use crate::{configuration::Configuration, state::AppState, tokens::InternalService};
 
 use super::APIResult;
 
@@ -15,6 +23,8 @@ struct FrontendJson {
     version: String,
     all_domains: String,
     per_domain: BTreeMap<String, FrontendJsonDomain>,
+    /// Each domain alias, mapped to the domain whose entries it shares
+    domain_aliases: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
@@ -25,6 +35,104 @@ struct FrontendJsonDomain {
     greylisting_enable: bool,
     viruscheck_enable: bool,
     spamcheck_threshold: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subaddress_separator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    catch_all: Option<FrontendJsonCatchAll>,
+    lists: BTreeMap<String, FrontendJsonList>,
+    forwards: BTreeMap<String, FrontendJsonForward>,
+}
+
+/// What happens to mail for addresses in the domain which match nothing else
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum FrontendJsonCatchAll {
+    Alias { expansion: Vec<String> },
+    Bouncer { reason: String },
+    Blackhole,
+}
+
+impl FrontendJsonCatchAll {
+    fn new(entry: models::MailEntry, domain: &str) -> Option<Self> {
+        match entry.kind {
+            models::MailEntryKind::Alias => Some(Self::Alias {
+                expansion: entry
+                    .targets()
+                    .iter()
+                    .map(|target| target.address(domain))
+                    .collect(),
+            }),
+            models::MailEntryKind::Bouncer => Some(Self::Bouncer {
+                reason: entry.expansion.unwrap_or_default(),
+            }),
+            models::MailEntryKind::Blackhole => Some(Self::Blackhole),
+            _ => None,
+        }
+    }
+}
+
+/// Where an account's mail is sent on to, as full addresses
+#[derive(Serialize)]
+struct FrontendJsonForward {
+    targets: Vec<String>,
+    keep_local_copy: bool,
+}
+
+impl FrontendJsonForward {
+    fn new(forwarding: models::Forwarding, domain: &str) -> Self {
+        Self {
+            targets: forwarding
+                .targets
+                .into_iter()
+                .map(|target| match models::ExpansionTarget::parse(&target) {
+                    Some(parsed) => parsed.address(domain),
+                    None => target,
+                })
+                .collect(),
+            keep_local_copy: forwarding.keep_local_copy,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FrontendJsonList {
+    post_policy: PostPolicy,
+    reply_to: ReplyTo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject_prefix: Option<String>,
+    members: Vec<FrontendJsonListMember>,
+}
+
+#[derive(Serialize)]
+struct FrontendJsonListMember {
+    address: String,
+    digest: bool,
+    nomail: bool,
+    moderator: bool,
+}
+
+impl FrontendJsonList {
+    fn new(list: models::MailList, domain: &str) -> Self {
+        let settings = list.settings.to_api();
+        Self {
+            post_policy: settings.post_policy,
+            reply_to: settings.reply_to,
+            subject_prefix: settings.subject_prefix,
+            members: list
+                .members
+                .into_iter()
+                .map(|member| FrontendJsonListMember {
+                    address: match models::ExpansionTarget::parse(&member.target) {
+                        Some(target) => target.address(domain),
+                        None => member.target,
+                    },
+                    digest: member.digest,
+                    nomail: member.nomail,
+                    moderator: member.moderator,
+                })
+                .collect(),
+        }
+    }
 }
 
 async fn get_json(
@@ -32,14 +140,36 @@ async fn get_json(
     mut db: Connection,
 ) -> APIResult<Json<FrontendJson>> {
     let all_mail_domains = models::MailDomain::get_all(&mut db).await?;
+    let domain_aliases: BTreeMap<String, String> = models::MailDomain::all_aliases(&mut db)
+        .await?
+        .into_iter()
+        .collect();
     let all_domains = all_mail_domains
         .iter()
         .map(|d| d.domainname.clone())
+        .chain(domain_aliases.keys().cloned())
         .collect::<Vec<_>>()
         .join(" : ");
     let mut per_domain = BTreeMap::new();
 
     for domain in all_mail_domains {
+        let mut lists = domain.mail_lists(&mut db).await?;
+        let mut forwardings = domain.forwardings(&mut db).await?;
+        let mut catch_all = None;
+        let mut list_entries = BTreeMap::new();
+        let mut forwards = BTreeMap::new();
+        for entry in domain.entries(&mut db).await? {
+            if entry.name == models::CATCH_ALL {
+                catch_all = FrontendJsonCatchAll::new(entry, &domain.domainname);
+            } else if let Some(list) = lists.remove(&entry.id) {
+                list_entries.insert(entry.name, FrontendJsonList::new(list, &domain.domainname));
+            } else if let Some(forwarding) = forwardings.remove(&entry.id) {
+                forwards.insert(
+                    entry.name,
+                    FrontendJsonForward::new(forwarding, &domain.domainname),
+                );
+            }
+        }
         let fedom = FrontendJsonDomain {
             sender_allow_list: models::AllowDenyList::all_allows(&mut db, domain.id).await?,
             sender_deny_list: models::AllowDenyList::all_denys(&mut db, domain.id).await?,
@@ -47,6 +177,10 @@ async fn get_json(
             greylisting_enable: domain.grey_listing,
             viruscheck_enable: domain.virus_check,
             spamcheck_threshold: domain.spamcheck_threshold,
+            subaddress_separator: domain.subaddress_separator,
+            catch_all,
+            lists: list_entries,
+            forwards,
         };
         per_domain.insert(domain.domainname, fedom);
     }
@@ -55,10 +189,164 @@ async fn get_json(
         version: config.version().to_string(),
         all_domains,
         per_domain,
+        domain_aliases,
     })
     .into())
 }
 
-pub fn router() -> Router<AppState> {
-    Router::new().route("/json", get(get_json))
+/// The most messages handed out by a single drain of the outbox
+const MAX_DRAIN: i64 = 100;
+
+#[derive(Deserialize)]
+struct DrainQuery {
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct OutboxJson {
+    messages: Vec<OutboxJsonMessage>,
+}
+
+#[derive(Serialize)]
+struct OutboxJsonMessage {
+    queued_at: String,
+    sender: String,
+    recipient: String,
+    subject: String,
+    body: String,
+}
+
+/// Hand over queued mail for sending.  Messages are removed from the outbox
+/// as they are returned, so each is only ever handed out once.
+async fn drain_outbox(
+    mut db: Connection,
+    Query(query): Query<DrainQuery>,
+) -> APIResult<Json<OutboxJson>> {
+    let limit = query.limit.unwrap_or(MAX_DRAIN).clamp(1, MAX_DRAIN);
+    let messages = models::OutboxMessage::drain(&mut db, limit)
+        .await?
+        .into_iter()
+        .map(|message| OutboxJsonMessage {
+            queued_at: message.queued_at.format(&Rfc3339).unwrap_or_default(),
+            sender: message.sender,
+            recipient: message.recipient,
+            subject: message.subject,
+            body: message.body,
+        })
+        .collect();
+
+    Ok(Json::from(OutboxJson { messages }))
+}
+
+#[derive(Serialize)]
+struct VacationJson {
+    /// A Sieve script for each account with an auto-reply enabled
+    scripts: BTreeMap<String, String>,
+}
+
+/// Auto-replies for the mail backend to run, as Sieve scripts
+async fn get_vacation(mut db: Connection) -> APIResult<Json<VacationJson>> {
+    let scripts = models::MailVacation::all_enabled(&mut db)
+        .await?
+        .into_iter()
+        .map(|(address, vacation)| (address, vacation.to_sieve()))
+        .collect();
+
+    Ok(Json::from(VacationJson { scripts }))
+}
+
+#[derive(Deserialize)]
+struct SieveQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<time::OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+struct SieveJson {
+    scripts: BTreeMap<String, SieveJsonScript>,
+}
+
+#[derive(Serialize)]
+struct SieveJsonScript {
+    script: String,
+    updated_at: String,
+}
+
+/// Accounts' Sieve scripts, for the IMAP backend to install.  With `since`,
+/// only those changed after then are included, so removed scripts only show
+/// up by their absence from a full listing.
+async fn get_sieve(
+    mut db: Connection,
+    Query(query): Query<SieveQuery>,
+) -> APIResult<Json<SieveJson>> {
+    let scripts = models::MailSieveScript::all(&mut db, query.since)
+        .await?
+        .into_iter()
+        .map(|(address, script)| {
+            (
+                address,
+                SieveJsonScript {
+                    script: script.script,
+                    updated_at: script.updated_at.format(&Rfc3339).unwrap_or_default(),
+                },
+            )
+        })
+        .collect();
+
+    Ok(Json::from(SieveJson { scripts }))
+}
+
+/// Accounts' quotas as a Dovecot passwd-file, for use as a userdb.  Accounts
+/// with no quota at all are left out.
+async fn get_quota(mut db: Connection) -> APIResult<Response> {
+    let mut body = String::new();
+    for domain in models::MailDomain::get_all(&mut db).await? {
+        let quota = domain.quota(&mut db).await?;
+        let own = domain.account_quotas(&mut db).await?;
+        for entry in domain.entries(&mut db).await? {
+            if entry.kind != models::MailEntryKind::Account {
+                continue;
+            }
+            let quota = quota.effective(own.get(&entry.id));
+            let mut rules = vec![];
+            if let Some(bytes) = quota.bytes {
+                rules.push(format!("*:bytes={bytes}"));
+            }
+            if let Some(messages) = quota.messages {
+                rules.push(format!("*:messages={messages}"));
+            }
+            if rules.is_empty() {
+                continue;
+            }
+            let fields = rules
+                .iter()
+                .zip(["quota_rule", "quota_rule2"])
+                .map(|(rule, field)| format!("{field}={rule}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            body.push_str(&format!(
+                "{}@{}:::::::{fields}\n",
+                entry.name, domain.domainname
+            ));
+        }
+    }
+
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, "text/plain")], body).into_response())
+}
+
+pub fn router(state: &AppState) -> Router<AppState> {
+    // The configuration has always been open to all unless a credential is
+    // set, but everything added since needs it
+    Router::new()
+        .route("/json", get(get_json))
+        .internal_if_configured(state.clone())
+        .merge(
+            Router::new()
+                .route("/quota", get(get_quota))
+                .route("/sieve", get(get_sieve))
+                .route("/vacation", get(get_vacation))
+                .route("/outbox/drain", post(drain_outbox))
+                .internal_only(state.clone()),
+        )
 }
@@ -0,0 +1,210 @@
+//! Lookups for the mail backend
+//!
+//! These answer Dovecot's passdb and userdb lookups, in the shape its dict
+//! and HTTP lookups expect: a JSON object of fields for a user it knows, or
+//! a 404 for one it does not.  Logins only exist as far as passdb is
+//! concerned, since they have no mailbox.
+//!
+//! Frontends which would rather not hold password hashes at all can ask for
+//! a password to be checked instead, and are told whether to let the user in
+//! and if not, why not.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use diesel_async::AsyncPgConnection;
+use mailconfig::{models::*, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{APIError, APIResult},
+    configuration::Configuration,
+    ratelimit::AuthLimiter,
+    state::AppState,
+    tokens::InternalService,
+};
+
+#[derive(Serialize, Debug)]
+struct PassdbResponse {
+    /// The canonical address, which may differ from the one looked up
+    user: String,
+    /// In a scheme Dovecot understands, such as `{ARGON2ID}`
+    password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct UserdbResponse {
+    user: String,
+    home: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_rule2: Option<String>,
+}
+
+/// What the user is trying to do with the mail service
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MailService {
+    /// Send mail, which logins and accounts may both do
+    Submit,
+    /// Read their mailbox, which only accounts have
+    Read,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct VerifyRequest {
+    address: String,
+    password: String,
+    service: MailService,
+    /// Where the user is connecting from, if the frontend knows
+    #[serde(default)]
+    remote_ip: Option<IpAddr>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum VerifyReason {
+    Ok,
+    /// Too many recent failures for the address or the source
+    RateLimited,
+    UnknownUser,
+    BadPassword,
+    /// The password was right, but this kind of entry may not use the service
+    ServiceNotPermitted,
+}
+
+#[derive(Serialize, Debug)]
+struct VerifyResponse {
+    allowed: bool,
+    reason: VerifyReason,
+    /// The canonical address, once the password is known to be right
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+impl VerifyResponse {
+    fn deny(reason: VerifyReason) -> Self {
+        Self {
+            allowed: false,
+            reason,
+            user: None,
+        }
+    }
+}
+
+/// The login or account with the given address, along with its domain
+async fn find_user(
+    db: &mut AsyncPgConnection,
+    user: &str,
+) -> APIResult<Option<(MailDomain, MailEntry)>> {
+    let Some((local, domain_name)) = user.rsplit_once('@') else {
+        return Ok(None);
+    };
+    let Some(domain) = MailDomain::hosting(db, &domain_name.to_ascii_lowercase()).await? else {
+        return Ok(None);
+    };
+    Ok(domain
+        .entry_by_name(db, local)
+        .await?
+        .filter(|entry| matches!(entry.kind, MailEntryKind::Login | MailEntryKind::Account))
+        .map(|entry| (domain, entry)))
+}
+
+async fn passdb(mut db: Connection, Path(user): Path<String>) -> APIResult<Json<PassdbResponse>> {
+    let (domain, entry) = find_user(&mut db, &user)
+        .await?
+        .ok_or_else(|| APIError::NotFound(user.clone()))?;
+    let password = entry
+        .password
+        .ok_or_else(|| APIError::NotFound(user.clone()))?;
+    Ok(Json::from(PassdbResponse {
+        user: format!("{}@{}", entry.name, domain.domainname),
+        password,
+    }))
+}
+
+async fn userdb(
+    State(config): State<Configuration>,
+    mut db: Connection,
+    Path(user): Path<String>,
+) -> APIResult<Json<UserdbResponse>> {
+    let (domain, entry) = find_user(&mut db, &user)
+        .await?
+        .filter(|(_, entry)| entry.kind == MailEntryKind::Account)
+        .ok_or_else(|| APIError::NotFound(user.clone()))?;
+    let user = format!("{}@{}", entry.name, domain.domainname);
+    let home = config
+        .mail_home(&entry.name, &domain.domainname)
+        .ok_or_else(|| APIError::BadEntryName(user.clone()))?;
+    let quota = domain
+        .quota(&mut db)
+        .await?
+        .effective(entry.quota(&mut db).await?.as_ref());
+    Ok(Json::from(UserdbResponse {
+        user,
+        home,
+        mail: config.mail_location().map(String::from),
+        quota_rule: quota.bytes.map(|bytes| format!("*:bytes={bytes}")),
+        quota_rule2: quota
+            .messages
+            .map(|messages| format!("*:messages={messages}")),
+    }))
+}
+
+async fn verify(
+    State(limiter): State<AuthLimiter>,
+    mut db: Connection,
+    Json(body): Json<VerifyRequest>,
+) -> APIResult<Json<VerifyResponse>> {
+    let found = find_user(&mut db, &body.address).await?;
+    // Count failures against the user they were for, however its address was
+    // spelled, so that domain aliases don't each get a go of their own
+    let user = match &found {
+        Some((domain, entry)) => format!("{}@{}", entry.name, domain.domainname),
+        None => body.address.clone(),
+    };
+    if limiter.blocked(&user, body.remote_ip) {
+        return Ok(Json::from(VerifyResponse::deny(VerifyReason::RateLimited)));
+    }
+
+    let Some((_, mut entry)) = found else {
+        limiter.record_failure(&user, body.remote_ip);
+        return Ok(Json::from(VerifyResponse::deny(VerifyReason::UnknownUser)));
+    };
+    if !entry.verify_password(&body.password) {
+        limiter.record_failure(&user, body.remote_ip);
+        return Ok(Json::from(VerifyResponse::deny(VerifyReason::BadPassword)));
+    }
+    limiter.record_success(&user);
+    entry.upgrade_password(&mut db, &body.password).await?;
+
+    let permitted = match body.service {
+        MailService::Submit => true,
+        MailService::Read => entry.kind == MailEntryKind::Account,
+    };
+    Ok(Json::from(VerifyResponse {
+        allowed: permitted,
+        reason: if permitted {
+            VerifyReason::Ok
+        } else {
+            VerifyReason::ServiceNotPermitted
+        },
+        user: Some(user),
+    }))
+}
+
+pub fn router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/passdb/:user", get(passdb))
+        .route("/userdb/:user", get(userdb))
+        .route("/auth/verify", post(verify))
+        .internal_only(state.clone())
+}
@@ -0,0 +1,273 @@
+//! Self-service subscription to mailing lists
+//!
+//! These need no authentication.  Anyone may ask for an address to join or
+//! leave a list, but nothing changes until whoever receives mail at that
+//! address confirms it with the token we send them.  Since each request
+//! sends mail to someone, they are limited per address and per source.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::HeaderMap,
+    response::Html,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection};
+use mailconfig::{models::*, Connection};
+use serde::{Deserialize, Serialize};
+use time::Duration;
+
+use crate::{
+    api::{APIError, APIResult},
+    configuration::Configuration,
+    ratelimit::{client_ip, MailoutLimiter},
+    state::AppState,
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SubscriptionRequest {
+    address: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct SubscriptionResponse {
+    list: String,
+    confirmation_sent_to: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ConfirmResponse {
+    list: String,
+    address: String,
+    subscribed: bool,
+}
+
+/// Find the live list at the given address
+async fn find_list(db: &mut AsyncPgConnection, list: &str) -> APIResult<(MailDomain, MailEntry)> {
+    let (local, domain_name) = list
+        .rsplit_once('@')
+        .ok_or_else(|| APIError::BadAddress(list.to_string()))?;
+    let domain = MailDomain::by_name(db, &domain_name.to_ascii_lowercase())
+        .await?
+        .ok_or_else(|| APIError::NotFound(list.to_string()))?;
+    let entry = domain
+        .entry_by_name(db, local)
+        .await?
+        .ok_or_else(|| APIError::NotFound(list.to_string()))?;
+    if entry.kind != MailEntryKind::List {
+        return Err(APIError::NotList(list.to_string()));
+    }
+    Ok((domain, entry))
+}
+
+fn confirm_link(config: &Configuration, token: &str) -> String {
+    let path = format!("api/list/confirm/{token}");
+    match config.public_url().and_then(|url| url.join(&path).ok()) {
+        Some(url) => url.to_string(),
+        None => format!("/{path}"),
+    }
+}
+
+async fn request_change(
+    config: &Configuration,
+    limiter: &MailoutLimiter,
+    ip: IpAddr,
+    db: &mut AsyncPgConnection,
+    list: &str,
+    address: &str,
+    subscribe: bool,
+) -> APIResult<SubscriptionResponse> {
+    let (domain, entry) = find_list(db, list).await?;
+    let list = format!("{}@{}", entry.name, domain.domainname);
+    let address = match ExpansionTarget::parse(address.trim()) {
+        Some(target @ ExpansionTarget::Address { .. }) => target.to_string(),
+        _ => return Err(APIError::BadAddress(address.to_string())),
+    };
+
+    if !limiter.allow(&address, Some(ip)) {
+        return Err(APIError::RateLimited(address));
+    }
+
+    let lifetime = config.list_confirm_lifetime();
+    let change = if subscribe {
+        "subscribed to"
+    } else {
+        "unsubscribed from"
+    };
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            let token = ListSubscription::request(
+                db,
+                &entry,
+                &address,
+                subscribe,
+                Duration::seconds(lifetime.try_into().unwrap_or(i64::MAX)),
+            )
+            .await?;
+            let body = format!(
+                "Someone, hopefully you, asked for {address} to be {change} the\n\
+                 mailing list {list}.\n\
+                 \n\
+                 To confirm this, visit:\n\
+                 \n    {link}\n\
+                 \n\
+                 If this wasn't you then ignore this message, and nothing will change.\n\
+                 This request expires in {hours} hour(s).\n",
+                link = confirm_link(config, &token),
+                hours = lifetime / (60 * 60),
+            );
+            OutboxMessage::queue(
+                db,
+                &list,
+                &address,
+                &format!("Confirm your request for {list}"),
+                &body,
+            )
+            .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(SubscriptionResponse {
+        list,
+        confirmation_sent_to: address,
+    })
+}
+
+async fn subscribe(
+    State(config): State<Configuration>,
+    State(limiter): State<MailoutLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut db: Connection,
+    Path(list): Path<String>,
+    Json(body): Json<SubscriptionRequest>,
+) -> APIResult<Json<SubscriptionResponse>> {
+    let ip = client_ip(&config, &headers, peer);
+    request_change(&config, &limiter, ip, &mut db, &list, &body.address, true)
+        .await
+        .map(Json::from)
+}
+
+async fn unsubscribe(
+    State(config): State<Configuration>,
+    State(limiter): State<MailoutLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut db: Connection,
+    Path(list): Path<String>,
+    Json(body): Json<SubscriptionRequest>,
+) -> APIResult<Json<SubscriptionResponse>> {
+    let ip = client_ip(&config, &headers, peer);
+    request_change(&config, &limiter, ip, &mut db, &list, &body.address, false)
+        .await
+        .map(Json::from)
+}
+
+/// Just enough escaping to put text in HTML
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// The page the emailed link leads to.  Mail scanners and link previews
+/// follow links without anyone asking them to, so this changes nothing and
+/// only offers a button to confirm with.
+async fn show_confirm(mut db: Connection, Path(token): Path<String>) -> APIResult<Html<String>> {
+    let request = ListSubscription::by_token(&mut db, &token)
+        .await?
+        .ok_or_else(|| APIError::NotFound("confirmation token".to_string()))?;
+    let entry = MailEntry::by_id(&mut db, request.mailentry)
+        .await?
+        .ok_or_else(|| APIError::NotFound("list".to_string()))?;
+    let domain = MailDomain::by_id(&mut db, entry.maildomain).await?;
+
+    let list = escape_html(&format!("{}@{}", entry.name, domain.domainname));
+    let address = escape_html(&request.address);
+    let change = if request.subscribe {
+        "subscribe to"
+    } else {
+        "unsubscribe from"
+    };
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head><meta charset=\"utf-8\"><title>Confirm your request for {list}</title></head>\n\
+         <body>\n\
+         <p>Someone asked for {address} to {change} the mailing list {list}.</p>\n\
+         <form method=\"post\"><button type=\"submit\">Confirm</button></form>\n\
+         </body>\n\
+         </html>\n"
+    )))
+}
+
+async fn confirm(
+    mut db: Connection,
+    Path(token): Path<String>,
+) -> APIResult<Json<ConfirmResponse>> {
+    let request = ListSubscription::by_token(&mut db, &token)
+        .await?
+        .ok_or_else(|| APIError::NotFound("confirmation token".to_string()))?;
+
+    let res = db
+        .transaction::<_, APIError, _>(|db| {
+            async move {
+                let entry = MailEntry::by_id(db, request.mailentry)
+                    .await?
+                    .ok_or_else(|| APIError::NotFound("list".to_string()))?;
+                let domain = MailDomain::by_id(db, entry.maildomain).await?;
+                let list = format!("{}@{}", entry.name, domain.domainname);
+
+                if request.subscribe {
+                    // Subscribers are not checked for existence, but must
+                    // not make the list loop back round to itself
+                    let current = entry.expansion.as_deref().unwrap_or("");
+                    let proposed = format!("{current}, {}", request.address);
+                    domain
+                        .check_expansion(db, &entry.name, &proposed, true)
+                        .await?
+                        .map_err(|problem| APIError::from_expansion(problem, &list))?;
+                    entry.add_target(db, &request.address).await?;
+                } else if entry.remove_target(db, &request.address).await?
+                    && entry.target_count(db).await? == 0
+                {
+                    return Err(APIError::AliasWouldBecomeEmpty(list));
+                }
+
+                let res = ConfirmResponse {
+                    list,
+                    address: request.address.clone(),
+                    subscribed: request.subscribe,
+                };
+                request.delete_self(db).await?;
+                Ok(res)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(Json::from(res))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/confirm/:token", get(show_confirm).post(confirm))
+        .route("/:list/subscribe", post(subscribe))
+        .route("/:list/unsubscribe", post(unsubscribe))
+}
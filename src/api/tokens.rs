@@ -1,12 +1,16 @@
+use std::num::NonZeroU32;
+
 use axum::{
     routing::{get, post},
     Extension, Json, Router,
 };
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
 use mailconfig::{
-    models::{Authorisation, MailAuthToken},
+    models::{AuditLog, Authorisation, Capability, MailAuthToken, MailDomain, TokenScope},
     Connection,
 };
 use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
 
 use crate::{state::AppState, tokens::Authorised};
 
@@ -18,17 +22,40 @@ async fn list_tokens(
     mut db: Connection,
     Extension(auth): Extension<Authorisation>,
 ) -> APIResult<Json<TokenListResponse>> {
+    if !auth.has_capability(Capability::Tokens) {
+        return Err(APIError::PermissionDenied(
+            "Token may not list tokens".into(),
+        ));
+    }
+
     let all_tokens = MailAuthToken::by_owner(&mut db, auth.user()).await?;
+    let mut tokens = Vec::with_capacity(all_tokens.len());
+    for v in all_tokens {
+        let scope = v.scope();
+        let domains = match &scope.domains {
+            Some(ids) => Some(
+                MailDomain::by_ids(&mut db, ids)
+                    .await?
+                    .into_iter()
+                    .map(|d| d.domainname)
+                    .collect(),
+            ),
+            None => None,
+        };
+        tokens.push(TokenListResponseEntry {
+            id: v.prefix,
+            label: v.label,
+            created_at: v.created_at,
+            last_used_at: v.last_used_at,
+            expires_at: v.expires_at,
+            domains,
+            capabilities: scope.capabilities,
+        });
+    }
     Ok(TokenListResponse {
         username: auth.username().to_string(),
-        used_token: auth.token().to_string(),
-        tokens: all_tokens
-            .into_iter()
-            .map(|v| TokenListResponseEntry {
-                token: v.token,
-                label: v.label,
-            })
-            .collect(),
+        used_token: auth.token_prefix().to_string(),
+        tokens,
     }
     .into())
 }
@@ -37,10 +64,20 @@ async fn list_tokens(
 #[serde(deny_unknown_fields)]
 struct CreateTokenRequest {
     label: String,
+    /// Lifetime of the token in seconds, if it should expire
+    #[serde(default)]
+    lifetime: Option<NonZeroU32>,
+    /// Domains the token is restricted to, if any
+    #[serde(default)]
+    domains: Option<Vec<String>>,
+    /// Capabilities the token is restricted to, if any
+    #[serde(default)]
+    capabilities: Option<Vec<Capability>>,
 }
 
 #[derive(Serialize)]
 struct CreateTokenResponse {
+    id: String,
     token: String,
 }
 
@@ -49,9 +86,76 @@ async fn create_token(
     Extension(auth): Extension<Authorisation>,
     Json(body): Json<CreateTokenRequest>,
 ) -> APIResult<Json<CreateTokenResponse>> {
-    let newtok = MailAuthToken::create(&mut db, auth.user(), &body.label).await?;
+    if !auth.has_capability(Capability::Tokens) {
+        return Err(APIError::PermissionDenied(
+            "Token may not create tokens".into(),
+        ));
+    }
+
+    let domains = match body.domains {
+        Some(names) => {
+            let mut ids = Vec::with_capacity(names.len());
+            for name in names {
+                let domain = MailDomain::by_name(&mut db, &name)
+                    .await?
+                    .ok_or_else(|| APIError::NotFound(name.clone()))?;
+                if domain.role_of(&mut db, &auth).await?.is_none() {
+                    return Err(APIError::PermissionDenied(name));
+                }
+                ids.push(domain.id);
+            }
+            Some(ids)
+        }
+        // Tokens created by a scoped token inherit its restrictions
+        None => auth.scope().domains.clone(),
+    };
+    let scope = TokenScope {
+        domains,
+        capabilities: body
+            .capabilities
+            .or_else(|| auth.scope().capabilities.clone()),
+    };
+    if !scope.within(auth.scope()) {
+        return Err(APIError::PermissionDenied(
+            "New token may not exceed the scope of the token creating it".into(),
+        ));
+    }
+
+    // Tokens created by an expiring token expire no later than it does
+    let expires_at = match body.lifetime {
+        Some(secs) => Some(OffsetDateTime::now_utc() + Duration::seconds(secs.get().into())),
+        None => auth.expires_at(),
+    };
+    if let Some(limit) = auth.expires_at() {
+        if expires_at.map(|at| at > limit).unwrap_or(true) {
+            return Err(APIError::PermissionDenied(
+                "New token may not outlive the token creating it".into(),
+            ));
+        }
+    }
+    let (newtok, token) = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let (newtok, token) =
+                    MailAuthToken::create(db, auth.user(), &body.label, expires_at, &scope).await?;
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "token:create",
+                    None,
+                    &newtok.prefix,
+                    None,
+                    Some(newtok.audit_json()),
+                )
+                .await?;
+                Ok((newtok, token))
+            }
+            .scope_boxed()
+        })
+        .await?;
     Ok(CreateTokenResponse {
-        token: newtok.token,
+        id: newtok.prefix,
+        token,
     }
     .into())
 }
@@ -59,7 +163,7 @@ async fn create_token(
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct RevokeTokenRequest {
-    token: String,
+    id: String,
 }
 
 #[derive(Serialize)]
@@ -72,16 +176,22 @@ async fn revoke_token(
     Extension(auth): Extension<Authorisation>,
     Json(body): Json<RevokeTokenRequest>,
 ) -> APIResult<Json<RevokeTokenResponse>> {
-    if auth.token() == body.token {
-        return Err(APIError::AuthErrorTokenInUse(body.token));
+    if !auth.has_capability(Capability::Tokens) {
+        return Err(APIError::PermissionDenied(
+            "Token may not revoke tokens".into(),
+        ));
+    }
+
+    if auth.token_prefix() == body.id {
+        return Err(APIError::AuthErrorTokenInUse(body.id));
     }
 
-    let db_token = MailAuthToken::by_token(&mut db, &body.token)
+    let db_token = MailAuthToken::by_prefix(&mut db, &body.id)
         .await?
-        .ok_or_else(|| APIError::BadToken(body.token.clone()))?;
+        .ok_or_else(|| APIError::BadToken(body.id.clone()))?;
 
     if db_token.mailuser != auth.user() {
-        return Err(APIError::BadToken(body.token));
+        return Err(APIError::BadToken(body.id));
     }
 
     // The token exists and it's ours, remove it
@@ -90,7 +200,18 @@ async fn revoke_token(
         label: db_token.label.clone(),
     };
 
-    db_token.delete_self(&mut db).await?;
+    let target = db_token.prefix.clone();
+    let before = db_token.audit_json();
+
+    db.transaction::<_, APIError, _>(|db| {
+        async {
+            db_token.delete_self(db).await?;
+            AuditLog::record(db, &auth, "token:revoke", None, &target, Some(before), None).await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
 
     Ok(ret.into())
 }
@@ -4,8 +4,9 @@ use axum::{
     routing::{get, post},
     Extension, Json, Router,
 };
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection};
 use mailconfig::{
-    models::{Authorisation, MailUser},
+    models::{AuditLog, Authorisation, MailUser},
     Connection,
 };
 use serde::{Deserialize, Serialize};
@@ -42,7 +43,7 @@ async fn list_users(
                 superuser: user.superuser,
                 tokens: tokens
                     .into_iter()
-                    .map(|tok| (tok.label, tok.token))
+                    .map(|tok| (tok.label, tok.prefix))
                     .collect(),
             },
         );
@@ -51,6 +52,12 @@ async fn list_users(
     Ok(Json::from(res))
 }
 
+#[derive(Serialize)]
+struct CreateUserResponse {
+    superuser: bool,
+    token: String,
+}
+
 #[derive(Deserialize)]
 struct CreateUserRequest {
     username: String,
@@ -62,7 +69,7 @@ async fn create_user(
     mut db: Connection,
     Extension(auth): Extension<Authorisation>,
     Json(body): Json<CreateUserRequest>,
-) -> APIResult<Json<ListUsersResponseEntry>> {
+) -> APIResult<Json<CreateUserResponse>> {
     if !auth.superuser() {
         return Err(APIError::PermissionDenied(
             "You may not create users".into(),
@@ -73,16 +80,29 @@ async fn create_user(
         return Err(APIError::UserAlreadyExists(body.username));
     }
 
-    let user = MailUser::create(&mut db, &body.username, body.superuser).await?;
-
-    let tokens = user.tokens(&mut db).await?;
+    let (user, token) = db
+        .transaction::<_, APIError, _>(|db| {
+            async {
+                let (user, token) = MailUser::create(db, &body.username, body.superuser).await?;
+                AuditLog::record(
+                    db,
+                    &auth,
+                    "user:create",
+                    None,
+                    &user.username,
+                    None,
+                    Some(user.audit_json()),
+                )
+                .await?;
+                Ok((user, token))
+            }
+            .scope_boxed()
+        })
+        .await?;
 
-    Ok(Json::from(ListUsersResponseEntry {
+    Ok(Json::from(CreateUserResponse {
         superuser: user.superuser,
-        tokens: tokens
-            .into_iter()
-            .map(|tok| (tok.label, tok.token))
-            .collect(),
+        token,
     }))
 }
 
@@ -0,0 +1,93 @@
+//! Checking passwords against known breaches, without them leaving the host
+//!
+//! The list is a directory laid out like Have I Been Pwned's range API: a file
+//! named `<PREFIX>.txt` for each five hex digit prefix of the SHA-1 hashes of
+//! breached passwords, holding a `SUFFIX:COUNT` line for each.  Only the file
+//! for the password's own prefix is ever read.
+
+use std::{io, path::Path};
+
+use sha1::{Digest, Sha1};
+
+const PREFIX_LEN: usize = 5;
+
+/// Whether the password appears in the list in `dir`, which is known to
+/// exist.  Prefixes with no file have no breached passwords.
+pub async fn is_breached(dir: &Path, password: &str) -> io::Result<bool> {
+    let hash: String = Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect();
+    let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+    let range = match tokio::fs::read_to_string(dir.join(format!("{prefix}.txt"))).await {
+        Ok(range) => range,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(range_contains(&range, suffix))
+}
+
+/// Whether a range file lists the hash suffix as breached
+fn range_contains(range: &str, suffix: &str) -> bool {
+    range.lines().any(|line| {
+        let (line_suffix, count) = line.trim().split_once(':').unwrap_or((line.trim(), "1"));
+        // Padded ranges include made-up hashes with a count of zero
+        line_suffix.eq_ignore_ascii_case(suffix) && count.trim() != "0"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+    const SUFFIX: &str = "1E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+    #[test]
+    fn finds_listed_suffixes() {
+        let range = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                     1E4C9B93F3F0682250B6CF8331B7EE68FD8:9545824\r\n\
+                     1F2B668E8AABEF1C59E9EC6F82E3F3CD786:2\r\n";
+        assert!(range_contains(range, SUFFIX));
+        assert!(!range_contains(
+            range,
+            "1E4C9B93F3F0682250B6CF8331B7EE68FD9"
+        ));
+    }
+
+    #[test]
+    fn ignores_case_of_suffixes() {
+        assert!(range_contains(
+            "1e4c9b93f3f0682250b6cf8331b7ee68fd8:3\n",
+            SUFFIX
+        ));
+    }
+
+    #[test]
+    fn skips_padding() {
+        assert!(!range_contains(
+            "1E4C9B93F3F0682250B6CF8331B7EE68FD8:0\r\n",
+            SUFFIX
+        ));
+    }
+
+    #[test]
+    fn takes_bare_suffixes_as_breached() {
+        assert!(range_contains(
+            "1E4C9B93F3F0682250B6CF8331B7EE68FD8\n",
+            SUFFIX
+        ));
+        assert!(!range_contains("", SUFFIX));
+    }
+
+    #[tokio::test]
+    async fn reads_the_prefix_file() {
+        let dir = std::env::temp_dir().join(format!("breached-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("5BAA6.txt"), format!("{SUFFIX}:10\r\n")).unwrap();
+        assert!(is_breached(&dir, "password").await.unwrap());
+        // No file for this prefix, so nothing breached
+        assert!(!is_breached(&dir, "not the password").await.unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,15 @@
-use std::sync::Arc;
+use std::{
+    net::IpAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use api_types::passwords::{PasswordPolicy, PasswordStyle};
 
 use config::{Config, ConfigError, Environment};
 use git_testament::git_testament;
+use mailconfig::models::{safe_entry_name, valid_domain_name};
 use serde::Deserialize;
 use url::Url;
 
@@ -12,12 +20,125 @@ pub struct ConfigurationInner {
     database_url: Url,
     #[serde(default = "String::new")]
     version: String,
+    #[serde(default = "default_token_prune_grace")]
+    token_prune_grace: u64,
+    #[serde(default = "default_trash_purge_window")]
+    trash_purge_window: u64,
+    #[serde(default = "default_list_confirm_lifetime")]
+    list_confirm_lifetime: u64,
+    #[serde(default)]
+    public_url: Option<Url>,
+    #[serde(default)]
+    internal_token: Option<String>,
+    #[serde(default = "default_mail_home")]
+    mail_home: String,
+    #[serde(default)]
+    mail_location: Option<String>,
+    #[serde(default = "default_auth_failure_window")]
+    auth_failure_window: u64,
+    #[serde(default = "default_auth_failures_per_address")]
+    auth_failures_per_address: usize,
+    #[serde(default = "default_auth_failures_per_ip")]
+    auth_failures_per_ip: usize,
+    #[serde(default = "default_list_request_window")]
+    list_request_window: u64,
+    #[serde(default = "default_list_requests_per_address")]
+    list_requests_per_address: usize,
+    #[serde(default = "default_list_requests_per_ip")]
+    list_requests_per_ip: usize,
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: String,
+    #[serde(skip)]
+    trusted_proxy_ips: Vec<IpAddr>,
+    #[serde(default = "default_password_min_length")]
+    password_min_length: u32,
+    #[serde(default = "default_password_min_classes")]
+    password_min_classes: u32,
+    #[serde(default = "default_password_forbid_names")]
+    password_forbid_names: bool,
+    #[serde(default)]
+    breached_passwords: Option<PathBuf>,
+    #[serde(default)]
+    generated_password_style: PasswordStyle,
+    #[serde(default = "default_generated_password_length")]
+    generated_password_length: usize,
+    #[serde(default = "default_generated_passphrase_words")]
+    generated_passphrase_words: usize,
 }
 
 fn default_port() -> u16 {
     1537
 }
 
+fn default_token_prune_grace() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_trash_purge_window() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_list_confirm_lifetime() -> u64 {
+    2 * 24 * 60 * 60
+}
+
+fn default_mail_home() -> String {
+    "/var/mail/%d/%n".to_string()
+}
+
+fn default_auth_failure_window() -> u64 {
+    15 * 60
+}
+
+fn default_auth_failures_per_address() -> usize {
+    5
+}
+
+fn default_auth_failures_per_ip() -> usize {
+    20
+}
+
+fn default_list_request_window() -> u64 {
+    60 * 60
+}
+
+fn default_list_requests_per_address() -> usize {
+    3
+}
+
+fn default_list_requests_per_ip() -> usize {
+    10
+}
+
+fn default_trusted_proxies() -> String {
+    "127.0.0.1,::1".to_string()
+}
+
+fn default_password_min_length() -> u32 {
+    8
+}
+
+fn default_password_min_classes() -> u32 {
+    1
+}
+
+fn default_password_forbid_names() -> bool {
+    true
+}
+
+/// Sizes outside these would make passwords too weak to be worth having, or
+/// too long for anyone to use
+const GENERATED_PASSWORD_LENGTHS: RangeInclusive<usize> = 8..=256;
+const GENERATED_PASSPHRASE_WORDS: RangeInclusive<usize> = 3..=64;
+
+fn default_generated_password_length() -> usize {
+    20
+}
+
+fn default_generated_passphrase_words() -> usize {
+    6
+}
+
 git_testament!(VERSION);
 
 #[derive(Clone)]
@@ -44,6 +165,131 @@ impl ConfigurationInner {
         self.port
     }
 
+    /// How long after expiry (in seconds) tokens are pruned from the database
+    pub fn token_prune_grace(&self) -> u64 {
+        self.token_prune_grace
+    }
+
+    /// How long (in seconds) deleted entries are kept in the trash
+    pub fn trash_purge_window(&self) -> u64 {
+        self.trash_purge_window
+    }
+
+    /// How long (in seconds) a list subscription request may wait for
+    /// confirmation
+    pub fn list_confirm_lifetime(&self) -> u64 {
+        self.list_confirm_lifetime
+    }
+
+    /// The URL at which this service is publicly reachable, if known
+    pub fn public_url(&self) -> Option<&Url> {
+        self.public_url.as_ref()
+    }
+
+    /// The credential which internal services such as the mail backend
+    /// present, if they are to be allowed in at all
+    pub fn internal_token(&self) -> Option<&str> {
+        self.internal_token
+            .as_deref()
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Where accounts' mail is kept, with `%d` for the domain, `%n` for the
+    /// local part, and `%u` for the whole address.  Nothing is given for
+    /// names which would not stay within their own directory.
+    pub fn mail_home(&self, local: &str, domain: &str) -> Option<String> {
+        if !(safe_entry_name(local) && valid_domain_name(domain)) {
+            return None;
+        }
+        let mut home = String::new();
+        let mut chars = self.mail_home.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('%', Some('d')) => home.push_str(domain),
+                ('%', Some('n')) => home.push_str(local),
+                ('%', Some('u')) => home.push_str(&format!("{local}@{domain}")),
+                _ => {
+                    home.push(c);
+                    continue;
+                }
+            }
+            chars.next();
+        }
+        Some(home)
+    }
+
+    /// The mail location for the mail backend to use, if not its own default
+    pub fn mail_location(&self) -> Option<&str> {
+        self.mail_location.as_deref()
+    }
+
+    /// How long (in seconds) failed authentication attempts are remembered
+    pub fn auth_failure_window(&self) -> u64 {
+        self.auth_failure_window
+    }
+
+    /// How many failed attempts an address may have within the window
+    pub fn auth_failures_per_address(&self) -> usize {
+        self.auth_failures_per_address
+    }
+
+    /// How many failed attempts may come from one source within the window
+    pub fn auth_failures_per_ip(&self) -> usize {
+        self.auth_failures_per_ip
+    }
+
+    /// How long (in seconds) list subscription requests are remembered
+    pub fn list_request_window(&self) -> u64 {
+        self.list_request_window
+    }
+
+    /// How many list subscription requests may mail one address within the
+    /// window
+    pub fn list_requests_per_address(&self) -> usize {
+        self.list_requests_per_address
+    }
+
+    /// How many list subscription requests may come from one source within
+    /// the window
+    pub fn list_requests_per_ip(&self) -> usize {
+        self.list_requests_per_ip
+    }
+
+    /// Whether a peer is a reverse proxy whose `X-Forwarded-For` can be
+    /// believed
+    pub fn trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxy_ips.contains(&ip)
+    }
+
+    /// The rules every new password must satisfy, which domains may tighten
+    pub fn password_policy(&self) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: self.password_min_length,
+            min_classes: self.password_min_classes,
+            forbid_names: self.password_forbid_names,
+        }
+    }
+
+    /// The directory of known-breached password hashes, if there is one
+    pub fn breached_passwords(&self) -> Option<&Path> {
+        self.breached_passwords.as_deref()
+    }
+
+    /// How passwords are made up when none is given, unless the request
+    /// says otherwise
+    pub fn generated_password_style(&self) -> PasswordStyle {
+        self.generated_password_style
+    }
+
+    /// How long made up passwords are: characters for random passwords, and
+    /// words for passphrases
+    pub fn generated_password_size(&self, style: PasswordStyle) -> usize {
+        match style {
+            PasswordStyle::Random => self.generated_password_length,
+            PasswordStyle::Passphrase => self.generated_passphrase_words,
+        }
+    }
+
     /// The version of this program
     pub fn version(&self) -> &str {
         &self.version
@@ -54,7 +300,46 @@ impl Configuration {
     /// Load a configuration from the environment
     pub fn load() -> Result<Configuration, ConfigError> {
         let config = Config::builder().add_source(Environment::default().try_parsing(true));
-        let mut inner: ConfigurationInner = config.build()?.try_deserialize()?;
+        Self::from_config(config.build()?)
+    }
+
+    /// Make a configuration from settings gathered elsewhere, checking them
+    pub fn from_config(config: Config) -> Result<Configuration, ConfigError> {
+        let mut inner: ConfigurationInner = config.try_deserialize()?;
+        if !GENERATED_PASSWORD_LENGTHS.contains(&inner.generated_password_length) {
+            return Err(ConfigError::Message(format!(
+                "GENERATED_PASSWORD_LENGTH must be between {} and {}",
+                GENERATED_PASSWORD_LENGTHS.start(),
+                GENERATED_PASSWORD_LENGTHS.end()
+            )));
+        }
+        if !GENERATED_PASSPHRASE_WORDS.contains(&inner.generated_passphrase_words) {
+            return Err(ConfigError::Message(format!(
+                "GENERATED_PASSPHRASE_WORDS must be between {} and {}",
+                GENERATED_PASSPHRASE_WORDS.start(),
+                GENERATED_PASSPHRASE_WORDS.end()
+            )));
+        }
+        // A missing list would otherwise quietly let every password through
+        if let Some(dir) = &inner.breached_passwords {
+            if !dir.is_dir() {
+                return Err(ConfigError::Message(format!(
+                    "BREACHED_PASSWORDS is not a directory: {}",
+                    dir.display()
+                )));
+            }
+        }
+        inner.trusted_proxy_ips = inner
+            .trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| {
+                ip.parse().map_err(|_| {
+                    ConfigError::Message(format!("TRUSTED_PROXIES has a bad address: {ip}"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
         inner.version = format!("{VERSION}");
         Ok(Self {
             inner: Arc::new(inner),
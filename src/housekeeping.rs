@@ -0,0 +1,74 @@
+//! Background housekeeping tasks
+
+use std::time::Duration;
+
+use diesel_async::AsyncPgConnection;
+use mailconfig::{
+    models::{ListSubscription, MailAuthToken, MailEntry},
+    Pool,
+};
+use tracing::{info, warn};
+
+use crate::{
+    configuration::Configuration,
+    ratelimit::{AuthLimiter, MailoutLimiter},
+};
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn seconds(secs: u64) -> time::Duration {
+    time::Duration::seconds(secs.try_into().unwrap_or(i64::MAX))
+}
+
+async fn prune_tokens(config: &Configuration, db: &mut AsyncPgConnection) {
+    let grace = seconds(config.token_prune_grace());
+    match MailAuthToken::prune_expired(db, grace).await {
+        Ok(0) => {}
+        Ok(n) => info!("Pruned {n} expired token(s)"),
+        Err(e) => warn!("Unable to prune expired tokens: {e}"),
+    }
+}
+
+async fn purge_trash(config: &Configuration, db: &mut AsyncPgConnection) {
+    let window = seconds(config.trash_purge_window());
+    match MailEntry::purge_trash(db, window).await {
+        Ok(0) => {}
+        Ok(n) => info!("Purged {n} entry(s) from the trash"),
+        Err(e) => warn!("Unable to purge trashed entries: {e}"),
+    }
+}
+
+async fn prune_subscriptions(db: &mut AsyncPgConnection) {
+    match ListSubscription::prune_expired(db).await {
+        Ok(0) => {}
+        Ok(n) => info!("Pruned {n} unconfirmed list subscription request(s)"),
+        Err(e) => warn!("Unable to prune list subscription requests: {e}"),
+    }
+}
+
+async fn housekeep(config: &Configuration, pool: &Pool) {
+    let mut db = match pool.get().await {
+        Ok(db) => db,
+        Err(e) => {
+            warn!("Unable to acquire connection for housekeeping: {e}");
+            return;
+        }
+    };
+    prune_tokens(config, &mut db).await;
+    purge_trash(config, &mut db).await;
+    prune_subscriptions(&mut db).await;
+}
+
+/// Spawn the housekeeping task which periodically tidies up the database,
+/// and the rate limiters' memory of who has been up to what
+pub fn spawn(config: Configuration, pool: Pool, limiter: AuthLimiter, mailout: MailoutLimiter) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiter.sweep();
+            mailout.sweep();
+            housekeep(&config, &pool).await;
+        }
+    });
+}
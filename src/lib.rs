@@ -48,7 +48,7 @@ lazy_static! {
     };
 }
 
-fn establish_connection(url: &str) -> BoxFuture<ConnectionResult<AsyncPgConnection>> {
+fn establish_connection(url: &str) -> BoxFuture<'_, ConnectionResult<AsyncPgConnection>> {
     (async {
         let (client, connection) = tokio_postgres::connect(url, MAKE_TLS_CONNECT.clone())
             .await
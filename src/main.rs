@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
 
-use axum::{http::Method, Router};
+use axum::{extract::FromRef, http::Method, Router};
 use configuration::Configuration;
 use mailconfig::{apply_migrations, create_pool};
+use ratelimit::{AuthLimiter, MailoutLimiter};
 use state::AppState;
 use tower_http::{
     cors::{AllowHeaders, AllowOrigin, CorsLayer},
@@ -13,7 +14,10 @@ use tracing::{info, warn, Level};
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 
 mod api;
+mod breached;
 mod configuration;
+mod housekeeping;
+mod ratelimit;
 pub mod state;
 pub mod tokens;
 
@@ -48,7 +52,16 @@ async fn main() {
         .expect("Unable to estable database pool");
 
     let port = config.port();
-    let state = AppState::new(config, pool);
+    let state = AppState::new(config.clone(), pool.clone());
+
+    info!("Starting housekeeping...");
+
+    housekeeping::spawn(
+        config,
+        pool,
+        AuthLimiter::from_ref(&state),
+        MailoutLimiter::from_ref(&state),
+    );
     let app = Router::new()
         .nest("/api", api::router(&state))
         .layer(
@@ -72,7 +85,7 @@ async fn main() {
     let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
     info!("Starting server on {addr}...");
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .expect("Failure when running axum");
 }
@@ -1,15 +1,48 @@
+mod aliases;
+mod audit;
+mod expansion;
+mod forwarding;
+mod lists;
+mod password;
+mod password_policy;
+mod quota;
+mod sieve;
 pub mod sql_types;
+mod subscriptions;
 mod util;
+mod vacation;
 
-use diesel::dsl::sql;
 use diesel::prelude::*;
 use diesel::{ExpressionMethods, Insertable, QueryDsl, QueryResult, Queryable};
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
-pub use sql_types::MailEntryKind;
-
-use crate::models::util::encode_password;
-
-pub use self::util::Authorisation;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use api_types::domains::MemberRole;
+pub use api_types::{domains::DomainRole, tokens::Capability};
+pub use sql_types::{ListPostPolicy, ListReplyTo, MailDomainRole, MailEntryKind};
+use time::{Duration, OffsetDateTime};
+
+pub use self::audit::{AuditFilter, AuditLog};
+pub use self::expansion::{
+    parse_expansion, render_expansion, ExpansionProblem, ExpansionTarget, HostedLookup,
+};
+pub use self::forwarding::Forwarding;
+pub use self::lists::{MailList, MailListMember, MailListSettings};
+pub use self::password::{GeneratedPassword, HashedPassword, MalformedHash};
+pub use self::sieve::{validate_sieve, MailSieveScript, SieveError, MAX_SIEVE_LENGTH};
+pub use self::subscriptions::{ListSubscription, OutboxMessage};
+pub use self::util::{
+    safe_entry_name, token_prefix, valid_domain_name, valid_entry_name, valid_local_part,
+    valid_subaddress_separator, Authorisation, TokenScope,
+};
+pub use self::vacation::{MailVacation, MAX_VACATION_INTERVAL};
+
+/// The name of a domain's catch-all entry, which takes any mail to the
+/// domain not otherwise matched
+pub const CATCH_ALL: &str = "*";
+
+/// Prefixes are random enough that needing more than one try is already rare
+const TOKEN_CREATE_ATTEMPTS: usize = 4;
 
 // These types need to match up with the schema
 
@@ -37,6 +70,7 @@ pub struct MailDomain {
     pub grey_listing: bool,
     pub virus_check: bool,
     pub spamcheck_threshold: i32,
+    pub subaddress_separator: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -49,6 +83,23 @@ pub struct NewMailDomain<'a> {
     pub grey_listing: bool,
     pub virus_check: bool,
     pub spamcheck_threshold: i32,
+    pub subaddress_separator: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct MailDomainMember {
+    pub id: i32,
+    pub maildomain: i32,
+    pub mailuser: i32,
+    pub role: MailDomainRole,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::maildomain_member)]
+pub struct NewMailDomainMember {
+    pub maildomain: i32,
+    pub mailuser: i32,
+    pub role: MailDomainRole,
 }
 
 #[derive(Queryable)]
@@ -59,6 +110,8 @@ pub struct MailEntry {
     pub kind: MailEntryKind,
     pub password: Option<String>,
     pub expansion: Option<String>,
+    pub deleted_at: Option<OffsetDateTime>,
+    pub keep_local_copy: bool,
 }
 
 #[derive(Insertable)]
@@ -93,6 +146,12 @@ pub struct MailAuthToken {
     pub mailuser: i32,
     pub token: String,
     pub label: String,
+    pub prefix: String,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+    pub expires_at: Option<OffsetDateTime>,
+    pub scope_domains: Option<Vec<i32>>,
+    pub scope_capabilities: Option<Vec<String>>,
 }
 
 #[derive(Insertable)]
@@ -101,6 +160,10 @@ pub struct NewMailAuthToken<'a> {
     pub mailuser: i32,
     pub token: &'a str,
     pub label: &'a str,
+    pub prefix: &'a str,
+    pub expires_at: Option<OffsetDateTime>,
+    pub scope_domains: Option<&'a [i32]>,
+    pub scope_capabilities: Option<Vec<&'a str>>,
 }
 
 #[derive(Queryable)]
@@ -136,6 +199,7 @@ impl MailDomain {
         grey_listing: bool,
         virus_check: bool,
         spamcheck_threshold: i32,
+        subaddress_separator: Option<&str>,
     ) -> QueryResult<Self> {
         let newdom = NewMailDomain {
             owner,
@@ -145,6 +209,7 @@ impl MailDomain {
             grey_listing,
             virus_check,
             spamcheck_threshold,
+            subaddress_separator,
         };
 
         use crate::schema::maildomain::dsl;
@@ -173,6 +238,22 @@ impl MailDomain {
             .await
     }
 
+    pub async fn by_ids(db: &mut AsyncPgConnection, ids: &[i32]) -> QueryResult<Vec<Self>> {
+        use crate::schema::maildomain::dsl;
+
+        dsl::maildomain
+            .filter(dsl::id.eq_any(ids))
+            .order_by(dsl::domainname.asc())
+            .get_results(db)
+            .await
+    }
+
+    pub async fn by_id(db: &mut AsyncPgConnection, id: i32) -> QueryResult<Self> {
+        use crate::schema::maildomain::dsl;
+
+        dsl::maildomain.find(id).get_result(db).await
+    }
+
     pub async fn by_name(db: &mut AsyncPgConnection, name: &str) -> QueryResult<Option<Self>> {
         use crate::schema::maildomain::dsl;
 
@@ -183,12 +264,181 @@ impl MailDomain {
             .optional()
     }
 
-    pub async fn may_access(
+    /// Domains shared with the given user, along with their role on each
+    pub async fn shared_with(
+        db: &mut AsyncPgConnection,
+        user: i32,
+    ) -> QueryResult<Vec<(Self, MailDomainRole)>> {
+        use crate::schema::{maildomain, maildomain_member};
+
+        maildomain::table
+            .inner_join(maildomain_member::table)
+            .filter(maildomain_member::mailuser.eq(user))
+            .order_by(maildomain::domainname.asc())
+            .select((maildomain::all_columns, maildomain_member::role))
+            .get_results(db)
+            .await
+    }
+
+    /// All domains the given authorisation can see, along with the role
+    /// held on each.  Superusers see only the domains they own or are
+    /// members of, just like everyone else.
+    pub async fn visible_to(
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+    ) -> QueryResult<Vec<(Self, DomainRole)>> {
+        let owned = Self::by_owner(db, auth.user())
+            .await?
+            .into_iter()
+            .map(|dom| (dom, DomainRole::Owner));
+        let shared = Self::shared_with(db, auth.user())
+            .await?
+            .into_iter()
+            .map(|(dom, role)| (dom, MemberRole::from(role).into()));
+        Ok(owned
+            .chain(shared)
+            .filter(|(dom, _)| auth.scope().permits_domain(dom.id))
+            .collect())
+    }
+
+    /// The role the given authorisation's user has on this domain, if any.
+    /// Owners and superusers are treated as owning the domain.
+    pub async fn role_of(
         &self,
-        _db: &mut AsyncPgConnection,
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+    ) -> QueryResult<Option<DomainRole>> {
+        if auth.superuser() || self.owner == auth.user() {
+            return Ok(Some(DomainRole::Owner));
+        }
+
+        Ok(self
+            .member_role(db, auth.user())
+            .await?
+            .map(|role| MemberRole::from(role).into()))
+    }
+
+    /// The role a user has been granted on this domain, if any
+    pub async fn member_role(
+        &self,
+        db: &mut AsyncPgConnection,
+        user: i32,
+    ) -> QueryResult<Option<MailDomainRole>> {
+        use crate::schema::maildomain_member::dsl;
+
+        dsl::maildomain_member
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::mailuser.eq(user))
+            .select(dsl::role)
+            .first(db)
+            .await
+            .optional()
+    }
+
+    async fn may(
+        &self,
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+        capability: Capability,
+        needed: DomainRole,
+    ) -> QueryResult<bool> {
+        if !auth.permits(self.id, capability) {
+            return Ok(false);
+        }
+        Ok(self
+            .role_of(db, auth)
+            .await?
+            .map(|role| role >= needed)
+            .unwrap_or(false))
+    }
+
+    /// Whether the given authorisation may read this domain's configuration
+    /// using `capability`
+    pub async fn may_read(
+        &self,
+        db: &mut AsyncPgConnection,
         auth: &Authorisation,
+        capability: Capability,
     ) -> QueryResult<bool> {
-        Ok(auth.superuser() || self.owner == auth.user())
+        self.may(db, auth, capability, DomainRole::Viewer).await
+    }
+
+    /// Whether the given authorisation may alter this domain's entries and
+    /// lists using `capability`
+    pub async fn may_write(
+        &self,
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+        capability: Capability,
+    ) -> QueryResult<bool> {
+        self.may(db, auth, capability, DomainRole::Editor).await
+    }
+
+    /// Whether the given authorisation may alter this domain's settings,
+    /// keys, and membership using `capability`
+    pub async fn may_administer(
+        &self,
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+        capability: Capability,
+    ) -> QueryResult<bool> {
+        self.may(db, auth, capability, DomainRole::Admin).await
+    }
+
+    /// All members of this domain by username, not including the owner
+    pub async fn members(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<Vec<(String, MailDomainRole)>> {
+        use crate::schema::{maildomain_member, mailuser};
+
+        maildomain_member::table
+            .inner_join(mailuser::table)
+            .filter(maildomain_member::maildomain.eq(self.id))
+            .order_by(mailuser::username.asc())
+            .select((mailuser::username, maildomain_member::role))
+            .get_results(db)
+            .await
+    }
+
+    /// Grant a user a role on this domain, replacing any role they had
+    pub async fn grant(
+        &self,
+        db: &mut AsyncPgConnection,
+        user: i32,
+        role: MailDomainRole,
+    ) -> QueryResult<()> {
+        use crate::schema::maildomain_member::dsl;
+
+        diesel::insert_into(dsl::maildomain_member)
+            .values(NewMailDomainMember {
+                maildomain: self.id,
+                mailuser: user,
+                role,
+            })
+            .on_conflict((dsl::maildomain, dsl::mailuser))
+            .do_update()
+            .set(dsl::role.eq(role))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Revoke a user's membership of this domain, returning their old role
+    pub async fn revoke(
+        &self,
+        db: &mut AsyncPgConnection,
+        user: i32,
+    ) -> QueryResult<Option<MailDomainRole>> {
+        use crate::schema::maildomain_member::dsl;
+
+        diesel::delete(dsl::maildomain_member)
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::mailuser.eq(user))
+            .returning(dsl::role)
+            .get_result(db)
+            .await
+            .optional()
     }
 
     pub async fn save(&self, db: &mut AsyncPgConnection) -> QueryResult<()> {
@@ -203,6 +453,7 @@ impl MailDomain {
                 dsl::sender_verify.eq(self.sender_verify),
                 dsl::spamcheck_threshold.eq(self.spamcheck_threshold),
                 dsl::virus_check.eq(self.virus_check),
+                dsl::subaddress_separator.eq(self.subaddress_separator.as_deref()),
                 dsl::owner.eq(self.owner),
             ))
             .execute(db)
@@ -213,11 +464,14 @@ impl MailDomain {
     pub async fn entries(&self, db: &mut AsyncPgConnection) -> QueryResult<Vec<MailEntry>> {
         use crate::schema::mailentry::dsl;
 
-        dsl::mailentry
+        let mut entries: Vec<MailEntry> = dsl::mailentry
             .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
             .order_by(dsl::name.desc())
             .get_results(db)
-            .await
+            .await?;
+        expansion::fill_targets(db, &mut entries).await?;
+        Ok(entries)
     }
 
     pub async fn entry_by_name(
@@ -227,22 +481,60 @@ impl MailDomain {
     ) -> QueryResult<Option<MailEntry>> {
         use crate::schema::mailentry::dsl;
 
-        dsl::mailentry
+        let mut entry: Option<MailEntry> = dsl::mailentry
             .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
             .filter(dsl::name.eq(entry))
             .first(db)
             .await
-            .optional()
+            .optional()?;
+        if let Some(entry) = &mut entry {
+            expansion::fill_targets(db, std::slice::from_mut(entry)).await?;
+        }
+        Ok(entry)
+    }
+
+    /// Entries in this domain's trash, most recently deleted first
+    pub async fn trashed_entries(&self, db: &mut AsyncPgConnection) -> QueryResult<Vec<MailEntry>> {
+        use crate::schema::mailentry::dsl;
+
+        let mut entries: Vec<MailEntry> = dsl::mailentry
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::deleted_at.is_not_null())
+            .order_by(dsl::deleted_at.desc())
+            .get_results(db)
+            .await?;
+        expansion::fill_targets(db, &mut entries).await?;
+        Ok(entries)
+    }
+
+    pub async fn trashed_entry_by_id(
+        &self,
+        db: &mut AsyncPgConnection,
+        id: i32,
+    ) -> QueryResult<Option<MailEntry>> {
+        use crate::schema::mailentry::dsl;
+
+        let mut entry: Option<MailEntry> = dsl::mailentry
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::deleted_at.is_not_null())
+            .filter(dsl::id.eq(id))
+            .first(db)
+            .await
+            .optional()?;
+        if let Some(entry) = &mut entry {
+            expansion::fill_targets(db, std::slice::from_mut(entry)).await?;
+        }
+        Ok(entry)
     }
 
     pub async fn new_login(
         &self,
         db: &mut AsyncPgConnection,
         name: &str,
-        password: &str,
+        password: &HashedPassword,
         has_account: bool,
     ) -> QueryResult<()> {
-        let password = encode_password(password);
         let new_entry = NewMailEntry {
             maildomain: self.id,
             name,
@@ -251,7 +543,7 @@ impl MailDomain {
             } else {
                 MailEntryKind::Login
             },
-            password: Some(&password),
+            password: Some(password.as_str()),
             expansion: None,
         };
 
@@ -274,15 +566,17 @@ impl MailDomain {
             name,
             kind: MailEntryKind::Alias,
             password: None,
-            expansion: Some(expansion),
+            expansion: None,
         };
 
         use crate::schema::mailentry::dsl;
-        diesel::insert_into(dsl::mailentry)
+        let entry: MailEntry = diesel::insert_into(dsl::mailentry)
             .values(new_entry)
-            .execute(db)
+            .get_result(db)
+            .await?;
+        entry
+            .set_targets(db, &expansion::split_expansion(expansion))
             .await
-            .map(|_| ())
     }
 
     pub async fn new_bouncer(
@@ -340,49 +634,267 @@ impl MailDomain {
             name,
             kind: MailEntryKind::List,
             password: None,
-            expansion: Some(members),
+            expansion: None,
         };
 
         use crate::schema::mailentry::dsl;
-        diesel::insert_into(dsl::mailentry)
+        let entry: MailEntry = diesel::insert_into(dsl::mailentry)
             .values(new_entry)
-            .execute(db)
+            .get_result(db)
+            .await?;
+        entry
+            .set_targets(db, &expansion::split_expansion(members))
             .await
-            .map(|_| ())
     }
 }
 
 impl MailEntry {
-    pub async fn delete(&self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+    /// The live entry with the given id, if it has not been deleted
+    pub async fn by_id(db: &mut AsyncPgConnection, id: i32) -> QueryResult<Option<Self>> {
         use crate::schema::mailentry::dsl;
 
-        diesel::delete(dsl::mailentry)
+        let mut entry: Option<MailEntry> = dsl::mailentry
+            .filter(dsl::id.eq(id))
+            .filter(dsl::deleted_at.is_null())
+            .first(db)
+            .await
+            .optional()?;
+        if let Some(entry) = &mut entry {
+            expansion::fill_targets(db, std::slice::from_mut(entry)).await?;
+        }
+        Ok(entry)
+    }
+
+    /// Move this entry into the trash
+    pub async fn trash(&mut self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::mailentry::dsl;
+
+        let now = OffsetDateTime::now_utc();
+        diesel::update(dsl::mailentry)
+            .filter(dsl::id.eq(self.id))
+            .set(dsl::deleted_at.eq(now))
+            .execute(db)
+            .await?;
+        self.deleted_at = Some(now);
+        Ok(())
+    }
+
+    /// Bring this entry back out of the trash.  The caller must ensure that
+    /// the name has not been reused in the meantime.
+    pub async fn restore(&mut self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::mailentry::dsl;
+
+        diesel::update(dsl::mailentry)
             .filter(dsl::id.eq(self.id))
+            .set(dsl::deleted_at.eq(None::<OffsetDateTime>))
+            .execute(db)
+            .await?;
+        self.deleted_at = None;
+        Ok(())
+    }
+
+    /// Permanently delete all entries which were put in the trash more than
+    /// `window` ago, returning how many were removed
+    pub async fn purge_trash(db: &mut AsyncPgConnection, window: Duration) -> QueryResult<usize> {
+        use crate::schema::mailentry::dsl;
+
+        diesel::delete(dsl::mailentry)
+            .filter(dsl::deleted_at.lt(OffsetDateTime::now_utc() - window))
             .execute(db)
             .await
-            .map(|_| ())
     }
 
-    pub fn set_password(&mut self, password: &str) {
-        self.password = Some(encode_password(password));
+    pub fn set_password(&mut self, password: HashedPassword) {
+        self.password = Some(password.into_string());
     }
 
-    pub async fn save(&self, db: &mut crate::Connection) -> QueryResult<()> {
+    /// Whether the given password (plain or hashed) matches this entry's
+    pub fn password_matches(&self, password: &str) -> bool {
+        self.password
+            .as_deref()
+            .map(|stored| password::password_matches(stored, password))
+            .unwrap_or(false)
+    }
+
+    /// Whether the given plain password is this entry's.  Knowing the stored
+    /// hash is not the same as knowing the password, so that is refused.
+    pub fn verify_password(&self, password: &str) -> bool {
+        !HashedPassword::is_prehashed(password) && self.password_matches(password)
+    }
+
+    /// Once a password has been verified, replace its hash if that is in one
+    /// of the legacy schemes
+    pub async fn upgrade_password(
+        &mut self,
+        db: &mut AsyncPgConnection,
+        password: &str,
+    ) -> QueryResult<()> {
+        use crate::schema::mailentry::dsl;
+
+        // Only hash when there's something to replace, since it's costly
+        let legacy = self
+            .password
+            .as_deref()
+            .map(password::needs_upgrade)
+            .unwrap_or(false);
+        if !legacy {
+            return Ok(());
+        }
+        let Ok(hashed) = HashedPassword::new(password) else {
+            return Ok(());
+        };
+        diesel::update(dsl::mailentry)
+            .filter(dsl::id.eq(self.id))
+            .set(dsl::password.eq(hashed.as_str()))
+            .execute(db)
+            .await?;
+        self.set_password(hashed);
+        Ok(())
+    }
+
+    pub async fn save(&self, db: &mut AsyncPgConnection) -> QueryResult<()> {
         use crate::schema::mailentry::dsl;
 
         diesel::update(dsl::mailentry)
             .filter(dsl::id.eq(self.id))
             .set((
+                dsl::kind.eq(self.kind),
                 dsl::password.eq(self.password.as_deref()),
-                dsl::expansion.eq(self.expansion.as_deref()),
+                dsl::expansion.eq(self.expansion.as_deref().filter(|_| !self.has_targets())),
+                dsl::keep_local_copy.eq(self.keep_local_copy),
             ))
             .execute(db)
-            .await
-            .map(|_| ())
+            .await?;
+        // Aliases and lists keep their targets as rows of their own
+        let targets = match self.has_targets() {
+            true => expansion::split_expansion(self.expansion.as_deref().unwrap_or("")),
+            false => vec![],
+        };
+        self.set_targets(db, &targets).await?;
+        // Only accounts may forward
+        if self.kind != MailEntryKind::Account {
+            self.set_forwards(db, &[]).await?;
+        }
+        Ok(())
     }
 }
 
 impl AllowDenyList {
+    /// Validate and normalise a sender allow/deny list value.
+    ///
+    /// Acceptable values are full addresses (`user@domain`), whole domains
+    /// (`@domain` or `*@domain`), and wildcarded subdomains of either form
+    /// (`user@*.domain`, `@*.domain`).  The domain part is lowercased, and
+    /// whole domains are always given as `@domain`, so that the two ways of
+    /// writing one can't both be on the lists.
+    pub fn normalise_value(value: &str) -> Option<String> {
+        let (local, domain) = value.trim().rsplit_once('@')?;
+        let local = match local {
+            "*" => "",
+            local => local,
+        };
+        if !(local.is_empty() || util::valid_local_part(local)) {
+            return None;
+        }
+        let domain = domain.to_ascii_lowercase();
+        if !util::valid_domain_name(domain.strip_prefix("*.").unwrap_or(&domain)) {
+            return None;
+        }
+        Some(format!("{local}@{domain}"))
+    }
+
+    pub async fn by_domain(db: &mut AsyncPgConnection, maildomain: i32) -> QueryResult<Vec<Self>> {
+        use crate::schema::allowdenylist::dsl;
+
+        dsl::allowdenylist
+            .filter(dsl::maildomain.eq(maildomain))
+            .order_by(dsl::value.asc())
+            .get_results(db)
+            .await
+    }
+
+    pub async fn by_value(
+        db: &mut AsyncPgConnection,
+        maildomain: i32,
+        value: &str,
+    ) -> QueryResult<Option<Self>> {
+        use crate::schema::allowdenylist::dsl;
+
+        dsl::allowdenylist
+            .filter(dsl::maildomain.eq(maildomain))
+            .filter(dsl::value.eq(value))
+            .first(db)
+            .await
+            .optional()
+    }
+
+    pub async fn create(
+        db: &mut AsyncPgConnection,
+        maildomain: i32,
+        allow: bool,
+        value: &str,
+    ) -> QueryResult<Self> {
+        use crate::schema::allowdenylist::dsl;
+
+        diesel::insert_into(dsl::allowdenylist)
+            .values(NewAllowDenyList {
+                maildomain,
+                allow,
+                value,
+            })
+            .get_result(db)
+            .await
+    }
+
+    /// Replace both the allow and deny lists of a domain in one transaction
+    pub async fn replace_all(
+        db: &mut AsyncPgConnection,
+        maildomain: i32,
+        allows: &[String],
+        denys: &[String],
+    ) -> QueryResult<()> {
+        use crate::schema::allowdenylist::dsl;
+
+        let rows: Vec<_> = allows
+            .iter()
+            .map(|value| (value, true))
+            .chain(denys.iter().map(|value| (value, false)))
+            .map(|(value, allow)| NewAllowDenyList {
+                maildomain,
+                allow,
+                value,
+            })
+            .collect();
+
+        db.transaction(|db| {
+            async move {
+                diesel::delete(dsl::allowdenylist)
+                    .filter(dsl::maildomain.eq(maildomain))
+                    .execute(db)
+                    .await?;
+                if !rows.is_empty() {
+                    diesel::insert_into(dsl::allowdenylist)
+                        .values(&rows)
+                        .execute(db)
+                        .await?;
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    pub async fn delete_self(self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::allowdenylist::dsl;
+
+        diesel::delete(dsl::allowdenylist)
+            .filter(dsl::id.eq(self.id))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
     pub async fn all_allows(
         db: &mut AsyncPgConnection,
         maildomain: i32,
@@ -415,16 +927,46 @@ impl AllowDenyList {
 }
 
 impl MailAuthToken {
-    pub async fn by_token(db: &mut AsyncPgConnection, token: &str) -> QueryResult<Option<Self>> {
+    pub async fn by_prefix(db: &mut AsyncPgConnection, prefix: &str) -> QueryResult<Option<Self>> {
         use crate::schema::mailauthtoken::dsl;
 
         dsl::mailauthtoken
-            .filter(dsl::token.eq(token))
+            .filter(dsl::prefix.eq(prefix))
             .first(db)
             .await
             .optional()
     }
 
+    /// Look up a token as presented by a client, verifying it against the
+    /// stored hash
+    pub async fn by_token(db: &mut AsyncPgConnection, token: &str) -> QueryResult<Option<Self>> {
+        use crate::schema::mailauthtoken::dsl;
+
+        let prefix = util::token_prefix(token);
+        if prefix.len() < util::TOKEN_PREFIX_LEN {
+            // Nothing was ever issued with a prefix this short
+            return Ok(None);
+        }
+        let hash = util::hash_token(token);
+        let mut query = dsl::mailauthtoken
+            .filter(dsl::prefix.eq(prefix))
+            .into_boxed();
+        if !token.contains('.') {
+            // Tokens from before prefixes which shared their first few
+            // characters had their ids added to keep them apart
+            let prefix = prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            query = query.or_filter(dsl::prefix.like(format!("{prefix}-%")));
+        }
+        Ok(query
+            .get_results::<Self>(db)
+            .await?
+            .into_iter()
+            .find(|t| util::constant_time_eq(&t.token, &hash)))
+    }
+
     pub async fn by_owner(db: &mut AsyncPgConnection, owner: i32) -> QueryResult<Vec<Self>> {
         use crate::schema::mailauthtoken::dsl;
 
@@ -434,16 +976,98 @@ impl MailAuthToken {
             .await
     }
 
-    pub async fn create(db: &mut AsyncPgConnection, owner: i32, label: &str) -> QueryResult<Self> {
+    /// Create a new token, returning it along with the full token string.
+    /// Only the hash of the token string is stored, so this is the only
+    /// opportunity to give it to the user.
+    pub async fn create(
+        db: &mut AsyncPgConnection,
+        owner: i32,
+        label: &str,
+        expires_at: Option<OffsetDateTime>,
+        scope: &TokenScope,
+    ) -> QueryResult<(Self, String)> {
         use crate::schema::mailauthtoken::dsl;
 
-        diesel::insert_into(dsl::mailauthtoken)
-            .values((
-                dsl::mailuser.eq(owner),
-                dsl::label.eq(label),
-                dsl::token.eq(sql("md5(gen_random_uuid()::varchar)")),
-            ))
-            .get_result(db)
+        let scope_capabilities: Option<Vec<&str>> = scope
+            .capabilities
+            .as_ref()
+            .map(|caps| caps.iter().map(|c| c.as_str()).collect());
+
+        for _ in 0..TOKEN_CREATE_ATTEMPTS {
+            let (prefix, token) = util::generate_token();
+            let hash = util::hash_token(&token);
+
+            let newtoken = NewMailAuthToken {
+                mailuser: owner,
+                token: &hash,
+                label,
+                prefix: &prefix,
+                expires_at,
+                scope_domains: scope.domains.as_deref(),
+                scope_capabilities: scope_capabilities.clone(),
+            };
+
+            // A clash is left to do nothing rather than fail, since a failed
+            // statement would spoil any transaction we're part of
+            let db_token = diesel::insert_into(dsl::mailauthtoken)
+                .values(newtoken)
+                .on_conflict(dsl::prefix)
+                .do_nothing()
+                .get_result(db)
+                .await
+                .optional()?;
+
+            if let Some(db_token) = db_token {
+                return Ok((db_token, token));
+            }
+        }
+
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            Box::new("Unable to find an unused token prefix".to_string()),
+        ))
+    }
+
+    /// The scope this token is restricted to.  Unrecognised capabilities
+    /// are ignored, so they grant nothing.
+    pub fn scope(&self) -> TokenScope {
+        TokenScope {
+            domains: self.scope_domains.clone(),
+            capabilities: self
+                .scope_capabilities
+                .as_ref()
+                .map(|caps| caps.iter().filter_map(|c| c.parse().ok()).collect()),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expiry| expiry <= OffsetDateTime::now_utc())
+            .unwrap_or(false)
+    }
+
+    /// Record that the token has just been used
+    pub async fn touch(&mut self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::mailauthtoken::dsl;
+
+        let now = OffsetDateTime::now_utc();
+        diesel::update(dsl::mailauthtoken)
+            .filter(dsl::id.eq(self.id))
+            .set(dsl::last_used_at.eq(now))
+            .execute(db)
+            .await?;
+        self.last_used_at = Some(now);
+        Ok(())
+    }
+
+    /// Remove all tokens which expired more than `grace` ago, returning
+    /// how many were removed
+    pub async fn prune_expired(db: &mut AsyncPgConnection, grace: Duration) -> QueryResult<usize> {
+        use crate::schema::mailauthtoken::dsl;
+
+        diesel::delete(dsl::mailauthtoken)
+            .filter(dsl::expires_at.lt(OffsetDateTime::now_utc() - grace))
+            .execute(db)
             .await
     }
 
@@ -492,11 +1116,13 @@ impl MailUser {
             .await
     }
 
+    /// Create a user along with a default token, returning the user and
+    /// the full token string
     pub async fn create(
         db: &mut AsyncPgConnection,
         username: &str,
         superuser: bool,
-    ) -> QueryResult<Self> {
+    ) -> QueryResult<(Self, String)> {
         let newuser = NewMailUser {
             username,
             superuser,
@@ -506,8 +1132,9 @@ impl MailUser {
             .values(&newuser)
             .get_result(db)
             .await?;
-        MailAuthToken::create(db, user.id, "default").await?;
-        Ok(user)
+        let (_, token) =
+            MailAuthToken::create(db, user.id, "default", None, &TokenScope::default()).await?;
+        Ok((user, token))
     }
 }
 
@@ -589,3 +1216,144 @@ impl MailDomainKey {
             .map(|_| ())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Connect to the database named by `TEST_DATABASE_URL`, migrated and
+    /// inside a transaction which is never committed
+    pub(super) async fn test_db() -> Option<AsyncPgConnection> {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL not set, skipping");
+            return None;
+        };
+        crate::apply_migrations(&url).expect("Unable to apply migrations");
+        let mut db = AsyncPgConnection::establish(&url)
+            .await
+            .expect("Unable to connect to test database");
+        db.begin_test_transaction()
+            .await
+            .expect("Unable to begin test transaction");
+        Some(db)
+    }
+
+    #[tokio::test]
+    async fn legacy_token_with_clashing_prefix() {
+        use crate::schema::mailauthtoken::dsl;
+
+        let Some(mut db) = test_db().await else {
+            return;
+        };
+        let (user, _) = MailUser::create(&mut db, "legacy-token-user", false)
+            .await
+            .unwrap();
+
+        // As left by the hashed tokens migration for two tokens sharing
+        // their first eight characters
+        let legacy = [
+            "clashingAAAAAAAAAAAAAAAAAAAAAAAA",
+            "clashingBBBBBBBBBBBBBBBBBBBBBBBB",
+        ];
+        for (n, token) in legacy.iter().enumerate() {
+            let hash = util::hash_token(token);
+            let prefix = format!("{}-{n}", &token[..8]);
+            diesel::insert_into(dsl::mailauthtoken)
+                .values(NewMailAuthToken {
+                    mailuser: user.id,
+                    token: &hash,
+                    label: token,
+                    prefix: &prefix,
+                    expires_at: None,
+                    scope_domains: None,
+                    scope_capabilities: None,
+                })
+                .execute(&mut db)
+                .await
+                .unwrap();
+        }
+
+        for token in legacy {
+            let found = MailAuthToken::by_token(&mut db, token)
+                .await
+                .unwrap()
+                .expect("legacy token not found");
+            assert_eq!(found.label, token);
+        }
+        for token in [
+            "clashingCCCCCCCCCCCCCCCCCCCCCCCC",
+            "clashin",
+            "",
+            "clash%ngAAAAAAAA",
+            "clashin_",
+        ] {
+            assert!(MailAuthToken::by_token(&mut db, token)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_legacy_password_on_login() {
+        let Some(mut db) = test_db().await else {
+            return;
+        };
+        let (user, _) = MailUser::create(&mut db, "upgrade-user", false)
+            .await
+            .unwrap();
+        let domain = MailDomain::create(
+            &mut db,
+            "upgrade.example",
+            user.id,
+            None,
+            true,
+            false,
+            true,
+            100,
+            None,
+        )
+        .await
+        .unwrap();
+        // Drepper's SHA-512 crypt test vector for "Hello world!"
+        let legacy = HashedPassword::new("{SHA512-CRYPT}$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1").unwrap();
+        domain
+            .new_login(&mut db, "someone", &legacy, true)
+            .await
+            .unwrap();
+
+        let mut entry = domain
+            .entry_by_name(&mut db, "someone")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(entry.password_matches("Hello world!"));
+        entry
+            .upgrade_password(&mut db, "Hello world!")
+            .await
+            .unwrap();
+
+        let stored = domain
+            .entry_by_name(&mut db, "someone")
+            .await
+            .unwrap()
+            .unwrap()
+            .password
+            .unwrap();
+        assert!(stored.starts_with("{ARGON2ID}"));
+        assert!(!password::needs_upgrade(&stored));
+        assert!(password::password_matches(&stored, "Hello world!"));
+
+        // Once upgraded, it is left alone
+        let mut entry = domain
+            .entry_by_name(&mut db, "someone")
+            .await
+            .unwrap()
+            .unwrap();
+        entry
+            .upgrade_password(&mut db, "Hello world!")
+            .await
+            .unwrap();
+        assert_eq!(entry.password.as_deref(), Some(stored.as_str()));
+    }
+}
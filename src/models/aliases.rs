@@ -0,0 +1,78 @@
+//! Domain aliases, which mirror the entries of another domain
+//!
+
+use diesel::prelude::*;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::MailDomain;
+
+impl MailDomain {
+    /// The names of the domains which alias this one, in order
+    pub async fn aliases(&self, db: &mut AsyncPgConnection) -> QueryResult<Vec<String>> {
+        use crate::schema::maildomain_alias::dsl;
+
+        dsl::maildomain_alias
+            .filter(dsl::maildomain.eq(self.id))
+            .order_by(dsl::domainname.asc())
+            .select(dsl::domainname)
+            .get_results(db)
+            .await
+    }
+
+    /// Every domain alias, along with the name of the domain it aliases
+    pub async fn all_aliases(db: &mut AsyncPgConnection) -> QueryResult<Vec<(String, String)>> {
+        use crate::schema::{maildomain, maildomain_alias};
+
+        maildomain_alias::table
+            .inner_join(maildomain::table)
+            .order_by(maildomain_alias::domainname.asc())
+            .select((maildomain_alias::domainname, maildomain::domainname))
+            .get_results(db)
+            .await
+    }
+
+    /// The domain which the named domain is an alias of, if it is one
+    pub async fn by_alias(db: &mut AsyncPgConnection, alias: &str) -> QueryResult<Option<Self>> {
+        use crate::schema::{maildomain, maildomain_alias};
+
+        maildomain::table
+            .inner_join(maildomain_alias::table)
+            .filter(maildomain_alias::domainname.eq(alias))
+            .select(maildomain::all_columns)
+            .first(db)
+            .await
+            .optional()
+    }
+
+    /// The domain whose entries take mail for the named domain, whether
+    /// that is the domain itself or the one it is an alias of
+    pub async fn hosting(db: &mut AsyncPgConnection, name: &str) -> QueryResult<Option<Self>> {
+        match Self::by_name(db, name).await? {
+            Some(domain) => Ok(Some(domain)),
+            None => Self::by_alias(db, name).await,
+        }
+    }
+
+    pub async fn add_alias(&self, db: &mut AsyncPgConnection, alias: &str) -> QueryResult<()> {
+        use crate::schema::maildomain_alias::dsl;
+
+        diesel::insert_into(dsl::maildomain_alias)
+            .values((dsl::maildomain.eq(self.id), dsl::domainname.eq(alias)))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Remove an alias of this domain, returning whether there was one
+    pub async fn remove_alias(&self, db: &mut AsyncPgConnection, alias: &str) -> QueryResult<bool> {
+        use crate::schema::maildomain_alias::dsl;
+
+        diesel::delete(dsl::maildomain_alias)
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::domainname.eq(alias))
+            .execute(db)
+            .await
+            .map(|n| n > 0)
+    }
+}
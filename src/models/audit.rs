@@ -0,0 +1,281 @@
+//! Audit logging of configuration changes
+//!
+
+use diesel::prelude::*;
+use diesel::{ExpressionMethods, Insertable, QueryDsl, QueryResult, Queryable};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{
+    Authorisation, Forwarding, MailAuthToken, MailDomain, MailDomainKey, MailEntry, MailEntryKind,
+    MailList, MailSieveScript, MailUser, MailVacation,
+};
+
+#[derive(Queryable)]
+pub struct AuditLog {
+    pub id: i32,
+    pub at: OffsetDateTime,
+    pub mailuser: Option<i32>,
+    pub token_label: String,
+    pub action: String,
+    pub maildomain: Option<i32>,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    /// The account which made the change itself, when no user did
+    pub account: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::auditlog)]
+pub struct NewAuditLog<'a> {
+    pub mailuser: Option<i32>,
+    pub token_label: &'a str,
+    pub action: &'a str,
+    pub maildomain: Option<i32>,
+    pub target: &'a str,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub account: Option<&'a str>,
+}
+
+/// Restrictions on which audit log entries are returned by a search
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    /// Only entries concerning this domain
+    pub domain: Option<i32>,
+    /// Only entries made by this user
+    pub user: Option<i32>,
+    /// Only entries about this target
+    pub target: Option<String>,
+    /// Only entries at or after this time
+    pub since: Option<OffsetDateTime>,
+    /// Only entries before this time
+    pub until: Option<OffsetDateTime>,
+    /// If set, only entries concerning these domains, or entries which
+    /// concern no domain but were made by the given user
+    pub visible_to: Option<(Vec<i32>, i32)>,
+    /// Maximum number of entries to return
+    pub limit: i64,
+}
+
+impl AuditLog {
+    /// Record an action in the audit log.  This should be called inside the
+    /// same transaction as the change being recorded.
+    pub async fn record(
+        db: &mut AsyncPgConnection,
+        auth: &Authorisation,
+        action: &str,
+        maildomain: Option<i32>,
+        target: &str,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) -> QueryResult<()> {
+        use crate::schema::auditlog::dsl;
+
+        diesel::insert_into(dsl::auditlog)
+            .values(NewAuditLog {
+                mailuser: Some(auth.user()),
+                token_label: auth.token_label(),
+                action,
+                maildomain,
+                target,
+                before,
+                after,
+                account: None,
+            })
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Record an action an account took on itself, having authenticated
+    /// with its own password rather than as a user
+    pub async fn record_by_account(
+        db: &mut AsyncPgConnection,
+        account: &str,
+        action: &str,
+        maildomain: i32,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) -> QueryResult<()> {
+        use crate::schema::auditlog::dsl;
+
+        diesel::insert_into(dsl::auditlog)
+            .values(NewAuditLog {
+                mailuser: None,
+                token_label: "",
+                action,
+                maildomain: Some(maildomain),
+                target: account,
+                before,
+                after,
+                account: Some(account),
+            })
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Search the audit log, newest first, returning each entry along with
+    /// the name of the user who made it (if a user did) and the domain it
+    /// concerns
+    pub async fn search(
+        db: &mut AsyncPgConnection,
+        filter: &AuditFilter,
+    ) -> QueryResult<Vec<(Self, Option<String>, Option<String>)>> {
+        use crate::schema::{auditlog, maildomain, mailuser};
+
+        let mut query = auditlog::table
+            .left_join(mailuser::table)
+            .left_join(maildomain::table.on(auditlog::maildomain.eq(maildomain::id.nullable())))
+            .select((
+                auditlog::all_columns,
+                mailuser::username.nullable(),
+                maildomain::domainname.nullable(),
+            ))
+            .order_by(auditlog::at.desc())
+            .then_order_by(auditlog::id.desc())
+            .limit(filter.limit)
+            .into_boxed();
+
+        if let Some(domain) = filter.domain {
+            query = query.filter(auditlog::maildomain.eq(domain));
+        }
+        if let Some(user) = filter.user {
+            query = query.filter(auditlog::mailuser.eq(user));
+        }
+        if let Some(target) = &filter.target {
+            query = query.filter(auditlog::target.eq(target.clone()));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(auditlog::at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(auditlog::at.lt(until));
+        }
+        if let Some((domains, user)) = &filter.visible_to {
+            query = query.filter(
+                auditlog::maildomain
+                    .eq_any(domains.clone())
+                    .or(auditlog::maildomain
+                        .is_null()
+                        .and(auditlog::mailuser.eq(*user))),
+            );
+        }
+
+        query.get_results(db).await
+    }
+}
+
+// Snapshots of the various models for recording in the audit log.  These
+// must never include secrets such as passwords or private keys.
+
+impl MailDomain {
+    pub fn audit_json(&self) -> Value {
+        json!({
+            "domain-name": self.domainname,
+            "owner": self.owner,
+            "remote-mx": self.remotemx,
+            "sender-verify": self.sender_verify,
+            "grey-listing": self.grey_listing,
+            "virus-check": self.virus_check,
+            "spamcheck-threshold": self.spamcheck_threshold,
+            "subaddress-separator": self.subaddress_separator,
+        })
+    }
+}
+
+impl MailEntry {
+    pub fn audit_json(&self) -> Value {
+        let kind = match self.kind {
+            MailEntryKind::Login => "login",
+            MailEntryKind::Account => "account",
+            MailEntryKind::Alias => "alias",
+            MailEntryKind::Bouncer => "bouncer",
+            MailEntryKind::Blackhole => "blackhole",
+            MailEntryKind::List => "list",
+        };
+        json!({
+            "name": self.name,
+            "kind": kind,
+            "expansion": self.expansion,
+        })
+    }
+}
+
+impl MailDomainKey {
+    pub fn audit_json(&self) -> Value {
+        json!({
+            "selector": self.selector,
+            "signing": self.signing,
+        })
+    }
+}
+
+impl MailAuthToken {
+    pub fn audit_json(&self) -> Value {
+        let scope = self.scope();
+        json!({
+            "id": self.prefix,
+            "label": self.label,
+            "expires-at": self.expires_at.and_then(|t| t.format(&Rfc3339).ok()),
+            "domains": scope.domains,
+            "capabilities": scope.capabilities,
+        })
+    }
+}
+
+impl MailList {
+    pub fn audit_json(&self) -> Value {
+        let members: Vec<Value> = self
+            .members
+            .iter()
+            .map(|member| {
+                json!({
+                    "target": member.target,
+                    "digest": member.digest,
+                    "nomail": member.nomail,
+                    "moderator": member.moderator,
+                })
+            })
+            .collect();
+        json!({
+            "settings": self.settings.to_api(),
+            "members": members,
+        })
+    }
+}
+
+impl Forwarding {
+    pub fn audit_json(&self) -> Value {
+        json!({
+            "targets": self.targets,
+            "keep-local-copy": self.keep_local_copy,
+        })
+    }
+}
+
+impl MailSieveScript {
+    pub fn audit_json(&self) -> Value {
+        json!({
+            "script": self.script,
+        })
+    }
+}
+
+impl MailVacation {
+    pub fn audit_json(&self) -> Value {
+        json!(self.to_api())
+    }
+}
+
+impl MailUser {
+    pub fn audit_json(&self) -> Value {
+        json!({
+            "username": self.username,
+            "superuser": self.superuser,
+        })
+    }
+}
@@ -0,0 +1,598 @@
+//! Parsing and checking of alias and list expansions
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::{util, MailDomain, MailEntry, MailEntryKind, CATCH_ALL};
+
+/// A single target of an alias or list expansion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpansionTarget {
+    /// An entry in the same domain as the alias or list
+    Local(String),
+    /// A full address, which may or may not be in a domain we host
+    Address { local: String, domain: String },
+}
+
+impl ExpansionTarget {
+    /// Parse a single target, returning `None` if it is not a plausible
+    /// local name or address.  Domain names are lowercased.
+    pub fn parse(target: &str) -> Option<Self> {
+        match target.rsplit_once('@') {
+            None => util::valid_local_part(target).then(|| Self::Local(target.to_string())),
+            Some((local, domain)) => {
+                let domain = domain.to_ascii_lowercase();
+                (util::valid_local_part(local) && util::valid_domain_name(&domain)).then(|| {
+                    Self::Address {
+                        local: local.to_string(),
+                        domain,
+                    }
+                })
+            }
+        }
+    }
+
+    /// The local part and domain of this target, qualifying local names
+    /// with the given domain
+    pub fn split<'a>(&'a self, domain: &'a str) -> (&'a str, &'a str) {
+        match self {
+            Self::Local(local) => (local, domain),
+            Self::Address { local, domain } => (local, domain),
+        }
+    }
+
+    /// The full address of this target, qualifying local names with the
+    /// given domain
+    pub fn address(&self, domain: &str) -> String {
+        let (local, domain) = self.split(domain);
+        format!("{local}@{domain}")
+    }
+}
+
+impl fmt::Display for ExpansionTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local(local) => f.write_str(local),
+            Self::Address { local, domain } => write!(f, "{local}@{domain}"),
+        }
+    }
+}
+
+/// Parse a comma-separated expansion into its targets, returning the first
+/// problem if there is one
+pub fn parse_expansion(expansion: &str) -> Result<Vec<ExpansionTarget>, ExpansionProblem> {
+    let mut targets = Vec::new();
+    for target in expansion.split(',').map(str::trim) {
+        if target.is_empty() {
+            continue;
+        }
+        let parsed = ExpansionTarget::parse(target)
+            .ok_or_else(|| ExpansionProblem::Invalid(target.to_string()))?;
+        if !targets.contains(&parsed) {
+            targets.push(parsed);
+        }
+    }
+    if targets.is_empty() {
+        return Err(ExpansionProblem::Empty);
+    }
+    Ok(targets)
+}
+
+/// Render targets back into the usual comma-separated form
+pub fn render_expansion(targets: &[ExpansionTarget]) -> String {
+    targets
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reasons an alias or list expansion is unacceptable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpansionProblem {
+    /// There are no targets at all
+    Empty,
+    /// The target is not a valid local name or address
+    Invalid(String),
+    /// The target is in a domain we host, but does not exist
+    Missing(String),
+    /// The target is a bouncer or blackhole so would never receive mail
+    Undeliverable(String),
+    /// Following the expansion leads back round to the entry, via these
+    /// addresses
+    Loop(Vec<String>),
+}
+
+impl MailEntry {
+    /// The targets of this entry's expansion, if it is an alias or list.
+    /// Any unparseable targets are skipped.
+    pub fn targets(&self) -> Vec<ExpansionTarget> {
+        if !self.has_targets() {
+            return vec![];
+        }
+        split_expansion(self.expansion.as_deref().unwrap_or(""))
+            .into_iter()
+            .filter_map(ExpansionTarget::parse)
+            .collect()
+    }
+}
+
+/// Split a comma-separated expansion into its targets, without checking
+/// them, dropping any empty or repeated targets
+pub(crate) fn split_expansion(expansion: &str) -> Vec<&str> {
+    let mut targets = Vec::new();
+    for target in expansion.split(',').map(str::trim) {
+        if !target.is_empty() && !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+    targets
+}
+
+/// Fill in the expansions of any aliases and lists from their targets, in
+/// the legacy comma-separated form
+pub(crate) async fn fill_targets(
+    db: &mut AsyncPgConnection,
+    entries: &mut [MailEntry],
+) -> QueryResult<()> {
+    use crate::schema::mailentry_target::dsl;
+
+    let ids: Vec<i32> = entries
+        .iter()
+        .filter(|entry| entry.has_targets())
+        .map(|entry| entry.id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let rows: Vec<(i32, String)> = dsl::mailentry_target
+        .filter(dsl::mailentry.eq_any(ids))
+        .order_by((dsl::mailentry, dsl::position))
+        .select((dsl::mailentry, dsl::target))
+        .get_results(db)
+        .await?;
+    let mut targets: HashMap<i32, Vec<String>> = HashMap::new();
+    for (entry, target) in rows {
+        targets.entry(entry).or_default().push(target);
+    }
+
+    for entry in entries.iter_mut().filter(|entry| entry.has_targets()) {
+        entry.expansion = Some(targets.remove(&entry.id).unwrap_or_default().join(", "));
+    }
+
+    Ok(())
+}
+
+impl MailEntry {
+    /// Whether this kind of entry has targets rather than an expansion
+    pub fn has_targets(&self) -> bool {
+        matches!(self.kind, MailEntryKind::Alias | MailEntryKind::List)
+    }
+
+    /// Replace all of this entry's targets.  Any flags on targets which
+    /// remain are kept.
+    pub async fn set_targets(
+        &self,
+        db: &mut AsyncPgConnection,
+        targets: &[&str],
+    ) -> QueryResult<()> {
+        use crate::schema::mailentry_target::dsl;
+
+        diesel::delete(dsl::mailentry_target)
+            .filter(dsl::mailentry.eq(self.id))
+            .filter(dsl::target.ne_all(targets))
+            .execute(db)
+            .await?;
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<_> = targets
+            .iter()
+            .zip(0..)
+            .map(|(target, position)| {
+                (
+                    dsl::mailentry.eq(self.id),
+                    dsl::position.eq(position),
+                    dsl::target.eq(*target),
+                )
+            })
+            .collect();
+        diesel::insert_into(dsl::mailentry_target)
+            .values(rows)
+            .on_conflict((dsl::mailentry, dsl::target))
+            .do_update()
+            .set(dsl::position.eq(excluded(dsl::position)))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Add a target to the end of this entry's targets, returning whether it
+    /// was added (it may already be present)
+    pub async fn add_target(&self, db: &mut AsyncPgConnection, target: &str) -> QueryResult<bool> {
+        use crate::schema::mailentry_target::dsl;
+
+        let last: Option<i32> = dsl::mailentry_target
+            .filter(dsl::mailentry.eq(self.id))
+            .select(diesel::dsl::max(dsl::position))
+            .get_result(db)
+            .await?;
+        diesel::insert_into(dsl::mailentry_target)
+            .values((
+                dsl::mailentry.eq(self.id),
+                dsl::position.eq(last.map_or(0, |p| p + 1)),
+                dsl::target.eq(target),
+            ))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await
+            .map(|n| n > 0)
+    }
+
+    /// Remove a target from this entry, returning whether it was present
+    pub async fn remove_target(
+        &self,
+        db: &mut AsyncPgConnection,
+        target: &str,
+    ) -> QueryResult<bool> {
+        use crate::schema::mailentry_target::dsl;
+
+        diesel::delete(dsl::mailentry_target)
+            .filter(dsl::mailentry.eq(self.id))
+            .filter(dsl::target.eq(target))
+            .execute(db)
+            .await
+            .map(|n| n > 0)
+    }
+
+    /// How many targets this entry has
+    pub async fn target_count(&self, db: &mut AsyncPgConnection) -> QueryResult<i64> {
+        use crate::schema::mailentry_target::dsl;
+
+        dsl::mailentry_target
+            .filter(dsl::mailentry.eq(self.id))
+            .count()
+            .get_result(db)
+            .await
+    }
+}
+
+/// Looks up entries by address across all the domains we host, caching the
+/// domains as it goes
+#[derive(Default)]
+pub struct HostedLookup {
+    domains: HashMap<String, Option<MailDomain>>,
+}
+
+impl HostedLookup {
+    /// The hosted domain of the given name, if we host it.  For domain
+    /// aliases this is the domain they are an alias of.
+    pub async fn domain(
+        &mut self,
+        db: &mut AsyncPgConnection,
+        domain: &str,
+    ) -> QueryResult<Option<&MailDomain>> {
+        if !self.domains.contains_key(domain) {
+            let found = MailDomain::hosting(db, domain).await?;
+            self.domains.insert(domain.to_string(), found);
+        }
+        Ok(self.domains.get(domain).and_then(Option::as_ref))
+    }
+
+    /// The live entry which takes mail for the given address, if its domain
+    /// is hosted and some entry matches it
+    pub async fn entry(
+        &mut self,
+        db: &mut AsyncPgConnection,
+        local: &str,
+        domain: &str,
+    ) -> QueryResult<Option<MailEntry>> {
+        match self.domain(db, domain).await? {
+            Some(domain) => domain.entry_for(db, local).await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl MailDomain {
+    /// The local part with any subaddress tag removed, if this domain uses
+    /// subaddressing and the local part has a tag
+    pub fn strip_subaddress<'a>(&self, local: &'a str) -> Option<&'a str> {
+        let separator = self.subaddress_separator.as_deref()?;
+        local
+            .find(|c| separator.contains(c))
+            .map(|pos| &local[..pos])
+            .filter(|base| !base.is_empty())
+    }
+
+    /// The live entry which takes mail for the given local part.  That is
+    /// the entry of that name, or failing that the entry for the local part
+    /// without its subaddress tag, or failing that the catch-all entry.
+    pub async fn entry_for(
+        &self,
+        db: &mut AsyncPgConnection,
+        local: &str,
+    ) -> QueryResult<Option<MailEntry>> {
+        if let Some(entry) = self.entry_by_name(db, local).await? {
+            return Ok(Some(entry));
+        }
+        if let Some(base) = self.strip_subaddress(local) {
+            if let Some(entry) = self.entry_by_name(db, base).await? {
+                return Ok(Some(entry));
+            }
+        }
+        self.entry_by_name(db, CATCH_ALL).await
+    }
+}
+
+impl MailDomain {
+    /// Check a proposed expansion for the named alias or list in this domain,
+    /// or the addresses a named account forwards to, returning the parsed
+    /// targets if it is acceptable.
+    ///
+    /// Unless `force` is set, every target in a domain we host must exist
+    /// (or the domain must forward unknown addresses to a remote MX), and
+    /// must not be a bouncer or blackhole.  Loops are never acceptable.
+    pub async fn check_expansion(
+        &self,
+        db: &mut AsyncPgConnection,
+        name: &str,
+        expansion: &str,
+        force: bool,
+    ) -> QueryResult<Result<Vec<ExpansionTarget>, ExpansionProblem>> {
+        let targets = match parse_expansion(expansion) {
+            Ok(targets) => targets,
+            Err(problem) => return Ok(Err(problem)),
+        };
+        let start = format!("{name}@{}", self.domainname);
+        let mut lookup = HostedLookup::default();
+
+        if !force {
+            for target in &targets {
+                let address = target.address(&self.domainname);
+                if address == start {
+                    // Caught as a loop below
+                    continue;
+                }
+                let (local, domain) = target.split(&self.domainname);
+                match lookup.entry(db, local, domain).await? {
+                    Some(entry)
+                        if matches!(
+                            entry.kind,
+                            MailEntryKind::Bouncer | MailEntryKind::Blackhole
+                        ) =>
+                    {
+                        return Ok(Err(ExpansionProblem::Undeliverable(address)));
+                    }
+                    Some(_) => {}
+                    None => {
+                        if let Some(domain) = lookup.domain(db, domain).await? {
+                            if domain.remotemx.is_none() {
+                                return Ok(Err(ExpansionProblem::Missing(address)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Walk everything reachable from the proposed targets, looking for
+        // a way back to the entry itself
+        let mut seen = HashSet::new();
+        let mut pending: Vec<(String, Vec<String>)> = targets
+            .iter()
+            .map(|target| {
+                let address = target.address(&self.domainname);
+                (address.clone(), vec![start.clone(), address])
+            })
+            .collect();
+        while !pending.is_empty() {
+            // Take a whole step at once, so that the onward targets of every
+            // entry reached can be loaded together
+            let mut reached = Vec::new();
+            for (address, path) in std::mem::take(&mut pending) {
+                if address == start {
+                    return Ok(Err(ExpansionProblem::Loop(path)));
+                }
+                if !seen.insert(address.clone()) {
+                    continue;
+                }
+                let Some((local, domain)) = address.rsplit_once('@') else {
+                    continue;
+                };
+                if let Some(entry) = lookup.entry(db, local, domain).await? {
+                    let hosted = lookup
+                        .domain(db, domain)
+                        .await?
+                        .map(|domain| domain.domainname.clone())
+                        .unwrap_or_default();
+                    if entry.name != local || hosted != domain {
+                        // Matched by subaddress, catch-all or domain alias,
+                        // so carry on from the entry which actually matched
+                        let next = format!("{}@{hosted}", entry.name);
+                        let mut path = path.clone();
+                        path.push(next.clone());
+                        pending.push((next, path));
+                        continue;
+                    }
+                    reached.push((entry, domain.to_string(), path));
+                }
+            }
+            let entries: Vec<&MailEntry> = reached.iter().map(|(entry, _, _)| entry).collect();
+            let mut onward = MailEntry::onward_targets_of(db, &entries).await?;
+            for (entry, domain, path) in &reached {
+                for target in onward.remove(&entry.id).unwrap_or_default() {
+                    let next = target.address(domain);
+                    let mut path = path.clone();
+                    path.push(next.clone());
+                    pending.push((next, path));
+                }
+            }
+        }
+
+        Ok(Ok(targets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test::test_db, MailUser};
+    use super::*;
+
+    fn local(name: &str) -> ExpansionTarget {
+        ExpansionTarget::Local(name.into())
+    }
+
+    fn address(local: &str, domain: &str) -> ExpansionTarget {
+        ExpansionTarget::Address {
+            local: local.into(),
+            domain: domain.into(),
+        }
+    }
+
+    #[test]
+    fn parses_local_and_full_targets() {
+        assert_eq!(ExpansionTarget::parse("someone"), Some(local("someone")));
+        assert_eq!(
+            ExpansionTarget::parse("Someone@Example.COM"),
+            Some(address("Someone", "example.com"))
+        );
+        assert_eq!(
+            address("someone", "example.com").address("other.org"),
+            "someone@example.com"
+        );
+        assert_eq!(local("someone").address("other.org"), "someone@other.org");
+    }
+
+    #[test]
+    fn refuses_implausible_targets() {
+        for target in [
+            "",
+            "some one",
+            "a:b",
+            "*",
+            "someone@",
+            "@example.com",
+            "x@-bad.com",
+        ] {
+            assert_eq!(ExpansionTarget::parse(target), None, "{target:?}");
+        }
+    }
+
+    #[test]
+    fn parses_expansions() {
+        assert_eq!(
+            parse_expansion(" one, two@Example.com,,one ,two@example.com "),
+            Ok(vec![local("one"), address("two", "example.com")])
+        );
+        assert_eq!(parse_expansion(" , "), Err(ExpansionProblem::Empty));
+        assert_eq!(
+            parse_expansion("one, bad target"),
+            Err(ExpansionProblem::Invalid("bad target".into()))
+        );
+        assert_eq!(
+            render_expansion(&parse_expansion("one,two@example.com").unwrap()),
+            "one, two@example.com"
+        );
+    }
+
+    async fn domain(
+        db: &mut AsyncPgConnection,
+        owner: i32,
+        name: &str,
+        subaddress_separator: Option<&str>,
+    ) -> MailDomain {
+        MailDomain::create(
+            db,
+            name,
+            owner,
+            None,
+            true,
+            false,
+            true,
+            100,
+            subaddress_separator,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn check(
+        db: &mut AsyncPgConnection,
+        domain: &MailDomain,
+        name: &str,
+        expansion: &str,
+        force: bool,
+    ) -> Result<Vec<ExpansionTarget>, ExpansionProblem> {
+        domain
+            .check_expansion(db, name, expansion, force)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_targets_and_loops() {
+        let Some(mut db) = test_db().await else {
+            return;
+        };
+        let db = &mut db;
+        let (user, _) = MailUser::create(db, "expansion-user", false).await.unwrap();
+        let first = domain(db, user.id, "first.example", Some("+")).await;
+        let second = domain(db, user.id, "second.example", None).await;
+        second.add_alias(db, "third.example").await.unwrap();
+        first
+            .new_alias(db, "loopy", "someone@elsewhere.example")
+            .await
+            .unwrap();
+        first.new_bouncer(db, "gone", "Left").await.unwrap();
+        first.new_blackhole(db, "void", "Dropped").await.unwrap();
+        // Anything unknown in the second domain comes back to the first
+        second
+            .new_alias(db, CATCH_ALL, "loopy+tagged@first.example")
+            .await
+            .unwrap();
+
+        assert!(
+            check(db, &first, "new", "loopy, someone@elsewhere.example", false)
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            check(db, &first, "new", "nobody", false).await,
+            Err(ExpansionProblem::Missing("nobody@first.example".into()))
+        );
+        assert_eq!(
+            check(db, &first, "new", "gone", false).await,
+            Err(ExpansionProblem::Undeliverable("gone@first.example".into()))
+        );
+        assert_eq!(
+            check(db, &first, "new", "void", false).await,
+            Err(ExpansionProblem::Undeliverable("void@first.example".into()))
+        );
+        assert!(check(db, &first, "new", "nobody, gone, void", true)
+            .await
+            .is_ok());
+
+        // Through the domain alias, the catch-all, and the subaddress
+        let cycle = vec![
+            "loopy@first.example".to_string(),
+            "anyone@third.example".to_string(),
+            "*@second.example".to_string(),
+            "loopy+tagged@first.example".to_string(),
+            "loopy@first.example".to_string(),
+        ];
+        for force in [false, true] {
+            assert_eq!(
+                check(db, &first, "loopy", "anyone@third.example", force).await,
+                Err(ExpansionProblem::Loop(cycle.clone()))
+            );
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Forwarding of an account's mail, optionally keeping a local copy
+//!
+
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::{ExpansionTarget, MailDomain, MailEntry, MailEntryKind};
+
+/// Where an account's mail is forwarded to, and whether it is also kept
+#[derive(Debug, Clone)]
+pub struct Forwarding {
+    pub targets: Vec<String>,
+    pub keep_local_copy: bool,
+}
+
+impl MailEntry {
+    /// The forwarding of this entry, if it is an account which forwards
+    pub async fn forwarding(&self, db: &mut AsyncPgConnection) -> QueryResult<Option<Forwarding>> {
+        use crate::schema::mailentry_forward::dsl;
+
+        if self.kind != MailEntryKind::Account {
+            return Ok(None);
+        }
+        let targets: Vec<String> = dsl::mailentry_forward
+            .filter(dsl::mailentry.eq(self.id))
+            .order_by(dsl::position)
+            .select(dsl::target)
+            .get_results(db)
+            .await?;
+        Ok((!targets.is_empty()).then_some(Forwarding {
+            targets,
+            keep_local_copy: self.keep_local_copy,
+        }))
+    }
+
+    /// Replace all of the addresses this entry forwards to.  An empty list
+    /// stops forwarding altogether.
+    pub async fn set_forwards(
+        &self,
+        db: &mut AsyncPgConnection,
+        targets: &[&str],
+    ) -> QueryResult<()> {
+        use crate::schema::mailentry_forward::dsl;
+
+        diesel::delete(dsl::mailentry_forward)
+            .filter(dsl::mailentry.eq(self.id))
+            .execute(db)
+            .await?;
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<_> = targets
+            .iter()
+            .zip(0..)
+            .map(|(target, position)| {
+                (
+                    dsl::mailentry.eq(self.id),
+                    dsl::position.eq(position),
+                    dsl::target.eq(*target),
+                )
+            })
+            .collect();
+        diesel::insert_into(dsl::mailentry_forward)
+            .values(rows)
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Everywhere mail to this entry is passed on to: the targets of an
+    /// alias or list, or the addresses an account forwards to
+    pub async fn onward_targets(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<Vec<ExpansionTarget>> {
+        Ok(Self::onward_targets_of(db, &[self])
+            .await?
+            .remove(&self.id)
+            .unwrap_or_default())
+    }
+
+    /// The onward targets of several entries by entry id, with the forwards
+    /// of all of them loaded at once
+    pub async fn onward_targets_of(
+        db: &mut AsyncPgConnection,
+        entries: &[&MailEntry],
+    ) -> QueryResult<HashMap<i32, Vec<ExpansionTarget>>> {
+        use crate::schema::mailentry_forward::dsl;
+
+        let accounts: Vec<i32> = entries
+            .iter()
+            .filter(|entry| entry.kind == MailEntryKind::Account)
+            .map(|entry| entry.id)
+            .collect();
+        let mut forwards: HashMap<i32, Vec<ExpansionTarget>> = HashMap::new();
+        if !accounts.is_empty() {
+            let rows: Vec<(i32, String)> = dsl::mailentry_forward
+                .filter(dsl::mailentry.eq_any(&accounts))
+                .order_by((dsl::mailentry, dsl::position))
+                .select((dsl::mailentry, dsl::target))
+                .get_results(db)
+                .await?;
+            for (entry, target) in rows {
+                let targets = forwards.entry(entry).or_default();
+                targets.extend(ExpansionTarget::parse(&target));
+            }
+        }
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let targets = forwards
+                    .remove(&entry.id)
+                    .unwrap_or_else(|| entry.targets());
+                (entry.id, targets)
+            })
+            .collect())
+    }
+}
+
+impl MailDomain {
+    /// The forwarding of every live account in this domain which forwards,
+    /// by entry id
+    pub async fn forwardings(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<HashMap<i32, Forwarding>> {
+        use crate::schema::{mailentry, mailentry_forward};
+
+        let rows: Vec<(i32, bool, String)> = mailentry_forward::table
+            .inner_join(mailentry::table)
+            .filter(mailentry::maildomain.eq(self.id))
+            .filter(mailentry::kind.eq(MailEntryKind::Account))
+            .filter(mailentry::deleted_at.is_null())
+            .order_by((mailentry_forward::mailentry, mailentry_forward::position))
+            .select((
+                mailentry_forward::mailentry,
+                mailentry::keep_local_copy,
+                mailentry_forward::target,
+            ))
+            .get_results(db)
+            .await?;
+
+        let mut forwardings: HashMap<i32, Forwarding> = HashMap::new();
+        for (entry, keep_local_copy, target) in rows {
+            forwardings
+                .entry(entry)
+                .or_insert_with(|| Forwarding {
+                    targets: vec![],
+                    keep_local_copy,
+                })
+                .targets
+                .push(target);
+        }
+        Ok(forwardings)
+    }
+}
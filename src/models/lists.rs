@@ -0,0 +1,201 @@
+//! Mailing list settings, and the flags on each list member
+//!
+
+use std::collections::HashMap;
+
+use api_types::lists::{ListMemberFlags, ListSettings};
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::{ListPostPolicy, ListReplyTo, MailDomain, MailEntry, MailEntryKind};
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::mailentry_list)]
+pub struct MailListSettings {
+    pub mailentry: i32,
+    pub post_policy: ListPostPolicy,
+    pub reply_to: ListReplyTo,
+    pub subject_prefix: Option<String>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct MailListMember {
+    pub target: String,
+    pub digest: bool,
+    pub nomail: bool,
+    pub moderator: bool,
+}
+
+/// A mailing list's settings, along with its members in order
+#[derive(Debug, Clone)]
+pub struct MailList {
+    pub settings: MailListSettings,
+    pub members: Vec<MailListMember>,
+}
+
+impl MailListSettings {
+    /// The settings of a list which has never had any set
+    pub fn defaults(mailentry: i32) -> Self {
+        Self {
+            mailentry,
+            post_policy: ListPostPolicy::Members,
+            reply_to: ListReplyTo::Sender,
+            subject_prefix: None,
+        }
+    }
+
+    pub fn to_api(&self) -> ListSettings {
+        ListSettings {
+            post_policy: self.post_policy.into(),
+            reply_to: self.reply_to.into(),
+            subject_prefix: self.subject_prefix.clone(),
+        }
+    }
+
+    pub fn apply(&mut self, settings: &ListSettings) {
+        self.post_policy = settings.post_policy.into();
+        self.reply_to = settings.reply_to.into();
+        self.subject_prefix = settings
+            .subject_prefix
+            .clone()
+            .filter(|prefix| !prefix.is_empty());
+    }
+
+    pub async fn save(&self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::mailentry_list::dsl;
+
+        diesel::insert_into(dsl::mailentry_list)
+            .values(self)
+            .on_conflict(dsl::mailentry)
+            .do_update()
+            .set((
+                dsl::post_policy.eq(excluded(dsl::post_policy)),
+                dsl::reply_to.eq(excluded(dsl::reply_to)),
+                dsl::subject_prefix.eq(excluded(dsl::subject_prefix)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl MailListMember {
+    pub fn flags(&self) -> ListMemberFlags {
+        ListMemberFlags {
+            digest: self.digest,
+            nomail: self.nomail,
+            moderator: self.moderator,
+        }
+    }
+}
+
+/// Load the settings and members of the given lists
+async fn load_lists(
+    db: &mut AsyncPgConnection,
+    ids: Vec<i32>,
+) -> QueryResult<HashMap<i32, MailList>> {
+    use crate::schema::{mailentry_list, mailentry_target};
+
+    let mut lists: HashMap<i32, MailList> = ids
+        .iter()
+        .map(|id| {
+            (
+                *id,
+                MailList {
+                    settings: MailListSettings::defaults(*id),
+                    members: vec![],
+                },
+            )
+        })
+        .collect();
+    if ids.is_empty() {
+        return Ok(lists);
+    }
+
+    let settings: Vec<MailListSettings> = mailentry_list::table
+        .filter(mailentry_list::mailentry.eq_any(&ids))
+        .get_results(db)
+        .await?;
+    for settings in settings {
+        if let Some(list) = lists.get_mut(&settings.mailentry) {
+            list.settings = settings;
+        }
+    }
+
+    let members: Vec<(i32, MailListMember)> = mailentry_target::table
+        .filter(mailentry_target::mailentry.eq_any(&ids))
+        .order_by((mailentry_target::mailentry, mailentry_target::position))
+        .select((
+            mailentry_target::mailentry,
+            (
+                mailentry_target::target,
+                mailentry_target::digest,
+                mailentry_target::nomail,
+                mailentry_target::moderator,
+            ),
+        ))
+        .get_results(db)
+        .await?;
+    for (id, member) in members {
+        if let Some(list) = lists.get_mut(&id) {
+            list.members.push(member);
+        }
+    }
+
+    Ok(lists)
+}
+
+impl MailEntry {
+    /// This entry's list settings and members, if it is a list
+    pub async fn mail_list(&self, db: &mut AsyncPgConnection) -> QueryResult<Option<MailList>> {
+        if self.kind != MailEntryKind::List {
+            return Ok(None);
+        }
+        Ok(load_lists(db, vec![self.id]).await?.remove(&self.id))
+    }
+
+    /// Set the flags on one of this list's members, returning whether the
+    /// member was found
+    pub async fn set_member_flags(
+        &self,
+        db: &mut AsyncPgConnection,
+        target: &str,
+        flags: ListMemberFlags,
+    ) -> QueryResult<bool> {
+        use crate::schema::mailentry_target::dsl;
+
+        diesel::update(dsl::mailentry_target)
+            .filter(dsl::mailentry.eq(self.id))
+            .filter(dsl::target.eq(target))
+            .set((
+                dsl::digest.eq(flags.digest),
+                dsl::nomail.eq(flags.nomail),
+                dsl::moderator.eq(flags.moderator),
+            ))
+            .execute(db)
+            .await
+            .map(|n| n > 0)
+    }
+}
+
+impl MailDomain {
+    /// The settings and members of all the live lists in this domain, keyed
+    /// by entry id
+    pub async fn mail_lists(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<HashMap<i32, MailList>> {
+        use crate::schema::mailentry::dsl;
+
+        let ids = dsl::mailentry
+            .filter(dsl::maildomain.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
+            .filter(dsl::kind.eq(MailEntryKind::List))
+            .select(dsl::id)
+            .get_results(db)
+            .await?;
+        load_lists(db, ids).await
+    }
+}
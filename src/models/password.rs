@@ -0,0 +1,480 @@
+//! Password hashes, in the schemes Dovecot understands
+//!
+//! New passwords are always hashed with Argon2id, but hashes carried over
+//! from other systems may be given in any of the schemes here.  Those keep
+//! working, and are replaced with Argon2id the next time their password is
+//! verified.
+//!
+//! Passwords can also be made up here, for when nobody wants to pick one.
+
+use std::fmt;
+
+use api_types::passwords::PasswordStyle;
+use argon2::{
+    password_hash::SaltString, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier,
+    ARGON2ID_IDENT,
+};
+use base64::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use rsa::rand_core::OsRng;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+
+use super::util::constant_time_eq;
+
+/// A way of hashing passwords, named as Dovecot names it
+trait PasswordScheme: Sync {
+    /// The name which appears in braces before the hash
+    fn name(&self) -> &'static str;
+
+    /// Whether the hash (without its prefix) could have come from this scheme
+    fn well_formed(&self, hash: &str) -> bool;
+
+    /// Whether the password is the one which was hashed
+    fn verify(&self, hash: &str, password: &str) -> bool;
+}
+
+/// The characters of the base64 variant used by crypt(3) style hashes
+fn crypt64(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '/')
+}
+
+struct Argon2id;
+
+impl Argon2id {
+    /// The most memory, in KiB, a given hash may ask for each time it is
+    /// checked
+    const MAX_MEMORY: u32 = 256 * 1024;
+    /// The most passes over that memory
+    const MAX_TIME: u32 = 16;
+    /// The most lanes, each of which may be a thread
+    const MAX_PARALLELISM: u32 = 8;
+}
+
+impl PasswordScheme for Argon2id {
+    fn name(&self) -> &'static str {
+        "ARGON2ID"
+    }
+
+    fn well_formed(&self, hash: &str) -> bool {
+        // Hashes are checked whenever someone logs in, so one which asks for
+        // more than we'd ever use ourselves could be used to tie us up
+        PasswordHash::new(hash)
+            .ok()
+            .filter(|hash| hash.algorithm == ARGON2ID_IDENT)
+            .and_then(|hash| Params::try_from(&hash).ok())
+            .map(|params| {
+                params.m_cost() <= Self::MAX_MEMORY
+                    && params.t_cost() <= Self::MAX_TIME
+                    && params.p_cost() <= Self::MAX_PARALLELISM
+            })
+            .unwrap_or(false)
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> bool {
+        PasswordHash::new(hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// bcrypt, as `$2y$<cost>$<salt and checksum>`
+struct BlfCrypt;
+
+impl BlfCrypt {
+    /// The highest cost accepted; each step up doubles the work
+    const MAX_COST: u32 = 14;
+}
+
+impl PasswordScheme for BlfCrypt {
+    fn name(&self) -> &'static str {
+        "BLF-CRYPT"
+    }
+
+    fn well_formed(&self, hash: &str) -> bool {
+        let mut parts = hash.split('$');
+        matches!(parts.next(), Some(""))
+            && matches!(parts.next(), Some("2a" | "2b" | "2y"))
+            && parts
+                .next()
+                .filter(|cost| cost.len() == 2)
+                .and_then(|cost| cost.parse::<u32>().ok())
+                .map(|cost| (4..=Self::MAX_COST).contains(&cost))
+                .unwrap_or(false)
+            && parts
+                .next()
+                .map(|rest| rest.len() == 53 && crypt64(rest))
+                .unwrap_or(false)
+            && parts.next().is_none()
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> bool {
+        // bcrypt only looks at the first 72 bytes, and cannot hash nothing
+        !password.is_empty() && pwhash::bcrypt::verify(password, hash)
+    }
+}
+
+/// glibc's SHA-512 crypt, as `$6$[rounds=<n>$]<salt>$<checksum>`
+struct Sha512Crypt;
+
+impl Sha512Crypt {
+    /// The most rounds accepted, well short of the 999,999,999 glibc allows
+    const MAX_ROUNDS: u32 = 1_000_000;
+}
+
+impl PasswordScheme for Sha512Crypt {
+    fn name(&self) -> &'static str {
+        "SHA512-CRYPT"
+    }
+
+    fn well_formed(&self, hash: &str) -> bool {
+        let Some(rest) = hash.strip_prefix("$6$") else {
+            return false;
+        };
+        let rest = match rest.strip_prefix("rounds=") {
+            Some(rest) => match rest.split_once('$') {
+                Some((rounds, rest))
+                    if rounds
+                        .parse::<u32>()
+                        .map(|rounds| rounds <= Self::MAX_ROUNDS)
+                        .unwrap_or(false) =>
+                {
+                    rest
+                }
+                _ => return false,
+            },
+            None => rest,
+        };
+        match rest.split_once('$') {
+            Some((salt, checksum)) => {
+                salt.len() <= 16 && crypt64(salt) && checksum.len() == 86 && crypt64(checksum)
+            }
+            None => false,
+        }
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> bool {
+        pwhash::sha512_crypt::verify(password, hash)
+    }
+}
+
+/// Salted SHA-512, as base64 of the digest followed by the salt
+struct Ssha512;
+
+impl Ssha512 {
+    const DIGEST_LEN: usize = 64;
+
+    fn decode(hash: &str) -> Option<Vec<u8>> {
+        BASE64_STANDARD
+            .decode(hash)
+            .ok()
+            .filter(|raw| raw.len() > Self::DIGEST_LEN)
+    }
+}
+
+impl PasswordScheme for Ssha512 {
+    fn name(&self) -> &'static str {
+        "SSHA512"
+    }
+
+    fn well_formed(&self, hash: &str) -> bool {
+        Self::decode(hash).is_some()
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> bool {
+        let Some(raw) = Self::decode(hash) else {
+            return false;
+        };
+        let (digest, salt) = raw.split_at(Self::DIGEST_LEN);
+        let computed = Sha512::new()
+            .chain_update(password.as_bytes())
+            .chain_update(salt)
+            .finalize();
+        constant_time_eq(digest, computed.as_slice())
+    }
+}
+
+/// Every scheme a stored hash may be in.  The first is the one new
+/// passwords are hashed with.
+const SCHEMES: &[&dyn PasswordScheme] = &[&Argon2id, &BlfCrypt, &Sha512Crypt, &Ssha512];
+
+/// The scheme named by the `{SCHEME}` prefix of a value, and the rest of it.
+/// Dovecot does not mind the case of the name, so neither do we.
+fn split_scheme(value: &str) -> Option<(&'static dyn PasswordScheme, &str)> {
+    SCHEMES.iter().find_map(|scheme| {
+        let prefix_len = scheme.name().len() + 2;
+        let prefix = value.get(..prefix_len)?;
+        let name = prefix.strip_prefix('{')?.strip_suffix('}')?;
+        name.eq_ignore_ascii_case(scheme.name())
+            .then(|| (*scheme, &value[prefix_len..]))
+    })
+}
+
+/// A pre-hashed password was given whose hash makes no sense for its scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MalformedHash {
+    pub scheme: &'static str,
+}
+
+impl fmt::Display for MalformedHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed {{{}}} password hash", self.scheme)
+    }
+}
+
+impl std::error::Error for MalformedHash {}
+
+/// A password ready to be stored, as `{SCHEME}hash`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashedPassword(String);
+
+impl HashedPassword {
+    /// Hash a plain password, or accept one which is already hashed in one
+    /// of the known schemes.  Anything else is taken to be a plain password,
+    /// even if it happens to start with something in braces.
+    pub fn new(password: &str) -> Result<Self, MalformedHash> {
+        Self::check(password)?;
+        Ok(Self(match split_scheme(password) {
+            Some((scheme, hash)) => format!("{{{}}}{hash}", scheme.name()),
+            None => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .expect("Unable to hash password");
+                format!("{{{}}}{hash}", SCHEMES[0].name())
+            }
+        }))
+    }
+
+    /// Check that a password could be stored, without the expense of
+    /// hashing it
+    pub fn check(password: &str) -> Result<(), MalformedHash> {
+        match split_scheme(password) {
+            Some((scheme, hash)) if !scheme.well_formed(hash) => Err(MalformedHash {
+                scheme: scheme.name(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the value is a hash in one of the known schemes, rather than
+    /// a plain password
+    pub fn is_prehashed(value: &str) -> bool {
+        split_scheme(value).is_some()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// Whether a stored hash is in a scheme other than the one new passwords
+/// get, and so should be replaced once the password is known
+pub fn needs_upgrade(stored: &str) -> bool {
+    !matches!(split_scheme(stored), Some((scheme, _)) if scheme.name() == SCHEMES[0].name())
+}
+
+/// Check whether a candidate password matches a stored hash.  A candidate
+/// which is itself hashed only matches the identical hash.
+pub fn password_matches(stored: &str, candidate: &str) -> bool {
+    if HashedPassword::is_prehashed(candidate) {
+        return stored == candidate;
+    }
+    split_scheme(stored)
+        .map(|(scheme, hash)| scheme.verify(hash, candidate))
+        .unwrap_or(false)
+}
+
+/// The words passphrases are made from: the BIP 39 English list, chosen
+/// because no two of its words share their first four letters
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// Characters for random passwords, leaving out those easily mistaken for
+/// one another
+const RANDOM_CHARSET: &[u8] =
+    b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789!#%+-.:=?@_~";
+
+/// A password made up by the server.  It is shown once to whoever asked for
+/// it and never recorded anywhere else, so it keeps out of debug output.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct GeneratedPassword(String);
+
+impl fmt::Debug for GeneratedPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GeneratedPassword(..)")
+    }
+}
+
+impl GeneratedPassword {
+    /// Make up a password of `size` characters, or `size` words for a
+    /// passphrase, but never shorter than `min_length` characters
+    pub fn generate(style: PasswordStyle, size: usize, min_length: usize) -> Self {
+        let mut rng = rand::thread_rng();
+        Self(match style {
+            PasswordStyle::Random => (0..size.max(min_length))
+                .map(|_| char::from(*RANDOM_CHARSET.choose(&mut rng).expect("charset")))
+                .collect(),
+            PasswordStyle::Passphrase => {
+                let words: Vec<&str> = WORDLIST.split_whitespace().collect();
+                let mut phrase: Vec<String> = Vec::new();
+                // Capitals, hyphens and a digit keep it within most policies
+                // on character classes without making it much harder to type
+                let len = |phrase: &[String]| phrase.iter().map(|w| w.len() + 1).sum::<usize>();
+                while phrase.len() < size.max(1) || len(&phrase) < min_length {
+                    let word = words.choose(&mut rng).expect("wordlist");
+                    let mut chars = word.chars();
+                    let first = chars.next().expect("word").to_ascii_uppercase();
+                    phrase.push(std::iter::once(first).chain(chars).collect());
+                }
+                format!("{}{}", phrase.join("-"), rng.gen_range(0..10))
+            }
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bcrypt(cost: &str) -> String {
+        format!("{{BLF-CRYPT}}$2y${cost}${}", "a".repeat(53))
+    }
+
+    fn sha512_crypt(rounds: &str) -> String {
+        format!("{{SHA512-CRYPT}}$6${rounds}saltsalt${}", "a".repeat(86))
+    }
+
+    fn argon2id(params: &str) -> String {
+        format!(
+            "{{ARGON2ID}}$argon2id$v=19${params}$c2FsdHNhbHRzYWx0${}",
+            "A".repeat(43)
+        )
+    }
+
+    // Known hashes, as Dovecot would store them
+    const ARGON2ID_PASSWORD: &str = "{ARGON2ID}$argon2id$v=19$m=256,t=2,p=1$c29tZXNhbHQ$nf65EOgLrQMR/uIPnA4rEsF5h7TKyQwu9U1bMCHGi/4";
+    const BLF_CRYPT_U_STAR_U: &str =
+        "{BLF-CRYPT}$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+    const SHA512_CRYPT_HELLO_WORLD: &str = "{SHA512-CRYPT}$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1";
+    const SHA512_CRYPT_ROUNDS_HELLO_WORLD: &str = "{SHA512-CRYPT}$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.";
+    const SSHA512_SECRET_PASSWORD: &str = "{SSHA512}OBt+/EzKlfaGPOu7f5nIoRBRhjG5T+Iwiedivo1hZLUq8vBB3/X5mJaf9uXy4jLvKqMFRVuAyKceBEMuoN/EZQECAwRzYWx0eSEh";
+
+    #[test]
+    fn accepts_new_hashes() {
+        let hashed = HashedPassword::new("correct horse").unwrap();
+        assert!(HashedPassword::check(hashed.as_str()).is_ok());
+        assert!(password_matches(hashed.as_str(), "correct horse"));
+        assert!(!needs_upgrade(hashed.as_str()));
+    }
+
+    #[test]
+    fn limits_bcrypt_cost() {
+        assert!(HashedPassword::check(&bcrypt("10")).is_ok());
+        assert!(HashedPassword::check(&bcrypt("14")).is_ok());
+        assert!(HashedPassword::check(&bcrypt("15")).is_err());
+        assert!(HashedPassword::check(&bcrypt("31")).is_err());
+    }
+
+    #[test]
+    fn limits_sha512_crypt_rounds() {
+        assert!(HashedPassword::check(&sha512_crypt("")).is_ok());
+        assert!(HashedPassword::check(&sha512_crypt("rounds=5000$")).is_ok());
+        assert!(HashedPassword::check(&sha512_crypt("rounds=1000000$")).is_ok());
+        assert!(HashedPassword::check(&sha512_crypt("rounds=1000001$")).is_err());
+        assert!(HashedPassword::check(&sha512_crypt("rounds=999999999$")).is_err());
+    }
+
+    #[test]
+    fn limits_argon2id_costs() {
+        assert!(HashedPassword::check(&argon2id("m=19456,t=2,p=1")).is_ok());
+        assert!(HashedPassword::check(&argon2id("m=262144,t=16,p=8")).is_ok());
+        assert!(HashedPassword::check(&argon2id("m=262145,t=2,p=1")).is_err());
+        assert!(HashedPassword::check(&argon2id("m=19456,t=17,p=1")).is_err());
+        assert!(HashedPassword::check(&argon2id("m=19456,t=2,p=9")).is_err());
+    }
+
+    #[test]
+    fn verifies_argon2id() {
+        assert!(HashedPassword::check(ARGON2ID_PASSWORD).is_ok());
+        assert!(password_matches(ARGON2ID_PASSWORD, "password"));
+    }
+
+    #[test]
+    fn verifies_blf_crypt() {
+        assert!(HashedPassword::check(BLF_CRYPT_U_STAR_U).is_ok());
+        assert!(password_matches(BLF_CRYPT_U_STAR_U, "U*U"));
+        // $2y$ is what Dovecot writes, and is the same for ASCII passwords
+        assert!(password_matches(
+            &BLF_CRYPT_U_STAR_U.replace("$2a$", "$2y$"),
+            "U*U"
+        ));
+    }
+
+    #[test]
+    fn verifies_sha512_crypt() {
+        assert!(HashedPassword::check(SHA512_CRYPT_HELLO_WORLD).is_ok());
+        assert!(password_matches(SHA512_CRYPT_HELLO_WORLD, "Hello world!"));
+        assert!(HashedPassword::check(SHA512_CRYPT_ROUNDS_HELLO_WORLD).is_ok());
+        assert!(password_matches(
+            SHA512_CRYPT_ROUNDS_HELLO_WORLD,
+            "Hello world!"
+        ));
+    }
+
+    #[test]
+    fn verifies_ssha512() {
+        assert!(HashedPassword::check(SSHA512_SECRET_PASSWORD).is_ok());
+        assert!(password_matches(SSHA512_SECRET_PASSWORD, "secret password"));
+        // Scheme names are not case sensitive
+        assert!(password_matches(
+            &SSHA512_SECRET_PASSWORD.replace("{SSHA512}", "{ssha512}"),
+            "secret password"
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_passwords() {
+        for (hash, password) in [
+            (ARGON2ID_PASSWORD, "Password"),
+            (BLF_CRYPT_U_STAR_U, "U*V"),
+            (SHA512_CRYPT_HELLO_WORLD, "Hello world"),
+            (SHA512_CRYPT_ROUNDS_HELLO_WORLD, "hello world!"),
+            (SSHA512_SECRET_PASSWORD, "secret passwore"),
+        ] {
+            assert!(!password_matches(hash, password), "{hash} took {password}");
+            assert!(!password_matches(hash, ""), "{hash} took nothing");
+        }
+        // A hash given as the password only matches itself
+        assert!(!password_matches(
+            SSHA512_SECRET_PASSWORD,
+            BLF_CRYPT_U_STAR_U
+        ));
+        assert!(password_matches(
+            SSHA512_SECRET_PASSWORD,
+            SSHA512_SECRET_PASSWORD
+        ));
+    }
+
+    #[test]
+    fn flags_legacy_hashes_for_upgrade() {
+        assert!(needs_upgrade(BLF_CRYPT_U_STAR_U));
+        assert!(needs_upgrade(SHA512_CRYPT_HELLO_WORLD));
+        assert!(needs_upgrade(SSHA512_SECRET_PASSWORD));
+        assert!(!needs_upgrade(ARGON2ID_PASSWORD));
+        assert!(!needs_upgrade(&argon2id("m=262144,t=16,p=8")));
+    }
+}
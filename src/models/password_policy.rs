@@ -0,0 +1,81 @@
+//! A domain's own rules for its logins' and accounts' passwords
+
+use api_types::passwords::PasswordPolicy;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::MailDomain;
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::maildomain_password_policy)]
+struct MailDomainPasswordPolicy {
+    maildomain: i32,
+    min_length: i32,
+    min_classes: i32,
+    forbid_names: bool,
+}
+
+fn to_db(value: u32) -> i32 {
+    i32::try_from(value).unwrap_or(i32::MAX)
+}
+
+fn from_db(value: i32) -> u32 {
+    u32::try_from(value).unwrap_or_default()
+}
+
+impl MailDomain {
+    /// The domain's own password rules, not including the deployment's
+    pub async fn password_policy(&self, db: &mut AsyncPgConnection) -> QueryResult<PasswordPolicy> {
+        use crate::schema::maildomain_password_policy::dsl;
+
+        let policy: Option<MailDomainPasswordPolicy> = dsl::maildomain_password_policy
+            .filter(dsl::maildomain.eq(self.id))
+            .first(db)
+            .await
+            .optional()?;
+        Ok(policy
+            .map(|policy| PasswordPolicy {
+                min_length: from_db(policy.min_length),
+                min_classes: from_db(policy.min_classes),
+                forbid_names: policy.forbid_names,
+            })
+            .unwrap_or_default())
+    }
+
+    /// Set the domain's own password rules.  A policy with no rules removes
+    /// them, leaving only the deployment's.
+    pub async fn set_password_policy(
+        &self,
+        db: &mut AsyncPgConnection,
+        policy: &PasswordPolicy,
+    ) -> QueryResult<()> {
+        use crate::schema::maildomain_password_policy::dsl;
+
+        if *policy == PasswordPolicy::default() {
+            return diesel::delete(dsl::maildomain_password_policy)
+                .filter(dsl::maildomain.eq(self.id))
+                .execute(db)
+                .await
+                .map(|_| ());
+        }
+        diesel::insert_into(dsl::maildomain_password_policy)
+            .values(MailDomainPasswordPolicy {
+                maildomain: self.id,
+                min_length: to_db(policy.min_length),
+                min_classes: to_db(policy.min_classes),
+                forbid_names: policy.forbid_names,
+            })
+            .on_conflict(dsl::maildomain)
+            .do_update()
+            .set((
+                dsl::min_length.eq(excluded(dsl::min_length)),
+                dsl::min_classes.eq(excluded(dsl::min_classes)),
+                dsl::forbid_names.eq(excluded(dsl::forbid_names)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+}
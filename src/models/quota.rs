@@ -0,0 +1,207 @@
+//! Mailbox storage quotas for accounts, and their domains' defaults and limits
+//!
+
+use std::collections::HashMap;
+
+use api_types::quota::{DomainQuota, Quota};
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::{MailDomain, MailEntry, MailEntryKind};
+
+fn to_db(value: Option<u64>) -> Option<i64> {
+    value.map(|value| i64::try_from(value).unwrap_or(i64::MAX))
+}
+
+fn from_db(value: Option<i64>) -> Option<u64> {
+    value.map(|value| u64::try_from(value).unwrap_or_default())
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::maildomain_quota)]
+struct MailDomainQuota {
+    maildomain: i32,
+    default_bytes: Option<i64>,
+    default_messages: Option<i64>,
+    limit_bytes: Option<i64>,
+    limit_messages: Option<i64>,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::mailentry_quota)]
+struct MailEntryQuota {
+    mailentry: i32,
+    bytes: Option<i64>,
+    messages: Option<i64>,
+}
+
+impl MailEntryQuota {
+    fn to_api(&self) -> Quota {
+        Quota {
+            bytes: from_db(self.bytes),
+            messages: from_db(self.messages),
+        }
+    }
+}
+
+impl MailDomain {
+    /// The default and limit for this domain's accounts
+    pub async fn quota(&self, db: &mut AsyncPgConnection) -> QueryResult<DomainQuota> {
+        use crate::schema::maildomain_quota::dsl;
+
+        let quota: Option<MailDomainQuota> = dsl::maildomain_quota
+            .filter(dsl::maildomain.eq(self.id))
+            .first(db)
+            .await
+            .optional()?;
+        Ok(quota
+            .map(|quota| DomainQuota {
+                default: Quota {
+                    bytes: from_db(quota.default_bytes),
+                    messages: from_db(quota.default_messages),
+                },
+                limit: Quota {
+                    bytes: from_db(quota.limit_bytes),
+                    messages: from_db(quota.limit_messages),
+                },
+            })
+            .unwrap_or_default())
+    }
+
+    pub async fn set_quota(
+        &self,
+        db: &mut AsyncPgConnection,
+        quota: &DomainQuota,
+    ) -> QueryResult<()> {
+        use crate::schema::maildomain_quota::dsl;
+
+        if quota.default.is_unlimited() && quota.limit.is_unlimited() {
+            return diesel::delete(dsl::maildomain_quota)
+                .filter(dsl::maildomain.eq(self.id))
+                .execute(db)
+                .await
+                .map(|_| ());
+        }
+        diesel::insert_into(dsl::maildomain_quota)
+            .values(MailDomainQuota {
+                maildomain: self.id,
+                default_bytes: to_db(quota.default.bytes),
+                default_messages: to_db(quota.default.messages),
+                limit_bytes: to_db(quota.limit.bytes),
+                limit_messages: to_db(quota.limit.messages),
+            })
+            .on_conflict(dsl::maildomain)
+            .do_update()
+            .set((
+                dsl::default_bytes.eq(excluded(dsl::default_bytes)),
+                dsl::default_messages.eq(excluded(dsl::default_messages)),
+                dsl::limit_bytes.eq(excluded(dsl::limit_bytes)),
+                dsl::limit_messages.eq(excluded(dsl::limit_messages)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// The quotas of all live accounts in this domain which have their own,
+    /// by entry id
+    pub async fn account_quotas(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<HashMap<i32, Quota>> {
+        use crate::schema::{mailentry, mailentry_quota};
+
+        let rows: Vec<MailEntryQuota> = mailentry_quota::table
+            .inner_join(mailentry::table)
+            .filter(mailentry::maildomain.eq(self.id))
+            .filter(mailentry::kind.eq(MailEntryKind::Account))
+            .filter(mailentry::deleted_at.is_null())
+            .select(mailentry_quota::all_columns)
+            .get_results(db)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|quota| (quota.mailentry, quota.to_api()))
+            .collect())
+    }
+
+    /// Whether this domain's live accounts fit within its limit between
+    /// them.  The domain is locked until the end of the transaction, so that
+    /// changes which each fit on their own can't go over it together; call
+    /// this after making them, within the same transaction.
+    pub async fn accounts_within_quota(&self, db: &mut AsyncPgConnection) -> QueryResult<bool> {
+        use crate::schema::{maildomain, mailentry, mailentry_quota};
+
+        maildomain::table
+            .filter(maildomain::id.eq(self.id))
+            .select(maildomain::id)
+            .for_update()
+            .execute(db)
+            .await?;
+        let quota = self.quota(db).await?;
+        if quota.limit.is_unlimited() {
+            return Ok(true);
+        }
+        let rows: Vec<Option<MailEntryQuota>> = mailentry::table
+            .left_join(mailentry_quota::table)
+            .filter(mailentry::maildomain.eq(self.id))
+            .filter(mailentry::kind.eq(MailEntryKind::Account))
+            .filter(mailentry::deleted_at.is_null())
+            .select(mailentry_quota::all_columns.nullable())
+            .get_results(db)
+            .await?;
+        let own: Vec<Option<Quota>> = rows
+            .iter()
+            .map(|row| row.as_ref().map(MailEntryQuota::to_api))
+            .collect();
+        Ok(quota.permits_total(own.iter().map(Option::as_ref)))
+    }
+}
+
+impl MailEntry {
+    /// This account's own quota, if it is an account and has one
+    pub async fn quota(&self, db: &mut AsyncPgConnection) -> QueryResult<Option<Quota>> {
+        use crate::schema::mailentry_quota::dsl;
+
+        if self.kind != MailEntryKind::Account {
+            return Ok(None);
+        }
+        let quota: Option<MailEntryQuota> = dsl::mailentry_quota
+            .filter(dsl::mailentry.eq(self.id))
+            .first(db)
+            .await
+            .optional()?;
+        Ok(quota.map(|quota| quota.to_api()))
+    }
+
+    /// Set this account's own quota.  An unlimited quota removes it, so that
+    /// the domain's default applies.
+    pub async fn set_quota(&self, db: &mut AsyncPgConnection, quota: &Quota) -> QueryResult<()> {
+        use crate::schema::mailentry_quota::dsl;
+
+        if quota.is_unlimited() {
+            return diesel::delete(dsl::mailentry_quota)
+                .filter(dsl::mailentry.eq(self.id))
+                .execute(db)
+                .await
+                .map(|_| ());
+        }
+        diesel::insert_into(dsl::mailentry_quota)
+            .values(MailEntryQuota {
+                mailentry: self.id,
+                bytes: to_db(quota.bytes),
+                messages: to_db(quota.messages),
+            })
+            .on_conflict(dsl::mailentry)
+            .do_update()
+            .set((
+                dsl::bytes.eq(excluded(dsl::bytes)),
+                dsl::messages.eq(excluded(dsl::messages)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,760 @@
+//! Per-account Sieve scripts, and checking them before they are stored
+//!
+//! The checker follows the grammar of RFC 5228.  On top of the grammar it
+//! insists that `require` comes before anything else, that `elsif` and
+//! `else` follow an `if`, and that the extensions we know about are required
+//! before their commands and tests are used.  It does not know every
+//! extension, so unknown commands and tests are left for the mail backend.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use time::OffsetDateTime;
+
+use super::{MailEntry, MailEntryKind};
+
+/// The longest script we will store, in bytes
+pub const MAX_SIEVE_LENGTH: usize = 64 * 1024;
+
+/// How deeply blocks and tests may nest.  The checker recurses as it goes
+/// deeper, so without a limit a short script could exhaust the stack.
+pub const MAX_SIEVE_NESTING: usize = 32;
+
+/// Commands and tests which need an extension to be required first
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("fileinto", "fileinto"),
+    ("reject", "reject"),
+    ("ereject", "ereject"),
+    ("vacation", "vacation"),
+    ("setflag", "imap4flags"),
+    ("addflag", "imap4flags"),
+    ("removeflag", "imap4flags"),
+    ("hasflag", "imap4flags"),
+    ("envelope", "envelope"),
+    ("body", "body"),
+    ("date", "date"),
+    ("currentdate", "date"),
+    ("set", "variables"),
+    ("string", "variables"),
+    ("notify", "enotify"),
+    ("valid_notify_method", "enotify"),
+    ("duplicate", "duplicate"),
+    ("mailboxexists", "mailbox"),
+    ("addheader", "editheader"),
+    ("deleteheader", "editheader"),
+    ("spamtest", "spamtest"),
+    ("virustest", "virustest"),
+];
+
+#[derive(Queryable, Debug, Clone)]
+pub struct MailSieveScript {
+    pub mailentry: i32,
+    pub script: String,
+    pub updated_at: OffsetDateTime,
+}
+
+/// Why a Sieve script was rejected, and where.  Lines and columns count
+/// from 1, with columns in characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SieveError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SieveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Tag,
+    Number,
+    String(String),
+    LeftBracket,
+    RightBracket,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Identifier(name) => format!("`{name}`"),
+            Token::Tag => "a tag".to_string(),
+            Token::Number => "a number".to_string(),
+            Token::String(_) => "a string".to_string(),
+            Token::LeftBracket => "`[`".to_string(),
+            Token::RightBracket => "`]`".to_string(),
+            Token::LeftParen => "`(`".to_string(),
+            Token::RightParen => "`)`".to_string(),
+            Token::LeftBrace => "`{`".to_string(),
+            Token::RightBrace => "`}`".to_string(),
+            Token::Comma => "`,`".to_string(),
+            Token::Semicolon => "`;`".to_string(),
+        }
+    }
+}
+
+/// A token along with the line and column it starts at
+type Positioned = (Token, usize, usize);
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn new(script: &str) -> Self {
+        Self {
+            chars: script.chars().collect(),
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error_at(line: usize, column: usize, message: impl Into<String>) -> SieveError {
+        SieveError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn tokens(mut self) -> Result<Vec<Positioned>, SieveError> {
+        let mut tokens = vec![];
+        while let Some(c) = self.peek() {
+            let (line, column) = (self.line, self.column);
+            let token = match c {
+                ' ' | '\t' | '\r' | '\n' => {
+                    self.bump();
+                    continue;
+                }
+                '#' => {
+                    while !matches!(self.bump(), None | Some('\n')) {}
+                    continue;
+                }
+                '/' if self.peek_at(1) == Some('*') => {
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match self.bump() {
+                            None => {
+                                return Err(Self::error_at(line, column, "unterminated comment"))
+                            }
+                            Some('*') if self.peek() == Some('/') => {
+                                self.bump();
+                                break;
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                    continue;
+                }
+                '"' => self.quoted_string(line, column)?,
+                ':' => {
+                    self.bump();
+                    if !self.peek().is_some_and(is_identifier_start) {
+                        return Err(Self::error_at(
+                            line,
+                            column,
+                            "expected a tag name after `:`",
+                        ));
+                    }
+                    self.identifier();
+                    Token::Tag
+                }
+                '0'..='9' => self.number(line, column)?,
+                c if is_identifier_start(c) => {
+                    let name = self.identifier();
+                    if name.eq_ignore_ascii_case("text") && self.peek() == Some(':') {
+                        self.bump();
+                        self.multi_line_string(line, column)?
+                    } else {
+                        Token::Identifier(name.to_ascii_lowercase())
+                    }
+                }
+                '[' | ']' | '(' | ')' | '{' | '}' | ',' | ';' => {
+                    self.bump();
+                    match c {
+                        '[' => Token::LeftBracket,
+                        ']' => Token::RightBracket,
+                        '(' => Token::LeftParen,
+                        ')' => Token::RightParen,
+                        '{' => Token::LeftBrace,
+                        '}' => Token::RightBrace,
+                        ',' => Token::Comma,
+                        _ => Token::Semicolon,
+                    }
+                }
+                c => {
+                    return Err(Self::error_at(
+                        line,
+                        column,
+                        format!("unexpected character `{c}`"),
+                    ))
+                }
+            };
+            tokens.push((token, line, column));
+        }
+        Ok(tokens)
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            name.push(c);
+            self.bump();
+        }
+        name
+    }
+
+    fn number(&mut self, line: usize, column: usize) -> Result<Token, SieveError> {
+        let mut value: u64 = 0;
+        while let Some(digit) = self.peek().and_then(|c| c.to_digit(10)) {
+            self.bump();
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(digit.into()))
+                .ok_or_else(|| Self::error_at(line, column, "number is too large"))?;
+        }
+        let scale: u64 = match self.peek().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => 1 << 10,
+            Some('M') => 1 << 20,
+            Some('G') => 1 << 30,
+            _ => 1,
+        };
+        if scale > 1 {
+            self.bump();
+        }
+        value
+            .checked_mul(scale)
+            .filter(|value| *value <= u64::from(u32::MAX))
+            .ok_or_else(|| Self::error_at(line, column, "number is too large"))?;
+        if self.peek().is_some_and(is_identifier_start) {
+            return Err(Self::error_at(
+                self.line,
+                self.column,
+                "unexpected letter in number",
+            ));
+        }
+        Ok(Token::Number)
+    }
+
+    fn quoted_string(&mut self, line: usize, column: usize) -> Result<Token, SieveError> {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(Self::error_at(line, column, "unterminated string")),
+                Some('"') => return Ok(Token::String(value)),
+                Some('\\') => match self.bump() {
+                    None => return Err(Self::error_at(line, column, "unterminated string")),
+                    Some(c) => value.push(c),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    /// The rest of a `text:` string, up to a line holding only a `.`
+    fn multi_line_string(&mut self, line: usize, column: usize) -> Result<Token, SieveError> {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\r' | '\n')) {
+                self.bump();
+            }
+        }
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        if self.bump() != Some('\n') {
+            return Err(Self::error_at(
+                self.line,
+                self.column,
+                "expected a new line after `text:`",
+            ));
+        }
+
+        let mut value = String::new();
+        loop {
+            let mut text = String::new();
+            let ended = loop {
+                match self.bump() {
+                    None => break false,
+                    Some('\n') => break true,
+                    Some(c) => text.push(c),
+                }
+            };
+            let text = text.strip_suffix('\r').unwrap_or(&text);
+            if text == "." {
+                return Ok(Token::String(value));
+            }
+            if !ended {
+                return Err(Self::error_at(line, column, "unterminated `text:` string"));
+            }
+            // Lines starting with a dot have had another added
+            value.push_str(text.strip_prefix('.').unwrap_or(text));
+            value.push('\n');
+        }
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// What was found in a single command, for checking how it was used
+struct Command {
+    name: String,
+    line: usize,
+    column: usize,
+    strings: Vec<String>,
+    has_test: bool,
+    has_block: bool,
+}
+
+struct Parser {
+    tokens: Vec<Positioned>,
+    pos: usize,
+    /// Where the script ends, for errors about things missing at the end
+    end: (usize, usize),
+    required: HashSet<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _, _)| token)
+    }
+
+    fn position(&self) -> (usize, usize) {
+        self.tokens
+            .get(self.pos)
+            .map(|(_, line, column)| (*line, *column))
+            .unwrap_or(self.end)
+    }
+
+    fn error(&self, message: impl Into<String>) -> SieveError {
+        let (line, column) = self.position();
+        Lexer::error_at(line, column, message)
+    }
+
+    fn expected(&self, what: &str) -> SieveError {
+        match self.peek() {
+            Some(token) => self.error(format!("expected {what}, found {}", token.describe())),
+            None => self.error(format!("expected {what}, found the end of the script")),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_required(&self, name: &str, line: usize, column: usize) -> Result<(), SieveError> {
+        match EXTENSIONS.iter().find(|(used, _)| *used == name) {
+            Some((_, extension)) if !self.required.contains(*extension) => Err(Lexer::error_at(
+                line,
+                column,
+                format!("`{name}` needs `require \"{extension}\";` first"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<(), SieveError> {
+        if depth > MAX_SIEVE_NESTING {
+            return Err(self.error(format!(
+                "blocks and tests may not nest more than {MAX_SIEVE_NESTING} deep"
+            )));
+        }
+        Ok(())
+    }
+
+    /// A sequence of commands, either the whole script or inside a block
+    fn commands(&mut self, top_level: bool, depth: usize) -> Result<(), SieveError> {
+        self.check_depth(depth)?;
+        let mut previous: Option<String> = None;
+        let mut past_requires = false;
+        loop {
+            match self.peek() {
+                None if top_level => return Ok(()),
+                None => return Err(self.expected("`}`")),
+                Some(Token::RightBrace) if !top_level => return Ok(()),
+                Some(Token::Identifier(_)) => {}
+                Some(_) => return Err(self.expected("a command")),
+            }
+            let command = self.command(depth)?;
+            let at = |message: String| Lexer::error_at(command.line, command.column, message);
+            match command.name.as_str() {
+                "require" => {
+                    if !top_level || past_requires {
+                        return Err(at(
+                            "`require` must come before any other command".to_string()
+                        ));
+                    }
+                    if command.strings.is_empty() || command.has_test || command.has_block {
+                        return Err(at("`require` takes a list of extensions".to_string()));
+                    }
+                    self.required.extend(command.strings);
+                }
+                "if" | "elsif" | "else" => {
+                    if command.name != "if"
+                        && !matches!(previous.as_deref(), Some("if") | Some("elsif"))
+                    {
+                        return Err(at(format!(
+                            "`{}` must follow `if` or `elsif`",
+                            command.name
+                        )));
+                    }
+                    if !command.has_block {
+                        return Err(at(format!("`{}` needs a block", command.name)));
+                    }
+                    if command.has_test == (command.name == "else") {
+                        return Err(at(match command.name.as_str() {
+                            "else" => "`else` does not take a test".to_string(),
+                            name => format!("`{name}` needs a test"),
+                        }));
+                    }
+                }
+                name => {
+                    self.check_required(name, command.line, command.column)?;
+                }
+            }
+            if command.name != "require" {
+                past_requires = true;
+            }
+            previous = Some(command.name);
+        }
+    }
+
+    fn command(&mut self, depth: usize) -> Result<Command, SieveError> {
+        let (line, column) = self.position();
+        let Some(Token::Identifier(name)) = self.next() else {
+            unreachable!("commands start with an identifier");
+        };
+        let strings = self.arguments()?;
+        let has_test = match self.peek() {
+            Some(Token::Identifier(_)) => {
+                self.test(depth + 1)?;
+                true
+            }
+            Some(Token::LeftParen) => {
+                self.test_list(depth + 1)?;
+                true
+            }
+            _ => false,
+        };
+        let has_block = match self.peek() {
+            Some(Token::Semicolon) => {
+                self.pos += 1;
+                false
+            }
+            Some(Token::LeftBrace) => {
+                self.pos += 1;
+                self.commands(false, depth + 1)?;
+                if !self.eat(&Token::RightBrace) {
+                    return Err(self.expected("`}`"));
+                }
+                true
+            }
+            _ => return Err(self.expected("`;` or `{`")),
+        };
+        Ok(Command {
+            name,
+            line,
+            column,
+            strings,
+            has_test,
+            has_block,
+        })
+    }
+
+    /// Any tags, numbers, strings and string lists, returning the strings
+    fn arguments(&mut self) -> Result<Vec<String>, SieveError> {
+        let mut strings = vec![];
+        loop {
+            match self.peek() {
+                Some(Token::Tag | Token::Number) => self.pos += 1,
+                Some(Token::String(value)) => {
+                    strings.push(value.clone());
+                    self.pos += 1;
+                }
+                Some(Token::LeftBracket) => {
+                    self.pos += 1;
+                    loop {
+                        match self.next() {
+                            Some(Token::String(value)) => strings.push(value),
+                            _ => {
+                                self.pos -= 1;
+                                return Err(self.expected("a string"));
+                            }
+                        }
+                        if self.eat(&Token::RightBracket) {
+                            break;
+                        }
+                        if !self.eat(&Token::Comma) {
+                            return Err(self.expected("`,` or `]`"));
+                        }
+                    }
+                }
+                _ => return Ok(strings),
+            }
+        }
+    }
+
+    fn test(&mut self, depth: usize) -> Result<(), SieveError> {
+        self.check_depth(depth)?;
+        let (line, column) = self.position();
+        let Some(Token::Identifier(name)) = self.next() else {
+            self.pos -= 1;
+            return Err(self.expected("a test"));
+        };
+        self.check_required(&name, line, column)?;
+        self.arguments()?;
+        match self.peek() {
+            Some(Token::Identifier(_)) => self.test(depth + 1),
+            Some(Token::LeftParen) => self.test_list(depth + 1),
+            _ => Ok(()),
+        }
+    }
+
+    fn test_list(&mut self, depth: usize) -> Result<(), SieveError> {
+        self.check_depth(depth)?;
+        self.pos += 1;
+        loop {
+            self.test(depth)?;
+            if self.eat(&Token::RightParen) {
+                return Ok(());
+            }
+            if !self.eat(&Token::Comma) {
+                return Err(self.expected("`,` or `)`"));
+            }
+        }
+    }
+}
+
+/// Check that a script is acceptable Sieve, returning the first problem
+pub fn validate_sieve(script: &str) -> Result<(), SieveError> {
+    if script.len() > MAX_SIEVE_LENGTH {
+        return Err(Lexer::error_at(
+            1,
+            1,
+            format!("script is longer than {MAX_SIEVE_LENGTH} bytes"),
+        ));
+    }
+    let lexer = Lexer::new(script);
+    let end = script.chars().fold((1, 1), |(line, column), c| match c {
+        '\n' => (line + 1, 1),
+        _ => (line, column + 1),
+    });
+    let mut parser = Parser {
+        tokens: lexer.tokens()?,
+        pos: 0,
+        end,
+        required: HashSet::new(),
+    };
+    parser.commands(true, 0)
+}
+
+impl MailEntry {
+    /// This account's Sieve script, if it has one
+    pub async fn sieve_script(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> QueryResult<Option<MailSieveScript>> {
+        use crate::schema::mailentry_sieve::dsl;
+
+        if self.kind != MailEntryKind::Account {
+            return Ok(None);
+        }
+        dsl::mailentry_sieve
+            .filter(dsl::mailentry.eq(self.id))
+            .first(db)
+            .await
+            .optional()
+    }
+
+    /// Store this entry's Sieve script, which must already have been
+    /// validated.  An empty script removes it.
+    pub async fn set_sieve_script(
+        &self,
+        db: &mut AsyncPgConnection,
+        script: &str,
+    ) -> QueryResult<()> {
+        use crate::schema::mailentry_sieve::dsl;
+
+        if script.trim().is_empty() {
+            return diesel::delete(dsl::mailentry_sieve)
+                .filter(dsl::mailentry.eq(self.id))
+                .execute(db)
+                .await
+                .map(|_| ());
+        }
+        diesel::insert_into(dsl::mailentry_sieve)
+            .values((
+                dsl::mailentry.eq(self.id),
+                dsl::script.eq(script),
+                dsl::updated_at.eq(OffsetDateTime::now_utc()),
+            ))
+            .on_conflict(dsl::mailentry)
+            .do_update()
+            .set((
+                dsl::script.eq(excluded(dsl::script)),
+                dsl::updated_at.eq(excluded(dsl::updated_at)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl MailSieveScript {
+    /// The scripts of every live account, along with the account's address,
+    /// optionally only those changed since the given time
+    pub async fn all(
+        db: &mut AsyncPgConnection,
+        since: Option<OffsetDateTime>,
+    ) -> QueryResult<Vec<(String, Self)>> {
+        use crate::schema::{maildomain, mailentry, mailentry_sieve};
+
+        let mut query = mailentry_sieve::table
+            .inner_join(mailentry::table.inner_join(maildomain::table))
+            .filter(mailentry::kind.eq(MailEntryKind::Account))
+            .filter(mailentry::deleted_at.is_null())
+            .order_by((maildomain::domainname, mailentry::name))
+            .select((
+                mailentry::name,
+                maildomain::domainname,
+                mailentry_sieve::all_columns,
+            ))
+            .into_boxed();
+        if let Some(since) = since {
+            query = query.filter(mailentry_sieve::updated_at.gt(since));
+        }
+
+        let rows: Vec<(String, String, Self)> = query.get_results(db).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, domain, script)| (format!("{name}@{domain}"), script))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_simple_script() {
+        let script = "require [\"fileinto\"];\n\
+                      if header :contains \"subject\" \"[club]\" {\n\
+                      \x20   fileinto \"Club\";\n\
+                      } elsif not exists \"list-id\" {\n\
+                      \x20   keep;\n\
+                      } else {\n\
+                      \x20   stop;\n\
+                      }\n";
+        assert_eq!(validate_sieve(script), Ok(()));
+    }
+
+    #[test]
+    fn needs_extensions_required_first() {
+        let err = validate_sieve("fileinto \"Junk\";").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 1));
+        let err = validate_sieve("keep;\nrequire \"fileinto\";").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+    }
+
+    #[test]
+    fn else_must_follow_if() {
+        assert!(validate_sieve("else { keep; }").is_err());
+    }
+
+    #[test]
+    fn accepts_nesting_up_to_the_limit() {
+        let depth = MAX_SIEVE_NESTING - 1;
+        let script = format!("{}keep;{}", "if true {".repeat(depth), "}".repeat(depth));
+        assert_eq!(validate_sieve(&script), Ok(()));
+        let script = format!("if {}true {{}}", "not ".repeat(depth));
+        assert_eq!(validate_sieve(&script), Ok(()));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_tests() {
+        let script = format!("if {}true {{}}", "not ".repeat(5000));
+        assert!(script.len() < MAX_SIEVE_LENGTH);
+        assert!(validate_sieve(&script)
+            .unwrap_err()
+            .message
+            .contains("nest"));
+        let script = format!("if {}true{} {{}}", "anyof(".repeat(5000), ")".repeat(5000));
+        assert!(validate_sieve(&script)
+            .unwrap_err()
+            .message
+            .contains("nest"));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_blocks() {
+        let script = format!("{}keep;{}", "if true {".repeat(6000), "}".repeat(6000));
+        assert!(script.len() < MAX_SIEVE_LENGTH);
+        assert!(validate_sieve(&script)
+            .unwrap_err()
+            .message
+            .contains("nest"));
+    }
+}
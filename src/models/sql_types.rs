@@ -3,9 +3,15 @@ use diesel::{
     sql_types::Text, AsExpression, FromSqlRow, SqlType,
 };
 
+use api_types::domains::MemberRole;
+use api_types::lists::{PostPolicy, ReplyTo};
+
+use crate::schema::sql_types::Listpostpolicy as ListPostPolicyType;
+use crate::schema::sql_types::Listreplyto as ListReplyToType;
+use crate::schema::sql_types::Maildomainrole as MailDomainRoleType;
 use crate::schema::sql_types::Mailentrykind as MailEntryKindType;
 
-#[derive(Debug, FromSqlRow, AsExpression, SqlType)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression, SqlType)]
 #[diesel(sql_type = MailEntryKindType)]
 pub enum MailEntryKind {
     Login,
@@ -56,3 +62,187 @@ impl QueryId for crate::schema::sql_types::Mailentrykind {
 
     const HAS_STATIC_QUERY_ID: bool = true;
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression, SqlType)]
+#[diesel(sql_type = MailDomainRoleType)]
+pub enum MailDomainRole {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl<DB: Backend> ToSql<MailDomainRoleType, DB> for MailDomainRole
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            MailDomainRole::Viewer => ("viewer").to_sql(out),
+            MailDomainRole::Editor => ("editor").to_sql(out),
+            MailDomainRole::Admin => ("admin").to_sql(out),
+        }
+    }
+}
+
+impl FromSql<MailDomainRoleType, Pg> for MailDomainRole {
+    fn from_sql(
+        bytes: <diesel::pg::Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"viewer" => Ok(Self::Viewer),
+            b"editor" => Ok(Self::Editor),
+            b"admin" => Ok(Self::Admin),
+            _ => Err("Unrecognised mail domain role variant".into()),
+        }
+    }
+}
+
+impl QueryId for crate::schema::sql_types::Maildomainrole {
+    type QueryId = Self;
+
+    const HAS_STATIC_QUERY_ID: bool = true;
+}
+
+impl From<MailDomainRole> for MemberRole {
+    fn from(value: MailDomainRole) -> Self {
+        match value {
+            MailDomainRole::Viewer => MemberRole::Viewer,
+            MailDomainRole::Editor => MemberRole::Editor,
+            MailDomainRole::Admin => MemberRole::Admin,
+        }
+    }
+}
+
+impl From<MemberRole> for MailDomainRole {
+    fn from(value: MemberRole) -> Self {
+        match value {
+            MemberRole::Viewer => MailDomainRole::Viewer,
+            MemberRole::Editor => MailDomainRole::Editor,
+            MemberRole::Admin => MailDomainRole::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression, SqlType)]
+#[diesel(sql_type = ListPostPolicyType)]
+pub enum ListPostPolicy {
+    Members,
+    Anyone,
+    Moderators,
+}
+
+impl<DB: Backend> ToSql<ListPostPolicyType, DB> for ListPostPolicy
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            ListPostPolicy::Members => ("members").to_sql(out),
+            ListPostPolicy::Anyone => ("anyone").to_sql(out),
+            ListPostPolicy::Moderators => ("moderators").to_sql(out),
+        }
+    }
+}
+
+impl FromSql<ListPostPolicyType, Pg> for ListPostPolicy {
+    fn from_sql(
+        bytes: <diesel::pg::Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"members" => Ok(Self::Members),
+            b"anyone" => Ok(Self::Anyone),
+            b"moderators" => Ok(Self::Moderators),
+            _ => Err("Unrecognised list post policy variant".into()),
+        }
+    }
+}
+
+impl QueryId for crate::schema::sql_types::Listpostpolicy {
+    type QueryId = Self;
+
+    const HAS_STATIC_QUERY_ID: bool = true;
+}
+
+impl From<ListPostPolicy> for PostPolicy {
+    fn from(value: ListPostPolicy) -> Self {
+        match value {
+            ListPostPolicy::Members => PostPolicy::Members,
+            ListPostPolicy::Anyone => PostPolicy::Anyone,
+            ListPostPolicy::Moderators => PostPolicy::Moderators,
+        }
+    }
+}
+
+impl From<PostPolicy> for ListPostPolicy {
+    fn from(value: PostPolicy) -> Self {
+        match value {
+            PostPolicy::Members => ListPostPolicy::Members,
+            PostPolicy::Anyone => ListPostPolicy::Anyone,
+            PostPolicy::Moderators => ListPostPolicy::Moderators,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression, SqlType)]
+#[diesel(sql_type = ListReplyToType)]
+pub enum ListReplyTo {
+    Sender,
+    List,
+}
+
+impl<DB: Backend> ToSql<ListReplyToType, DB> for ListReplyTo
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            ListReplyTo::Sender => ("sender").to_sql(out),
+            ListReplyTo::List => ("list").to_sql(out),
+        }
+    }
+}
+
+impl FromSql<ListReplyToType, Pg> for ListReplyTo {
+    fn from_sql(
+        bytes: <diesel::pg::Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"sender" => Ok(Self::Sender),
+            b"list" => Ok(Self::List),
+            _ => Err("Unrecognised list reply-to variant".into()),
+        }
+    }
+}
+
+impl QueryId for crate::schema::sql_types::Listreplyto {
+    type QueryId = Self;
+
+    const HAS_STATIC_QUERY_ID: bool = true;
+}
+
+impl From<ListReplyTo> for ReplyTo {
+    fn from(value: ListReplyTo) -> Self {
+        match value {
+            ListReplyTo::Sender => ReplyTo::Sender,
+            ListReplyTo::List => ReplyTo::List,
+        }
+    }
+}
+
+impl From<ReplyTo> for ListReplyTo {
+    fn from(value: ReplyTo) -> Self {
+        match value {
+            ReplyTo::Sender => ListReplyTo::Sender,
+            ReplyTo::List => ListReplyTo::List,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Self-service list subscriptions, and the outbox of mail to be sent
+//!
+
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use time::{Duration, OffsetDateTime};
+
+use super::{util, MailEntry};
+
+/// A request to join or leave a list, awaiting confirmation by the owner of
+/// the address
+#[derive(Queryable, Debug)]
+pub struct ListSubscription {
+    pub id: i32,
+    pub mailentry: i32,
+    pub address: String,
+    pub subscribe: bool,
+    pub token_hash: String,
+    pub expires_at: OffsetDateTime,
+}
+
+#[derive(Queryable, Debug)]
+pub struct OutboxMessage {
+    pub id: i32,
+    pub queued_at: OffsetDateTime,
+    pub sender: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl ListSubscription {
+    /// Record a request to join (or leave) the given list, replacing any
+    /// earlier such request for the same address.  Returns the token which
+    /// confirms the request.
+    pub async fn request(
+        db: &mut AsyncPgConnection,
+        list: &MailEntry,
+        address: &str,
+        subscribe: bool,
+        lifetime: Duration,
+    ) -> QueryResult<String> {
+        use crate::schema::list_subscription::dsl;
+
+        let token = util::generate_secret();
+        diesel::insert_into(dsl::list_subscription)
+            .values((
+                dsl::mailentry.eq(list.id),
+                dsl::address.eq(address),
+                dsl::subscribe.eq(subscribe),
+                dsl::token_hash.eq(util::hash_token(&token)),
+                dsl::expires_at.eq(OffsetDateTime::now_utc() + lifetime),
+            ))
+            .on_conflict((dsl::mailentry, dsl::address, dsl::subscribe))
+            .do_update()
+            .set((
+                dsl::token_hash.eq(excluded(dsl::token_hash)),
+                dsl::expires_at.eq(excluded(dsl::expires_at)),
+            ))
+            .execute(db)
+            .await?;
+        Ok(token)
+    }
+
+    /// The unexpired request confirmed by the given token, if there is one
+    pub async fn by_token(db: &mut AsyncPgConnection, token: &str) -> QueryResult<Option<Self>> {
+        use crate::schema::list_subscription::dsl;
+
+        dsl::list_subscription
+            .filter(dsl::token_hash.eq(util::hash_token(token)))
+            .filter(dsl::expires_at.gt(OffsetDateTime::now_utc()))
+            .first(db)
+            .await
+            .optional()
+    }
+
+    /// Remove all requests which have expired without being confirmed,
+    /// returning how many were removed
+    pub async fn prune_expired(db: &mut AsyncPgConnection) -> QueryResult<usize> {
+        use crate::schema::list_subscription::dsl;
+
+        diesel::delete(dsl::list_subscription)
+            .filter(dsl::expires_at.le(OffsetDateTime::now_utc()))
+            .execute(db)
+            .await
+    }
+
+    pub async fn delete_self(self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::list_subscription::dsl;
+
+        diesel::delete(dsl::list_subscription)
+            .filter(dsl::id.eq(self.id))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl OutboxMessage {
+    /// Queue a message to be sent by the mail frontend
+    pub async fn queue(
+        db: &mut AsyncPgConnection,
+        sender: &str,
+        recipient: &str,
+        subject: &str,
+        body: &str,
+    ) -> QueryResult<()> {
+        use crate::schema::mail_outbox::dsl;
+
+        diesel::insert_into(dsl::mail_outbox)
+            .values((
+                dsl::sender.eq(sender),
+                dsl::recipient.eq(recipient),
+                dsl::subject.eq(subject),
+                dsl::body.eq(body),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Take up to `limit` of the oldest messages out of the outbox.  Once
+    /// taken, messages are gone, so the caller is responsible for them.
+    pub async fn drain(db: &mut AsyncPgConnection, limit: i64) -> QueryResult<Vec<Self>> {
+        use crate::schema::mail_outbox::dsl;
+
+        db.transaction(|db| {
+            async move {
+                let messages: Vec<Self> = dsl::mail_outbox
+                    .order_by(dsl::id)
+                    .limit(limit)
+                    .for_update()
+                    .skip_locked()
+                    .get_results(db)
+                    .await?;
+                let ids: Vec<i32> = messages.iter().map(|message| message.id).collect();
+                diesel::delete(dsl::mail_outbox)
+                    .filter(dsl::id.eq_any(ids))
+                    .execute(db)
+                    .await?;
+                Ok(messages)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+}
@@ -1,18 +1,19 @@
 //! Utility stuff for the models, not exported
 //!
 
-use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher};
 use diesel::QueryResult;
 use rsa::{
     pkcs1::EncodeRsaPrivateKey,
     pkcs8::{EncodePublicKey, LineEnding},
-    rand_core::OsRng,
     RsaPrivateKey, RsaPublicKey,
 };
 
 use base64::prelude::*;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
 
-use super::MailUser;
+use super::{Capability, MailAuthToken, MailUser};
 
 pub fn create_dkim_pair() -> QueryResult<(String, String)> {
     let mut rng = rand::thread_rng();
@@ -35,30 +36,129 @@ pub fn create_dkim_pair() -> QueryResult<(String, String)> {
     Ok((privkey.to_string(), pubkey))
 }
 
+pub const TOKEN_PREFIX_LEN: usize = 8;
+const TOKEN_SECRET_LEN: usize = 32;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Generate a new authentication token, returning its public prefix and the
+/// full token string which is only ever shown to the user once.
+pub fn generate_token() -> (String, String) {
+    let prefix = random_string(TOKEN_PREFIX_LEN);
+    let secret = random_string(TOKEN_SECRET_LEN);
+    let token = format!("{prefix}.{secret}");
+    (prefix, token)
+}
+
+/// Generate a single-use secret, such as for confirming a subscription
+pub fn generate_secret() -> String {
+    random_string(TOKEN_SECRET_LEN)
+}
+
+/// The public identifier of a token.  Tokens issued before prefixes existed
+/// have no separator, and are identified by their first few characters.
+pub fn token_prefix(token: &str) -> &str {
+    match token.split_once('.') {
+        Some((prefix, _)) => prefix,
+        None => token.get(..TOKEN_PREFIX_LEN).unwrap_or(token),
+    }
+}
+
+/// Hash a full token string for storage
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Compare two byte strings without short-circuiting on the first difference
+pub fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The restrictions placed upon a token.  `None` means unrestricted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenScope {
+    pub domains: Option<Vec<i32>>,
+    pub capabilities: Option<Vec<Capability>>,
+}
+
+impl TokenScope {
+    pub fn is_restricted(&self) -> bool {
+        self.domains.is_some() || self.capabilities.is_some()
+    }
+
+    pub fn permits_domain(&self, domain: i32) -> bool {
+        self.domains
+            .as_ref()
+            .map(|domains| domains.contains(&domain))
+            .unwrap_or(true)
+    }
+
+    pub fn permits_capability(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .map(|caps| caps.contains(&capability))
+            .unwrap_or(true)
+    }
+
+    /// Whether this scope is no wider than `other`
+    pub fn within(&self, other: &TokenScope) -> bool {
+        fn subset<T: PartialEq>(inner: &Option<Vec<T>>, outer: &Option<Vec<T>>) -> bool {
+            match (inner, outer) {
+                (_, None) => true,
+                (None, Some(_)) => false,
+                (Some(inner), Some(outer)) => inner.iter().all(|v| outer.contains(v)),
+            }
+        }
+        subset(&self.domains, &other.domains) && subset(&self.capabilities, &other.capabilities)
+    }
+}
+
 /// An extension to be used by routes to determine access control
 /// If this extension isn't present that means that the user didn't
 /// supply a token.  if they supplied a token and it was bad then
 /// we return an error instead.
 #[derive(Debug, Clone)]
 pub struct Authorisation {
-    token: String,
+    token_prefix: String,
+    token_label: String,
     user: i32,
     username: String,
     superuser: bool,
+    scope: TokenScope,
+    expires_at: Option<OffsetDateTime>,
 }
 
 impl Authorisation {
-    pub fn new(token: String, user: &MailUser) -> Self {
+    pub fn new(token: &MailAuthToken, user: &MailUser) -> Self {
         Self {
-            token,
+            token_prefix: token.prefix.clone(),
+            token_label: token.label.clone(),
             user: user.id,
             username: user.username.clone(),
             superuser: user.superuser,
+            scope: token.scope(),
+            expires_at: token.expires_at,
         }
     }
 
-    pub fn token(&self) -> &str {
-        &self.token
+    /// The public identifier of the token used to authenticate
+    pub fn token_prefix(&self) -> &str {
+        &self.token_prefix
+    }
+
+    /// The label of the token used to authenticate
+    pub fn token_label(&self) -> &str {
+        &self.token_label
     }
 
     pub fn user(&self) -> i32 {
@@ -69,24 +169,77 @@ impl Authorisation {
         &self.username
     }
 
+    /// Superuser powers are only available through unrestricted tokens
     pub fn superuser(&self) -> bool {
-        self.superuser
+        self.superuser && !self.scope.is_restricted()
+    }
+
+    pub fn scope(&self) -> &TokenScope {
+        &self.scope
+    }
+
+    /// When the token used to authenticate expires, if it does
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.expires_at
+    }
+
+    /// Whether the token permits `capability` to be used at all
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.scope.permits_capability(capability)
     }
+
+    /// Whether the token permits `capability` to be used on `domain`
+    pub fn permits(&self, domain: i32, capability: Capability) -> bool {
+        self.scope.permits_domain(domain) && self.scope.permits_capability(capability)
+    }
+}
+
+/// Check that a string is usable as a domain's subaddress separator(s): a
+/// few punctuation characters, any one of which separates an address's tag
+pub fn valid_subaddress_separator(separator: &str) -> bool {
+    (1..=4).contains(&separator.len())
+        && separator
+            .chars()
+            .all(|c| c.is_ascii_punctuation() && !matches!(c, '@' | ',' | '.' | ':' | '*' | '"'))
+}
+
+/// Check that a string is a plausible DNS domain name
+pub fn valid_domain_name(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Check that a string is a plausible unquoted local part of an address
+pub fn valid_local_part(local: &str) -> bool {
+    !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && local
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, '@' | ',' | ':' | '*'))
+}
+
+/// Names which the entry APIs use for domain-wide routes, and so which an
+/// entry could not be reached by
+const RESERVED_ENTRY_NAMES: &[&str] = &["export", "import", "trash"];
+
+/// Check that a string is a local part which can safely be a component of
+/// paths such as accounts' mail homes, containing nothing which would take
+/// it out of its directory.
+pub fn safe_entry_name(name: &str) -> bool {
+    valid_local_part(name) && !name.contains(['/', '\\']) && !name.contains("..")
 }
 
-pub fn encode_password(password: &str) -> String {
-    (if let Some(rest) = password.strip_prefix("{ARGON2ID}") {
-        PasswordHash::new(rest).map(|h| h.serialize())
-    } else {
-        let salt = SaltString::generate(&mut OsRng);
-        PasswordHash::new(
-            &Argon2::default()
-                .hash_password(password.as_bytes(), &salt)
-                .expect("Unable to hash password")
-                .to_string(),
-        )
-        .map(|h| h.serialize())
-    })
-    .map(|h| format!("{{ARGON2ID}}{h}"))
-    .unwrap_or_else(|_| String::from(password))
+/// Check that a string may name a new entry: safe to use in paths, and not
+/// one of the names reserved by the entry APIs
+pub fn valid_entry_name(name: &str) -> bool {
+    safe_entry_name(name) && !RESERVED_ENTRY_NAMES.contains(&name)
 }
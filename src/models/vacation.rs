@@ -0,0 +1,197 @@
+//! Out of office auto-replies for accounts, and rendering them as Sieve
+//!
+
+use api_types::vacation::VacationSettings;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use time::{format_description::FormatItem, macros::format_description, Date};
+
+use super::{MailEntry, MailEntryKind};
+
+/// The longest interval between replies to the same sender
+pub const MAX_VACATION_INTERVAL: u32 = 365;
+
+const DATE_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::mailentry_vacation)]
+pub struct MailVacation {
+    pub mailentry: i32,
+    pub enabled: bool,
+    pub subject: String,
+    pub body: String,
+    pub starts_on: Option<Date>,
+    pub ends_on: Option<Date>,
+    pub interval_days: i32,
+}
+
+impl MailVacation {
+    /// The settings of an account which has never had any set
+    pub fn defaults(mailentry: i32) -> Self {
+        let settings = VacationSettings::default();
+        Self {
+            mailentry,
+            enabled: settings.enabled,
+            subject: settings.subject,
+            body: settings.body,
+            starts_on: None,
+            ends_on: None,
+            interval_days: settings.interval_days as i32,
+        }
+    }
+
+    pub fn to_api(&self) -> VacationSettings {
+        let format = |date: Date| date.format(DATE_FORMAT).ok();
+        VacationSettings {
+            enabled: self.enabled,
+            subject: self.subject.clone(),
+            body: self.body.clone(),
+            start: self.starts_on.and_then(format),
+            end: self.ends_on.and_then(format),
+            interval_days: self.interval_days as u32,
+        }
+    }
+
+    /// Replace these settings, returning why if they are unacceptable
+    pub fn apply(&mut self, settings: &VacationSettings) -> Result<(), String> {
+        let parse = |date: &Option<String>| match date.as_deref().map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(date) => Date::parse(date, DATE_FORMAT)
+                .map(Some)
+                .map_err(|_| format!("{date} is not a YYYY-MM-DD date")),
+        };
+        let starts_on = parse(&settings.start)?;
+        let ends_on = parse(&settings.end)?;
+        if let (Some(start), Some(end)) = (starts_on, ends_on) {
+            if start > end {
+                return Err("end is before start".to_string());
+            }
+        }
+        if !(1..=MAX_VACATION_INTERVAL).contains(&settings.interval_days) {
+            return Err(format!(
+                "interval-days must be between 1 and {MAX_VACATION_INTERVAL}"
+            ));
+        }
+        if settings.enabled && settings.body.trim().is_empty() {
+            return Err("an enabled auto-reply needs a body".to_string());
+        }
+        if settings.subject.contains(['\r', '\n']) {
+            return Err("subject must be a single line".to_string());
+        }
+
+        self.enabled = settings.enabled;
+        self.subject = settings.subject.trim().to_string();
+        self.body = settings.body.clone();
+        self.starts_on = starts_on;
+        self.ends_on = ends_on;
+        self.interval_days = settings.interval_days as i32;
+        Ok(())
+    }
+
+    pub async fn save(&self, db: &mut AsyncPgConnection) -> QueryResult<()> {
+        use crate::schema::mailentry_vacation::dsl;
+
+        diesel::insert_into(dsl::mailentry_vacation)
+            .values(self)
+            .on_conflict(dsl::mailentry)
+            .do_update()
+            .set((
+                dsl::enabled.eq(excluded(dsl::enabled)),
+                dsl::subject.eq(excluded(dsl::subject)),
+                dsl::body.eq(excluded(dsl::body)),
+                dsl::starts_on.eq(excluded(dsl::starts_on)),
+                dsl::ends_on.eq(excluded(dsl::ends_on)),
+                dsl::interval_days.eq(excluded(dsl::interval_days)),
+            ))
+            .execute(db)
+            .await
+            .map(|_| ())
+    }
+
+    /// Every enabled auto-reply on a live account, along with the account's
+    /// address
+    pub async fn all_enabled(db: &mut AsyncPgConnection) -> QueryResult<Vec<(String, Self)>> {
+        use crate::schema::{maildomain, mailentry, mailentry_vacation};
+
+        let rows: Vec<(String, String, Self)> = mailentry_vacation::table
+            .inner_join(mailentry::table.inner_join(maildomain::table))
+            .filter(mailentry_vacation::enabled.eq(true))
+            .filter(mailentry::kind.eq(MailEntryKind::Account))
+            .filter(mailentry::deleted_at.is_null())
+            .order_by((maildomain::domainname, mailentry::name))
+            .select((
+                mailentry::name,
+                maildomain::domainname,
+                mailentry_vacation::all_columns,
+            ))
+            .get_results(db)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, domain, vacation)| (format!("{name}@{domain}"), vacation))
+            .collect())
+    }
+
+    /// A Sieve script (RFC 5230, with RFC 5260 dates) which sends these
+    /// auto-replies
+    pub fn to_sieve(&self) -> String {
+        let mut action = format!("vacation :days {}", self.interval_days);
+        if !self.subject.is_empty() {
+            action.push_str(&format!(" :subject {}", sieve_string(&self.subject)));
+        }
+        action.push_str(&format!(" {};\n", sieve_string(&self.body)));
+
+        let mut tests = vec![];
+        if let Some(start) = self.starts_on.and_then(|d| d.format(DATE_FORMAT).ok()) {
+            tests.push(format!("currentdate :value \"ge\" \"date\" \"{start}\""));
+        }
+        if let Some(end) = self.ends_on.and_then(|d| d.format(DATE_FORMAT).ok()) {
+            tests.push(format!("currentdate :value \"le\" \"date\" \"{end}\""));
+        }
+
+        if tests.is_empty() {
+            format!("require [\"vacation\"];\n\n{action}")
+        } else {
+            format!(
+                "require [\"vacation\", \"date\", \"relational\"];\n\n\
+                 if allof ({}) {{\n    {action}}}\n",
+                tests.join(", ")
+            )
+        }
+    }
+}
+
+/// Quote a string for Sieve, which only needs `"` and `\` escaped
+fn sieve_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl MailEntry {
+    /// This account's auto-reply settings, or `None` if it is not an account
+    pub async fn vacation(&self, db: &mut AsyncPgConnection) -> QueryResult<Option<MailVacation>> {
+        use crate::schema::mailentry_vacation::dsl;
+
+        if self.kind != MailEntryKind::Account {
+            return Ok(None);
+        }
+        let vacation = dsl::mailentry_vacation
+            .filter(dsl::mailentry.eq(self.id))
+            .first(db)
+            .await
+            .optional()?;
+        Ok(Some(
+            vacation.unwrap_or_else(|| MailVacation::defaults(self.id)),
+        ))
+    }
+}
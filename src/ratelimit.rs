@@ -0,0 +1,380 @@
+//! Counting failed authentication attempts, to slow down password guessing,
+//! and requests which send mail to strangers, to stop them being abused
+//!
+//! Counts are only kept in memory, so restarting the service forgets them.
+//! They are kept both by the address concerned and by where the request
+//! came from, so that neither hammering one address nor trying many
+//! addresses from one place gets very far.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::HeaderMap;
+
+use crate::configuration::Configuration;
+
+/// Where a request came from.  Anyone may claim anything in
+/// `X-Forwarded-For`, so it is only believed when a trusted proxy passed it
+/// on, and then only as far back as the chain of trusted proxies goes.
+pub fn client_ip(config: &Configuration, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    let mut ip = peer.ip();
+    if !config.trusted_proxy(ip) {
+        return ip;
+    }
+    let hops: Vec<_> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in hops.into_iter().rev() {
+        let Ok(hop) = hop.trim().parse() else {
+            break;
+        };
+        ip = hop;
+        if !config.trusted_proxy(ip) {
+            break;
+        }
+    }
+    ip
+}
+
+/// The most keys tracked at once.  Once there are this many, stale ones are
+/// swept out, and if that is not enough those which failed least recently
+/// are dropped, so that a flood of new keys can't use up all our memory.
+const MAX_TRACKED: usize = 10_000;
+
+struct Failures<K> {
+    limit: usize,
+    recent: HashMap<K, VecDeque<Instant>>,
+}
+
+impl<K: Hash + Eq + Clone> Failures<K> {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            recent: HashMap::new(),
+        }
+    }
+
+    fn forget_before(times: &mut VecDeque<Instant>, cutoff: Option<Instant>) {
+        let Some(cutoff) = cutoff else {
+            return;
+        };
+        while times.front().map(|t| *t < cutoff).unwrap_or(false) {
+            times.pop_front();
+        }
+    }
+
+    fn blocked(&mut self, key: &K, cutoff: Option<Instant>) -> bool {
+        let Some(times) = self.recent.get_mut(key) else {
+            return false;
+        };
+        Self::forget_before(times, cutoff);
+        times.len() >= self.limit
+    }
+
+    fn sweep(&mut self, cutoff: Option<Instant>) {
+        self.recent.retain(|_, times| {
+            Self::forget_before(times, cutoff);
+            !times.is_empty()
+        });
+    }
+
+    fn record(&mut self, key: K, now: Instant, cutoff: Option<Instant>) {
+        if self.recent.len() >= MAX_TRACKED && !self.recent.contains_key(&key) {
+            self.sweep(cutoff);
+            while self.recent.len() >= MAX_TRACKED {
+                let stalest = self
+                    .recent
+                    .iter()
+                    .min_by_key(|(_, times)| times.back().copied())
+                    .map(|(key, _)| key.clone());
+                match stalest {
+                    Some(stalest) => self.recent.remove(&stalest),
+                    None => break,
+                };
+            }
+        }
+        let times = self.recent.entry(key).or_default();
+        Self::forget_before(times, cutoff);
+        times.push_back(now);
+    }
+
+    fn clear(&mut self, key: &K) {
+        self.recent.remove(key);
+    }
+}
+
+struct LimiterInner {
+    window: Duration,
+    by_address: Failures<String>,
+    by_ip: Failures<IpAddr>,
+}
+
+impl LimiterInner {
+    fn new(window: u64, per_address: usize, per_ip: usize) -> Self {
+        Self {
+            window: Duration::from_secs(window),
+            by_address: Failures::new(per_address),
+            by_ip: Failures::new(per_ip),
+        }
+    }
+
+    /// Failures before this are forgotten.  Shortly after boot the window may
+    /// reach back before the clock began, in which case nothing is.
+    fn cutoff(&self, now: Instant) -> Option<Instant> {
+        now.checked_sub(self.window)
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let cutoff = self.cutoff(now);
+        self.by_address.sweep(cutoff);
+        self.by_ip.sweep(cutoff);
+    }
+
+    fn blocked(&mut self, address: &str, ip: Option<IpAddr>) -> bool {
+        let cutoff = self.cutoff(Instant::now());
+        let address = address.to_ascii_lowercase();
+        self.by_address.blocked(&address, cutoff)
+            || ip
+                .map(|ip| self.by_ip.blocked(&ip, cutoff))
+                .unwrap_or(false)
+    }
+
+    fn record(&mut self, address: &str, ip: Option<IpAddr>) {
+        self.record_at(address, ip, Instant::now());
+    }
+
+    fn record_at(&mut self, address: &str, ip: Option<IpAddr>, now: Instant) {
+        let cutoff = self.cutoff(now);
+        self.by_address
+            .record(address.to_ascii_lowercase(), now, cutoff);
+        if let Some(ip) = ip {
+            self.by_ip.record(ip, now, cutoff);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Limiter {
+    inner: Arc<Mutex<LimiterInner>>,
+}
+
+impl Limiter {
+    fn new(window: u64, per_address: usize, per_ip: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LimiterInner::new(window, per_address, per_ip))),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterInner> {
+        // Nothing can be left half-updated, so a poisoned lock is still usable
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sweep(&self) {
+        self.lock().sweep(Instant::now());
+    }
+}
+
+/// Failed authentication attempts within the configured window
+#[derive(Clone)]
+pub struct AuthLimiter {
+    limiter: Limiter,
+}
+
+impl AuthLimiter {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            limiter: Limiter::new(
+                config.auth_failure_window(),
+                config.auth_failures_per_address(),
+                config.auth_failures_per_ip(),
+            ),
+        }
+    }
+
+    /// Forget failures which have dropped out of the window
+    pub fn sweep(&self) {
+        self.limiter.sweep();
+    }
+
+    /// Whether attempts for this address, or from this source, have failed
+    /// too often recently to be allowed another go
+    pub fn blocked(&self, address: &str, ip: Option<IpAddr>) -> bool {
+        self.limiter.lock().blocked(address, ip)
+    }
+
+    pub fn record_failure(&self, address: &str, ip: Option<IpAddr>) {
+        self.limiter.lock().record(address, ip);
+    }
+
+    /// Forget the failures for an address once someone gets it right.  Those
+    /// from the source are kept, since it may be trying many addresses.
+    pub fn record_success(&self, address: &str) {
+        self.limiter
+            .lock()
+            .by_address
+            .clear(&address.to_ascii_lowercase());
+    }
+}
+
+/// Requests which send mail to an address on the say-so of whoever asked,
+/// counted so that they can't be used to flood someone's mailbox
+#[derive(Clone)]
+pub struct MailoutLimiter {
+    limiter: Limiter,
+}
+
+impl MailoutLimiter {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            limiter: Limiter::new(
+                config.list_request_window(),
+                config.list_requests_per_address(),
+                config.list_requests_per_ip(),
+            ),
+        }
+    }
+
+    /// Forget requests which have dropped out of the window
+    pub fn sweep(&self) {
+        self.limiter.sweep();
+    }
+
+    /// Count a request to send mail to this address, unless there have
+    /// already been too many for it, or from this source
+    pub fn allow(&self, address: &str, ip: Option<IpAddr>) -> bool {
+        let mut inner = self.limiter.lock();
+        if inner.blocked(address, ip) {
+            return false;
+        }
+        inner.record(address, ip);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use config::Config;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    fn config(trusted_proxies: Option<&str>) -> Configuration {
+        let mut config = Config::builder()
+            .set_override("database_url", "postgres://localhost/test")
+            .unwrap();
+        if let Some(trusted_proxies) = trusted_proxies {
+            config = config
+                .set_override("trusted_proxies", trusted_proxies)
+                .unwrap();
+        }
+        Configuration::from_config(config.build().unwrap()).unwrap()
+    }
+
+    fn forwarded(hops: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for hop in hops {
+            headers.append("x-forwarded-for", HeaderValue::from_str(hop).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn believes_trusted_proxies() {
+        let config = config(None);
+        let proxy: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let headers = forwarded(&["198.51.100.7, 192.0.2.1"]);
+        assert_eq!(client_ip(&config, &headers, proxy), IP);
+        assert_eq!(client_ip(&config, &HeaderMap::new(), proxy), proxy.ip());
+        let headers = forwarded(&["nonsense"]);
+        assert_eq!(client_ip(&config, &headers, proxy), proxy.ip());
+    }
+
+    #[test]
+    fn ignores_forwarding_from_others() {
+        let peer: SocketAddr = "203.0.113.9:4000".parse().unwrap();
+        let headers = forwarded(&["192.0.2.1"]);
+        assert_eq!(client_ip(&config(None), &headers, peer), peer.ip());
+        assert_eq!(client_ip(&config(Some("")), &headers, peer), peer.ip());
+    }
+
+    #[test]
+    fn follows_chains_of_trusted_proxies() {
+        let config = config(Some("10.0.0.1, 10.0.0.2"));
+        let proxy: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let headers = forwarded(&["198.51.100.7", "192.0.2.1", "10.0.0.1"]);
+        assert_eq!(client_ip(&config, &headers, proxy), IP);
+        let local: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(client_ip(&config, &headers, local), local.ip());
+    }
+
+    #[test]
+    fn refuses_bad_proxies() {
+        let config = Config::builder()
+            .set_override("database_url", "postgres://localhost/test")
+            .unwrap()
+            .set_override("trusted_proxies", "127.0.0.1,localhost")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(Configuration::from_config(config).is_err());
+    }
+
+    #[test]
+    fn blocks_once_the_limit_is_reached() {
+        let mut limiter = LimiterInner::new(60, 3, 100);
+        for _ in 0..3 {
+            assert!(!limiter.blocked("someone@example.com", Some(IP)));
+            limiter.record("Someone@Example.com", Some(IP));
+        }
+        assert!(limiter.blocked("someone@example.com", None));
+        assert!(!limiter.blocked("other@example.com", Some(IP)));
+    }
+
+    #[test]
+    fn blocks_a_busy_source() {
+        let mut limiter = LimiterInner::new(60, 100, 2);
+        limiter.record("one@example.com", Some(IP));
+        limiter.record("two@example.com", Some(IP));
+        assert!(limiter.blocked("three@example.com", Some(IP)));
+        assert!(!limiter.blocked("three@example.com", None));
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let mut limiter = LimiterInner::new(60, 2, 2);
+        let long_ago = Instant::now() - Duration::from_secs(61);
+        limiter.record_at("someone@example.com", Some(IP), long_ago);
+        limiter.record_at("someone@example.com", Some(IP), long_ago);
+        assert!(!limiter.blocked("someone@example.com", Some(IP)));
+
+        limiter.record_at("other@example.com", None, long_ago);
+        limiter.sweep(Instant::now());
+        assert!(limiter.by_address.recent.is_empty());
+        assert!(limiter.by_ip.recent.is_empty());
+    }
+
+    #[test]
+    fn tracks_a_limited_number_of_keys() {
+        let mut failures = Failures::new(1);
+        let start = Instant::now();
+        for n in 0..MAX_TRACKED + 10 {
+            let at = start + Duration::from_millis(n as u64);
+            failures.record(n, at, None);
+        }
+        assert_eq!(failures.recent.len(), MAX_TRACKED);
+        // The least recent are the ones dropped
+        assert!(!failures.blocked(&0, None));
+        assert!(failures.blocked(&(MAX_TRACKED + 9), None));
+    }
+}
@@ -4,6 +4,18 @@ pub mod sql_types {
     #[derive(diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "mailentrykind"))]
     pub struct Mailentrykind;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "maildomainrole"))]
+    pub struct Maildomainrole;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "listpostpolicy"))]
+    pub struct Listpostpolicy;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "listreplyto"))]
+    pub struct Listreplyto;
 }
 
 diesel::table! {
@@ -15,12 +27,55 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    auditlog (id) {
+        id -> Int4,
+        at -> Timestamptz,
+        mailuser -> Nullable<Int4>,
+        token_label -> Varchar,
+        action -> Varchar,
+        maildomain -> Nullable<Int4>,
+        target -> Varchar,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        account -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    list_subscription (id) {
+        id -> Int4,
+        mailentry -> Int4,
+        address -> Varchar,
+        subscribe -> Bool,
+        token_hash -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    mail_outbox (id) {
+        id -> Int4,
+        queued_at -> Timestamptz,
+        sender -> Varchar,
+        recipient -> Varchar,
+        subject -> Varchar,
+        body -> Text,
+    }
+}
+
 diesel::table! {
     mailauthtoken (id) {
         id -> Int4,
         mailuser -> Int4,
         token -> Varchar,
         label -> Varchar,
+        prefix -> Varchar,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+        scope_domains -> Nullable<Array<Int4>>,
+        scope_capabilities -> Nullable<Array<Varchar>>,
     }
 }
 
@@ -34,6 +89,46 @@ diesel::table! {
         grey_listing -> Bool,
         virus_check -> Bool,
         spamcheck_threshold -> Int4,
+        subaddress_separator -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    maildomain_alias (id) {
+        id -> Int4,
+        maildomain -> Int4,
+        domainname -> Varchar,
+    }
+}
+
+diesel::table! {
+    maildomain_password_policy (maildomain) {
+        maildomain -> Int4,
+        min_length -> Int4,
+        min_classes -> Int4,
+        forbid_names -> Bool,
+    }
+}
+
+diesel::table! {
+    maildomain_quota (maildomain) {
+        maildomain -> Int4,
+        default_bytes -> Nullable<Int8>,
+        default_messages -> Nullable<Int8>,
+        limit_bytes -> Nullable<Int8>,
+        limit_messages -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Maildomainrole;
+
+    maildomain_member (id) {
+        id -> Int4,
+        maildomain -> Int4,
+        mailuser -> Int4,
+        role -> Maildomainrole,
     }
 }
 
@@ -59,6 +154,70 @@ diesel::table! {
         kind -> Mailentrykind,
         password -> Nullable<Varchar>,
         expansion -> Nullable<Varchar>,
+        deleted_at -> Nullable<Timestamptz>,
+        keep_local_copy -> Bool,
+    }
+}
+
+diesel::table! {
+    mailentry_forward (id) {
+        id -> Int4,
+        mailentry -> Int4,
+        position -> Int4,
+        target -> Varchar,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Listpostpolicy;
+    use super::sql_types::Listreplyto;
+
+    mailentry_list (mailentry) {
+        mailentry -> Int4,
+        post_policy -> Listpostpolicy,
+        reply_to -> Listreplyto,
+        subject_prefix -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    mailentry_quota (mailentry) {
+        mailentry -> Int4,
+        bytes -> Nullable<Int8>,
+        messages -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    mailentry_sieve (mailentry) {
+        mailentry -> Int4,
+        script -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    mailentry_target (id) {
+        id -> Int4,
+        mailentry -> Int4,
+        position -> Int4,
+        target -> Varchar,
+        digest -> Bool,
+        nomail -> Bool,
+        moderator -> Bool,
+    }
+}
+
+diesel::table! {
+    mailentry_vacation (mailentry) {
+        mailentry -> Int4,
+        enabled -> Bool,
+        subject -> Varchar,
+        body -> Text,
+        starts_on -> Nullable<Date>,
+        ends_on -> Nullable<Date>,
+        interval_days -> Int4,
     }
 }
 
@@ -71,16 +230,43 @@ diesel::table! {
 }
 
 diesel::joinable!(allowdenylist -> maildomain (maildomain));
+diesel::joinable!(auditlog -> maildomain (maildomain));
+diesel::joinable!(auditlog -> mailuser (mailuser));
+diesel::joinable!(list_subscription -> mailentry (mailentry));
 diesel::joinable!(mailauthtoken -> mailuser (mailuser));
 diesel::joinable!(maildomain -> mailuser (owner));
+diesel::joinable!(maildomain_alias -> maildomain (maildomain));
+diesel::joinable!(maildomain_member -> maildomain (maildomain));
+diesel::joinable!(maildomain_password_policy -> maildomain (maildomain));
+diesel::joinable!(maildomain_quota -> maildomain (maildomain));
+diesel::joinable!(maildomain_member -> mailuser (mailuser));
 diesel::joinable!(maildomainkey -> maildomain (maildomain));
 diesel::joinable!(mailentry -> maildomain (maildomain));
+diesel::joinable!(mailentry_forward -> mailentry (mailentry));
+diesel::joinable!(mailentry_list -> mailentry (mailentry));
+diesel::joinable!(mailentry_quota -> mailentry (mailentry));
+diesel::joinable!(mailentry_sieve -> mailentry (mailentry));
+diesel::joinable!(mailentry_target -> mailentry (mailentry));
+diesel::joinable!(mailentry_vacation -> mailentry (mailentry));
 
 diesel::allow_tables_to_appear_in_same_query!(
     allowdenylist,
+    auditlog,
+    list_subscription,
+    mail_outbox,
     mailauthtoken,
     maildomain,
+    maildomain_alias,
+    maildomain_member,
+    maildomain_password_policy,
+    maildomain_quota,
     maildomainkey,
     mailentry,
+    mailentry_forward,
+    mailentry_list,
+    mailentry_quota,
+    mailentry_sieve,
+    mailentry_target,
+    mailentry_vacation,
     mailuser,
 );
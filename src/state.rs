@@ -1,15 +1,27 @@
 use axum::extract::FromRef;
 
-use crate::configuration::Configuration;
+use crate::{
+    configuration::Configuration,
+    ratelimit::{AuthLimiter, MailoutLimiter},
+};
 
 #[derive(Clone, FromRef)]
 pub struct AppState {
     config: Configuration,
     pool: mailconfig::Pool,
+    limiter: AuthLimiter,
+    mailout: MailoutLimiter,
 }
 
 impl AppState {
     pub fn new(config: Configuration, pool: mailconfig::Pool) -> Self {
-        Self { config, pool }
+        let limiter = AuthLimiter::new(&config);
+        let mailout = MailoutLimiter::new(&config);
+        Self {
+            config,
+            pool,
+            limiter,
+            mailout,
+        }
     }
 }
@@ -1,18 +1,22 @@
 //! Token management stuff
 
 use axum::{
-    http::{header, Request},
+    extract::State,
+    http::{header, HeaderMap, Request},
     middleware::{self, Next},
     response::IntoResponse,
     Router,
 };
 use mailconfig::{
-    models::{Authorisation, MailAuthToken, MailUser},
+    models::{token_prefix, Authorisation, MailAuthToken, MailUser},
     Connection,
 };
 
+use sha2::{Digest, Sha256};
+
 use crate::{
     api::{APIError, APIResult},
+    configuration::Configuration,
     state::AppState,
 };
 
@@ -29,13 +33,19 @@ async fn auth<B>(
 
     let token = auth_header.ok_or(APIError::AuthErrorNoToken)?;
 
-    let db_token = MailAuthToken::by_token(&mut db, &token)
+    let mut db_token = MailAuthToken::by_token(&mut db, &token)
         .await?
-        .ok_or_else(|| APIError::AuthErrorBadToken(token.clone()))?;
+        .ok_or_else(|| APIError::AuthErrorBadToken(token_prefix(&token).to_string()))?;
+
+    if db_token.is_expired() {
+        return Err(APIError::AuthErrorTokenExpired(db_token.prefix));
+    }
+
+    db_token.touch(&mut db).await?;
 
     let user = MailUser::by_id(&mut db, db_token.mailuser).await?;
 
-    let auth = Authorisation::new(token, &user);
+    let auth = Authorisation::new(&db_token, &user);
 
     req.extensions_mut().insert(auth);
 
@@ -51,3 +61,71 @@ impl Authorised for Router<AppState> {
         self.route_layer(middleware::from_fn_with_state(state, auth))
     }
 }
+
+/// Check for the internal service credential.  If none is configured then
+/// nobody gets in, unless `open_if_unset` is given.
+fn check_internal(
+    config: &Configuration,
+    headers: &HeaderMap,
+    open_if_unset: bool,
+) -> APIResult<()> {
+    let Some(expected) = config.internal_token() else {
+        if open_if_unset {
+            return Ok(());
+        }
+        return Err(APIError::PermissionDenied(
+            "No internal service credential is configured".into(),
+        ));
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|auth_value| auth_value.strip_prefix("Bearer "))
+        .ok_or(APIError::AuthErrorNoToken)?;
+    // Compare digests so that how long the comparison takes says nothing
+    // about how much of the credential was right
+    if Sha256::digest(token.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+        return Err(APIError::AuthErrorBadToken("internal".into()));
+    }
+    Ok(())
+}
+
+async fn internal<B>(
+    State(config): State<Configuration>,
+    req: Request<B>,
+    next: Next<B>,
+) -> APIResult<impl IntoResponse> {
+    check_internal(&config, req.headers(), false)?;
+    Ok(next.run(req).await)
+}
+
+async fn internal_if_configured<B>(
+    State(config): State<Configuration>,
+    req: Request<B>,
+    next: Next<B>,
+) -> APIResult<impl IntoResponse> {
+    check_internal(&config, req.headers(), true)?;
+    Ok(next.run(req).await)
+}
+
+/// Routes for internal services such as the mail frontend and backend,
+/// rather than for users
+pub trait InternalService {
+    /// Only allow in those with the internal service credential
+    fn internal_only(self, state: AppState) -> Self;
+    /// As `internal_only`, but open to all if no credential is configured
+    fn internal_if_configured(self, state: AppState) -> Self;
+}
+
+impl InternalService for Router<AppState> {
+    fn internal_only(self, state: AppState) -> Self {
+        self.route_layer(middleware::from_fn_with_state(state, internal))
+    }
+
+    fn internal_if_configured(self, state: AppState) -> Self {
+        self.route_layer(middleware::from_fn_with_state(
+            state,
+            internal_if_configured,
+        ))
+    }
+}